@@ -0,0 +1,122 @@
+//! Readers on distinct keys hammering the map from several threads, compared against the
+//! single global lock design (`RwLock<SlotMap<_, RwLock<_>>>`) the crate started out as, and
+//! then again with other threads scanning the whole map meanwhile.
+//!
+//! Run with `cargo bench --bench contention`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Barrier;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+use slotmap::{DefaultKey, SlotMap};
+use sync_slotmap::SyncSlotMap;
+
+const THREADS: usize = 8;
+const KEYS_PER_THREAD: usize = 64;
+const ROUNDS: usize = 20_000;
+const SCANNERS: usize = 2;
+
+fn run<M: Sync>(
+    map: &M,
+    keys: &[Vec<DefaultKey>],
+    read: impl Fn(&M, DefaultKey) -> u64 + Sync,
+) -> Duration {
+    let barrier = Barrier::new(THREADS);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for keys in keys {
+            let (barrier, read) = (&barrier, &read);
+            s.spawn(move || {
+                barrier.wait();
+                let mut sum = 0;
+                for _ in 0..ROUNDS {
+                    for &key in keys {
+                        sum += read(map, key);
+                    }
+                }
+                std::hint::black_box(sum);
+            });
+        }
+    });
+    start.elapsed()
+}
+
+// Like `run`, with `SCANNERS` more threads calling `scan` over and over until the readers are
+// done.
+fn run_with_scans<M: Sync>(
+    map: &M,
+    keys: &[Vec<DefaultKey>],
+    read: impl Fn(&M, DefaultKey) -> u64 + Sync,
+    scan: impl Fn(&M) -> u64 + Sync,
+) -> Duration {
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        for _ in 0..SCANNERS {
+            let (done, scan) = (&done, &scan);
+            s.spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    std::hint::black_box(scan(map));
+                }
+            });
+        }
+        let elapsed = run(map, keys, read);
+        done.store(true, Ordering::Relaxed);
+        elapsed
+    })
+}
+
+fn main() {
+    let sharded = SyncSlotMap::<DefaultKey, u64>::new();
+    let keys: Vec<Vec<_>> = (0..THREADS)
+        .map(|t| {
+            (0..KEYS_PER_THREAD)
+                .map(|i| sharded.insert((t * i) as u64))
+                .collect()
+        })
+        .collect();
+
+    let mut inner = SlotMap::new();
+    let global_keys: Vec<Vec<_>> = (0..THREADS)
+        .map(|t| {
+            (0..KEYS_PER_THREAD)
+                .map(|i| inner.insert(RwLock::new((t * i) as u64)))
+                .collect()
+        })
+        .collect();
+    let global = RwLock::new(inner);
+
+    let reads = (THREADS * KEYS_PER_THREAD * ROUNDS) as f64;
+    let elapsed = run(&global, &global_keys, |map, key| *map.read()[key].read());
+    println!(
+        "single global lock: {:>8.1} ns/read",
+        elapsed.as_nanos() as f64 / reads
+    );
+    let elapsed = run(&sharded, &keys, |map, key| *map.get(key).unwrap());
+    println!(
+        "sharded global lock: {:>7.1} ns/read",
+        elapsed.as_nanos() as f64 / reads
+    );
+
+    let elapsed = run_with_scans(
+        &global,
+        &global_keys,
+        |map, key| *map.read()[key].read(),
+        |map| map.read().values().map(|value| *value.read()).sum(),
+    );
+    println!(
+        "single global lock, with scans: {:>8.1} ns/read",
+        elapsed.as_nanos() as f64 / reads
+    );
+    let elapsed = run_with_scans(
+        &sharded,
+        &keys,
+        |map, key| *map.get(key).unwrap(),
+        |map| map.fold(0, |sum, _, value| sum + value),
+    );
+    println!(
+        "sharded global lock, with scans: {:>7.1} ns/read",
+        elapsed.as_nanos() as f64 / reads
+    );
+}
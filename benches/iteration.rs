@@ -0,0 +1,53 @@
+//! Going through every value of a `SyncSlotMap` compared to a `SyncDenseSlotMap`, which keeps
+//! its values next to each other.
+//!
+//! Run with `cargo bench --bench iteration`.
+
+use std::time::Instant;
+
+use slotmap::DefaultKey;
+use sync_slotmap::{SyncDenseSlotMap, SyncSlotMap};
+
+const ENTRIES: usize = 100_000;
+const ROUNDS: usize = 50;
+
+fn main() {
+    let sparse = SyncSlotMap::<DefaultKey, u64>::new();
+    let dense = SyncDenseSlotMap::<DefaultKey, u64>::new();
+    for i in 0..ENTRIES as u64 {
+        sparse.insert(i);
+        dense.insert(i);
+    }
+
+    let visits = (ENTRIES * ROUNDS) as f64;
+    let time = |name: &str, scan: &dyn Fn() -> u64| {
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            std::hint::black_box(scan());
+        }
+        println!(
+            "{name:<30} {:>5.1} ns/value",
+            start.elapsed().as_nanos() as f64 / visits
+        );
+    };
+    time("SyncSlotMap::for_each", &|| {
+        let mut sum = 0;
+        sparse.for_each(|_, value| sum += value);
+        sum
+    });
+    time("SyncDenseSlotMap::for_each", &|| {
+        let mut sum = 0;
+        dense.for_each(|_, value| sum += value);
+        sum
+    });
+    time("SyncSlotMap::for_each_mut", &|| {
+        let mut sum = 0;
+        sparse.for_each_mut(|_, value| sum += *value);
+        sum
+    });
+    time("SyncDenseSlotMap::for_each_mut", &|| {
+        let mut sum = 0;
+        dense.for_each_mut(|_, value| sum += *value);
+        sum
+    });
+}
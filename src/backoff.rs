@@ -0,0 +1,71 @@
+//! How long the methods that lock several entries at once wait before trying again.
+//!
+//! Those methods take their element locks in address order and then check that every key
+//! still owns the lock it waited on. If one of the entries was removed (and its slot reused)
+//! in the meantime, they let go of every lock and start over. By default they start over
+//! straight away; a [`Backoff`] set with
+//! [`SyncSlotMapBuilder::backoff`](crate::SyncSlotMapBuilder::backoff) makes them wait first,
+//! e.g. to leave the CPU to whoever is removing and inserting entries.
+//!
+//! This only spaces the attempts out. Whether one has to start over again depends on whether
+//! another of its entries gets removed while it waits, not on when it tries, so waiting longer
+//! isn't a way of making retries rarer.
+
+/// Waits between two attempts at locking a set of entries.
+pub trait Backoff: Send + Sync {
+    /// Waits before attempt number `retry` (1 for the first retry) is made.
+    fn backoff(&self, retry: u32);
+}
+
+/// Spins `spins` times before every retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Fixed {
+    pub spins: u32,
+}
+
+impl Backoff for Fixed {
+    fn backoff(&self, _retry: u32) {
+        spin(self.spins);
+    }
+}
+
+/// Spins `initial` times before the first retry, doubling that every retry up to `max`.
+///
+/// With `std`, a retry that would spin longer than `max` yields the thread instead, since
+/// whoever is in the way is then likely not running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Exponential {
+    pub initial: u32,
+    pub max: u32,
+}
+
+impl Default for Exponential {
+    fn default() -> Self {
+        Self {
+            initial: 1,
+            max: 1 << 10,
+        }
+    }
+}
+
+impl Backoff for Exponential {
+    fn backoff(&self, retry: u32) {
+        let spins = 1u32
+            .checked_shl(retry.saturating_sub(1))
+            .and_then(|factor| self.initial.checked_mul(factor))
+            .filter(|&spins| spins <= self.max);
+        match spins {
+            Some(spins) => spin(spins),
+            #[cfg(feature = "std")]
+            None => std::thread::yield_now(),
+            #[cfg(not(feature = "std"))]
+            None => spin(self.max),
+        }
+    }
+}
+
+fn spin(spins: u32) {
+    for _ in 0..spins {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,67 @@
+//! When the timed `*_for` methods give up waiting.
+//!
+//! On wasm32 a thread can't block, and `wasm32-unknown-unknown` doesn't even have a clock
+//! (`Instant::now` panics there), so a deadline means trying each lock just once instead. The
+//! `*_async` methods, which never block, are the way to wait for a lock there.
+
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+use crate::raw_lock::RawLock;
+
+#[derive(Clone, Copy)]
+pub(crate) struct Deadline {
+    #[cfg(not(target_arch = "wasm32"))]
+    at: Instant,
+}
+
+impl Deadline {
+    pub(crate) fn after(timeout: Duration) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            Self {
+                at: Instant::now() + timeout,
+            }
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            let _ = timeout;
+            Self {}
+        }
+    }
+
+    /// Whether it's time to give up. Without a clock that's right away, after the first try.
+    pub(crate) fn has_passed(self) -> bool {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            Instant::now() >= self.at
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            true
+        }
+    }
+
+    pub(crate) fn try_lock_shared<L: RawLock>(self, lock: &L) -> bool {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            lock.try_lock_shared_until(self.at)
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            lock.try_lock_shared()
+        }
+    }
+
+    pub(crate) fn try_lock_exclusive<L: RawLock>(self, lock: &L) -> bool {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            lock.try_lock_exclusive_until(self.at)
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            lock.try_lock_exclusive()
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! Finding deadlocks between threads holding guards, with the `deadlock-detection` feature.
+//!
+//! This turns on parking_lot's deadlock detector, which every blocking wait in this crate goes
+//! through (the timed `*_for` methods included). The `*_async` methods don't block a thread
+//! while they wait, so they can't be seen by it, and neither can the `std-locks` locks.
+//!
+//! Call [`check_deadlocks`] periodically, e.g. from a background thread in tests or CI:
+//!
+//! ```no_run
+//! std::thread::spawn(|| loop {
+//!     std::thread::sleep(std::time::Duration::from_secs(1));
+//!     for deadlock in sync_slotmap::check_deadlocks() {
+//!         panic!("{deadlock:?}");
+//!     }
+//! });
+//! ```
+
+/// A cycle of threads each waiting on a lock held by the next, found by [`check_deadlocks`].
+#[derive(Debug, Clone)]
+pub struct DeadlockInfo {
+    /// The id and a formatted backtrace of each thread in the cycle.
+    pub threads: Vec<(std::thread::ThreadId, String)>,
+}
+
+/// Returns every deadlock that has formed since the last call.
+///
+/// Each deadlock is only reported once, so a later call won't report it again.
+pub fn check_deadlocks() -> Vec<DeadlockInfo> {
+    parking_lot::deadlock::check_deadlock()
+        .into_iter()
+        .map(|threads| DeadlockInfo {
+            threads: threads
+                .iter()
+                .map(|thread| (thread.thread_id(), format!("{:?}", thread.backtrace())))
+                .collect(),
+        })
+        .collect()
+}
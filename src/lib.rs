@@ -1,8 +1,96 @@
+//! Without the default `std` feature the crate is `no_std` (it still needs `alloc`), for use with
+//! the `spin-locks` lock backend. That leaves out the timed `*_for` methods, entry TTLs,
+//! `LazySyncSlotMap` and `SyncSparseSecondarySlotMap`, which all need `std`.
+//!
+//! On wasm32 there's no blocking a thread to wait for a lock, so the `*_async` methods are the
+//! way to wait there. The timed `*_for` methods still work, but try each lock just once rather
+//! than waiting out their timeout, and entry TTLs need a clock, which
+//! `wasm32-unknown-unknown` doesn't have.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod backoff;
+
+#[cfg(feature = "deadlock-detection")]
+pub mod deadlock;
+
+#[cfg(feature = "std")]
+mod deadline;
+
+mod lock_order;
+
+mod locks;
+
+pub mod metrics;
+
+mod raw_lock;
+
+mod sharded_lock;
+
+#[cfg(feature = "tokio")]
+pub mod tokio_lock;
+
+mod trace;
+
+pub mod sync_dense_slot_map;
+
 pub mod sync_secondary_map;
 
 pub mod sync_slot_map;
 
-pub use sync_slot_map::*;
+pub mod sync_slot_map_mutex;
+
+#[cfg(feature = "std")]
+pub mod sync_sparse_secondary_map;
+
+pub use backoff::Backoff;
+#[cfg(feature = "deadlock-detection")]
+pub use deadlock::*;
+pub use metrics::Metrics;
+pub use sync_dense_slot_map::*;
 pub use sync_secondary_map::*;
+pub use sync_slot_map::*;
+pub use sync_slot_map_mutex::*;
+#[cfg(feature = "std")]
+pub use sync_sparse_secondary_map::*;
+#[cfg(feature = "tokio")]
+pub use tokio_lock::*;
+
+#[cfg(feature = "spin-locks")]
+pub use raw_lock::SpinRawLock;
+pub use raw_lock::{DefaultRawLock, RawLock};
+
+pub use slotmap::new_key_type;
+
+/// Declares a key type with [`new_key_type!`] together with an alias for a [`SyncSlotMap`]
+/// keyed by it, for when a program has many maps that each want their own key type.
+///
+/// The alias is written with the map's value type, `pub type Players = Player;` declaring
+/// `pub type Players = SyncSlotMap<PlayerKey, Player>;`.
+///
+/// ```
+/// sync_slotmap::define_sync_slotmap! {
+///     /// A key into `Players`.
+///     pub struct PlayerKey;
+///     pub type Players = &'static str;
+/// }
+///
+/// let players = Players::new();
+/// let key: PlayerKey = players.insert("ferris");
+/// assert_eq!(*players.get(key).unwrap(), "ferris");
+/// ```
+#[macro_export]
+macro_rules! define_sync_slotmap {
+    ($(
+        $(#[$key_meta:meta])* $key_vis:vis struct $key:ident;
+        $(#[$map_meta:meta])* $map_vis:vis type $map:ident = $value:ty;
+    )*) => {$(
+        $crate::new_key_type! {
+            $(#[$key_meta])* $key_vis struct $key;
+        }
 
-pub use slotmap::new_key_type;
\ No newline at end of file
+        $(#[$map_meta])* $map_vis type $map = $crate::SyncSlotMap<$key, $value>;
+    )*};
+}
@@ -0,0 +1,88 @@
+//! A debug-build check that the methods taking several locks at once take them in address
+//! order, which is what keeps two threads locking overlapping sets from deadlocking each other.
+//!
+//! Each such method opens an [`Ordered`] sequence and reports its locks in the order it's going
+//! to take them, before taking any, so that the panic doesn't leave some of them locked. The
+//! locks reported so far are recorded per thread, and one coming before the last of them
+//! panics. In release builds, and without `std`, all of it compiles away.
+
+#[cfg(all(debug_assertions, feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(all(debug_assertions, feature = "std"))]
+use core::cell::RefCell;
+
+#[cfg(all(debug_assertions, feature = "std"))]
+std::thread_local! {
+    static TAKEN: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A sequence of locks that must be taken in address order, which ends when this is dropped.
+pub(crate) struct Ordered {
+    // Where this sequence starts in `TAKEN`, since sequences can nest.
+    #[cfg(all(debug_assertions, feature = "std"))]
+    start: usize,
+}
+
+impl Ordered {
+    #[inline(always)]
+    pub(crate) fn begin() -> Self {
+        Self {
+            #[cfg(all(debug_assertions, feature = "std"))]
+            start: TAKEN.with(|taken| taken.borrow().len()),
+        }
+    }
+
+    /// Records that `lock` is going to be taken next, panicking if it comes before a lock
+    /// already reported in this sequence.
+    #[inline(always)]
+    pub(crate) fn taking<T>(&self, lock: *const T) {
+        #[cfg(all(debug_assertions, feature = "std"))]
+        TAKEN.with(|taken| {
+            let mut taken = taken.borrow_mut();
+            let lock = lock as usize;
+            if let Some(&last) = taken[self.start..].last() {
+                assert!(
+                    lock > last,
+                    "lock at {lock:#x} taken after the one at {last:#x}, out of address order"
+                );
+            }
+            taken.push(lock);
+        });
+        #[cfg(not(all(debug_assertions, feature = "std")))]
+        let _ = lock;
+    }
+}
+
+#[cfg(all(debug_assertions, feature = "std"))]
+impl Drop for Ordered {
+    fn drop(&mut self) {
+        TAKEN.with(|taken| taken.borrow_mut().truncate(self.start));
+    }
+}
+
+#[cfg(all(test, debug_assertions, feature = "std"))]
+mod tests {
+    use super::Ordered;
+
+    #[test]
+    fn in_order() {
+        let locks = [0u8; 4];
+        let order = Ordered::begin();
+        order.taking(&locks[0]);
+        order.taking(&locks[2]);
+        // A nested sequence is checked on its own, and forgotten once it ends.
+        let nested = Ordered::begin();
+        nested.taking(&locks[1]);
+        drop(nested);
+        order.taking(&locks[3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of address order")]
+    fn out_of_order() {
+        let locks = [0u8; 2];
+        let order = Ordered::begin();
+        order.taking(&locks[1]);
+        order.taking(&locks[0]);
+    }
+}
@@ -0,0 +1,1262 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::ControlFlow;
+#[cfg(feature = "std")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use core::task::Poll;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::time::Duration;
+
+use slotmap::Key;
+
+use crate::backoff::Backoff;
+#[cfg(feature = "std")]
+use crate::deadline::Deadline;
+use crate::lock_order::Ordered;
+use crate::metrics::MetricsSlot;
+use crate::raw_lock::{Mutex, RawLock};
+use crate::sharded_lock::{ExclusiveShards, ShardedRwLock};
+use crate::sync_slot_map::{spin_until, DropBehavior, UnlockRwLockExclusive, UnlockRwLockShared};
+use crate::trace;
+
+pub(crate) fn slot_index<K: Key>(key: K) -> u32 {
+    key.data().as_ffi() as u32
+}
+
+pub(crate) fn version<K: Key>(key: K) -> u32 {
+    (key.data().as_ffi() >> 32) as u32
+}
+
+/// The element locks of a map, keyed by slot index so that whichever key currently owns a
+/// slot can be found (and evicted) when a newer key for the same slot is inserted.
+pub(crate) trait ElementLocks<K: Key, L>: Default {
+    fn get(&self, key: K) -> Option<&L>;
+    fn occupant(&self, key: K) -> Option<K>;
+    fn insert(&mut self, key: K, lock: Box<L>) -> Option<(K, Box<L>)>;
+    fn remove(&mut self, key: K) -> Option<Box<L>>;
+    /// Frees whatever room is left over from keys that have been removed.
+    fn shrink_to_fit(&mut self);
+}
+
+/// Element locks in a vector indexed by slot, like `SecondaryMap`.
+pub(crate) struct DenseLocks<K, L> {
+    slots: Vec<Option<(K, Box<L>)>>,
+}
+
+impl<K, L> Default for DenseLocks<K, L> {
+    fn default() -> Self {
+        Self { slots: Vec::new() }
+    }
+}
+
+impl<K: Key, L> ElementLocks<K, L> for DenseLocks<K, L> {
+    fn get(&self, key: K) -> Option<&L> {
+        match self.slots.get(slot_index(key) as usize)? {
+            Some((occupant, lock)) if *occupant == key => Some(lock),
+            _ => None,
+        }
+    }
+
+    fn occupant(&self, key: K) -> Option<K> {
+        self.slots
+            .get(slot_index(key) as usize)?
+            .as_ref()
+            .map(|(occupant, _)| *occupant)
+    }
+
+    fn insert(&mut self, key: K, lock: Box<L>) -> Option<(K, Box<L>)> {
+        let idx = slot_index(key) as usize;
+        if idx >= self.slots.len() {
+            self.slots.resize_with(idx + 1, || None);
+        }
+        self.slots[idx].replace((key, lock))
+    }
+
+    fn remove(&mut self, key: K) -> Option<Box<L>> {
+        let slot = self.slots.get_mut(slot_index(key) as usize)?;
+        match slot {
+            Some((occupant, _)) if *occupant == key => slot.take().map(|(_, lock)| lock),
+            _ => None,
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        let len = self
+            .slots
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |idx| idx + 1);
+        self.slots.truncate(len);
+        self.slots.shrink_to_fit();
+    }
+}
+
+/// Element locks in a hash map keyed by slot, like `SparseSecondaryMap`.
+#[cfg(feature = "std")]
+pub(crate) struct SparseLocks<K, L> {
+    slots: HashMap<u32, (K, Box<L>)>,
+}
+
+#[cfg(feature = "std")]
+impl<K, L> Default for SparseLocks<K, L> {
+    fn default() -> Self {
+        Self {
+            slots: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: Key, L> ElementLocks<K, L> for SparseLocks<K, L> {
+    fn get(&self, key: K) -> Option<&L> {
+        match self.slots.get(&slot_index(key))? {
+            (occupant, lock) if *occupant == key => Some(lock),
+            _ => None,
+        }
+    }
+
+    fn occupant(&self, key: K) -> Option<K> {
+        self.slots
+            .get(&slot_index(key))
+            .map(|(occupant, _)| *occupant)
+    }
+
+    fn insert(&mut self, key: K, lock: Box<L>) -> Option<(K, Box<L>)> {
+        self.slots.insert(slot_index(key), (key, lock))
+    }
+
+    fn remove(&mut self, key: K) -> Option<Box<L>> {
+        let idx = slot_index(key);
+        match self.slots.get(&idx) {
+            Some((occupant, _)) if *occupant == key => {
+                self.slots.remove(&idx).map(|(_, lock)| lock)
+            }
+            _ => None,
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.slots.shrink_to_fit();
+    }
+}
+
+/// Element locks in whichever of [`DenseLocks`] and [`SparseLocks`] the map was made with, see
+/// `SyncSlotMapBuilder::sparse_locks`.
+pub(crate) enum SlotLocks<K, L> {
+    Dense(DenseLocks<K, L>),
+    #[cfg(feature = "std")]
+    Sparse(SparseLocks<K, L>),
+}
+
+impl<K, L> SlotLocks<K, L> {
+    #[cfg(feature = "std")]
+    pub(crate) fn sparse() -> Self {
+        Self::Sparse(SparseLocks::default())
+    }
+
+    /// An empty table of the same kind.
+    pub(crate) fn empty_like(&self) -> Self {
+        match self {
+            Self::Dense(_) => Self::Dense(DenseLocks::default()),
+            #[cfg(feature = "std")]
+            Self::Sparse(_) => Self::Sparse(SparseLocks::default()),
+        }
+    }
+}
+
+impl<K, L> Default for SlotLocks<K, L> {
+    fn default() -> Self {
+        Self::Dense(DenseLocks::default())
+    }
+}
+
+impl<K: Key, L> ElementLocks<K, L> for SlotLocks<K, L> {
+    fn get(&self, key: K) -> Option<&L> {
+        match self {
+            Self::Dense(locks) => locks.get(key),
+            #[cfg(feature = "std")]
+            Self::Sparse(locks) => locks.get(key),
+        }
+    }
+
+    fn occupant(&self, key: K) -> Option<K> {
+        match self {
+            Self::Dense(locks) => locks.occupant(key),
+            #[cfg(feature = "std")]
+            Self::Sparse(locks) => locks.occupant(key),
+        }
+    }
+
+    fn insert(&mut self, key: K, lock: Box<L>) -> Option<(K, Box<L>)> {
+        match self {
+            Self::Dense(locks) => locks.insert(key, lock),
+            #[cfg(feature = "std")]
+            Self::Sparse(locks) => locks.insert(key, lock),
+        }
+    }
+
+    fn remove(&mut self, key: K) -> Option<Box<L>> {
+        match self {
+            Self::Dense(locks) => locks.remove(key),
+            #[cfg(feature = "std")]
+            Self::Sparse(locks) => locks.remove(key),
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        match self {
+            Self::Dense(locks) => locks.shrink_to_fit(),
+            #[cfg(feature = "std")]
+            Self::Sparse(locks) => locks.shrink_to_fit(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    Shared,
+    Exclusive,
+}
+
+impl Access {
+    fn try_lock<L: RawLock>(self, lock: &L) -> bool {
+        match self {
+            Access::Shared => lock.try_lock_shared(),
+            Access::Exclusive => lock.try_lock_exclusive(),
+        }
+    }
+
+    fn lock<L: RawLock>(self, lock: &L) {
+        match self {
+            Access::Shared => lock.lock_shared(),
+            Access::Exclusive => lock.lock_exclusive(),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn try_lock_until<L: RawLock>(self, lock: &L, deadline: Deadline) -> bool {
+        match self {
+            Access::Shared => deadline.try_lock_shared(lock),
+            Access::Exclusive => deadline.try_lock_exclusive(lock),
+        }
+    }
+
+    pub(crate) async fn lock_async<L: RawLock>(self, lock: &L, spins: u32) {
+        #[cfg(feature = "tokio")]
+        if let Some(unlocked) = lock.unlocked() {
+            loop {
+                // Listening before trying the lock means an unlock in between isn't missed.
+                let mut notified = core::pin::pin!(unlocked.notified());
+                notified.as_mut().enable();
+                if spin_until(spins, || self.try_lock(lock)) {
+                    return;
+                }
+                notified.await;
+            }
+        }
+        let lock = lock as *const L as *mut L;
+        match self {
+            Access::Shared => UnlockRwLockShared { lock, spins }.await,
+            Access::Exclusive => UnlockRwLockExclusive { lock, spins }.await,
+        }
+    }
+
+    unsafe fn unlock<L: RawLock>(self, lock: &L) {
+        match self {
+            Access::Shared => lock.unlock_shared(),
+            Access::Exclusive => lock.unlock_exclusive(),
+        }
+    }
+
+    unsafe fn unlock_fair<L: RawLock>(self, lock: &L) {
+        match self {
+            Access::Shared => lock.unlock_shared_fair(),
+            Access::Exclusive => lock.unlock_exclusive_fair(),
+        }
+    }
+}
+
+/// Like [`Access::lock_async`] with shared access, but takes `lock` recursively, so that it
+/// doesn't queue up behind a writer that is waiting for other readers.
+pub(crate) async fn lock_shared_recursive_async<L: RawLock>(lock: &L, spins: u32) {
+    #[cfg(feature = "tokio")]
+    if let Some(unlocked) = lock.unlocked() {
+        loop {
+            let mut notified = core::pin::pin!(unlocked.notified());
+            notified.as_mut().enable();
+            if spin_until(spins, || lock.try_lock_shared_recursive()) {
+                return;
+            }
+            notified.await;
+        }
+    }
+    core::future::poll_fn(|cx| {
+        if spin_until(spins, || lock.try_lock_shared_recursive()) {
+            Poll::Ready(())
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Runs `f` with both element locks held shared, or hands back whichever of them is busy
+/// without waiting for it, for [`Locks::with_both_shared`] to wait on.
+pub(crate) fn try_read_both<L: RawLock, R>(
+    a: &L,
+    b: &L,
+    f: impl FnOnce() -> R,
+) -> Result<R, *const L> {
+    if !a.try_lock_shared() {
+        return Err(a);
+    }
+    let _a = unsafe { Held::new(a, Access::Shared) };
+    if !b.try_lock_shared() {
+        return Err(b);
+    }
+    let _b = unsafe { Held::new(b, Access::Shared) };
+    Ok(f())
+}
+
+/// Unlocks `lock` for `access` when dropped, so that dropping a future halfway through an
+/// `.await` doesn't leave locks it already took locked forever.
+#[must_use]
+pub(crate) struct Held<'a, L: RawLock> {
+    lock: &'a L,
+    access: Access,
+}
+
+impl<'a, L: RawLock> Held<'a, L> {
+    /// # Safety
+    /// `lock` must be held for `access`.
+    pub(crate) unsafe fn new(lock: &'a L, access: Access) -> Self {
+        Self { lock, access }
+    }
+
+    /// Keeps the lock locked after all.
+    pub(crate) fn keep(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl<L: RawLock> Drop for Held<'_, L> {
+    fn drop(&mut self) {
+        unsafe { self.access.unlock(self.lock) };
+    }
+}
+
+/// Unlocks a map taken with [`Locks::lock_exclusive`] when dropped, like [`Held`], so that a
+/// closure panicking while the whole map is locked doesn't leave it locked forever.
+#[must_use]
+pub(crate) struct HeldExclusive<'a, K: Key, L: RawLock, E: ElementLocks<K, L>> {
+    locks: &'a Locks<K, L, E>,
+}
+
+impl<'a, K: Key, L: RawLock, E: ElementLocks<K, L>> HeldExclusive<'a, K, L, E> {
+    /// # Safety
+    /// `locks` must be held with [`Locks::lock_exclusive`].
+    pub(crate) unsafe fn new(locks: &'a Locks<K, L, E>) -> Self {
+        Self { locks }
+    }
+
+    /// Keeps the map locked after all.
+    pub(crate) fn keep(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl<K: Key, L: RawLock, E: ElementLocks<K, L>> Drop for HeldExclusive<'_, K, L, E> {
+    fn drop(&mut self) {
+        unsafe { self.locks.unlock_exclusive() };
+    }
+}
+
+pub(crate) enum Registered<K, L> {
+    Fresh,
+    Replaced(K, Box<L>),
+    Refused,
+}
+
+/// The locks a guard holds: a share of its global shard and its element lock, along with the
+/// map's fairness setting to unlock them by.
+pub(crate) struct GuardLocks<L> {
+    pub(crate) global: *mut L,
+    pub(crate) element: *mut L,
+    fair: *const AtomicBool,
+}
+
+impl<L> Clone for GuardLocks<L> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<L> Copy for GuardLocks<L> {}
+
+impl<L: RawLock> GuardLocks<L> {
+    /// # Safety
+    /// The element lock must be held for `access` and the global shard shared.
+    pub(crate) unsafe fn unlock(&self, access: Access) {
+        if (*self.fair).load(Ordering::Relaxed) {
+            access.unlock_fair(&*self.element);
+            (*self.global).unlock_shared_fair();
+        } else {
+            access.unlock(&*self.element);
+            (*self.global).unlock_shared();
+        }
+        let op = match access {
+            Access::Shared => "get",
+            Access::Exclusive => "get_mut",
+        };
+        trace::released(op, None);
+    }
+}
+
+/// The pointers a guard is made of: the locks it holds, and its value.
+pub(crate) type Acquired<V, L> = (GuardLocks<L>, *mut V);
+
+/// All the locking state of a map, kept behind a pointer so guards stay valid if the map moves.
+///
+/// Lock order is global, then structure, then element:
+/// - `global` is held shared by every guard for its whole life (and while a guard is being
+///   acquired), so holding it exclusively means there are no guards at all.
+/// - `structure` protects the layout of the value map and `elements` and is only ever held
+///   for a lookup or a structural change, never while a guard is alive. Nobody waits for an
+///   element lock while holding it, see [`Locks::scan`]. Values and element locks are boxed
+///   so that changing the layout doesn't move them.
+/// - Removed element locks are recycled instead of freed, since a thread may still be queued
+///   on one; the pool is only emptied while `global` is held exclusively.
+pub(crate) struct Locks<K: Key, L, E = SlotLocks<K, L>> {
+    pub(crate) global: ShardedRwLock<L>,
+    pub(crate) structure: ShardedRwLock<L>,
+    elements: UnsafeCell<E>,
+    // Boxed so that recycled locks keep their address.
+    #[allow(clippy::vec_box)]
+    free: Mutex<Vec<Box<L>>>,
+    // Whether guards unlock fairly, see `RawLock::unlock_shared_fair`.
+    fair: AtomicBool,
+    // How many extra tries the async lock futures make per poll, see `UnlockRwLockShared`.
+    spins: AtomicU32,
+    // What `acquire_all` and `try_acquire_all_until` do before starting over, see `Backoff`.
+    backoff: Option<Arc<dyn Backoff>>,
+    pub(crate) metrics: MetricsSlot,
+    // A `DropBehavior` for the map owning this, see `SyncSlotMap::set_drop_behavior`.
+    drop_behavior: AtomicU8,
+    // In nanoseconds, see `SyncSlotMap::set_drop_timeout`.
+    #[cfg(feature = "std")]
+    drop_timeout: AtomicU64,
+    _key: PhantomData<K>,
+}
+
+// Everything in `elements` is only touched under `structure`, so `Locks` can be shared between
+// threads like the locks it is made of. This is also what lets the `*_async` futures be `Send`.
+unsafe impl<K: Key, L: RawLock, E: Send + Sync> Send for Locks<K, L, E> {}
+unsafe impl<K: Key, L: RawLock, E: Send + Sync> Sync for Locks<K, L, E> {}
+
+impl<K: Key, L: RawLock, E: ElementLocks<K, L>> Locks<K, L, E> {
+    pub(crate) fn new() -> Self {
+        Self {
+            global: ShardedRwLock::new(),
+            structure: ShardedRwLock::new(),
+            elements: UnsafeCell::new(E::default()),
+            free: Mutex::new(Vec::new()),
+            fair: AtomicBool::new(false),
+            spins: AtomicU32::new(0),
+            backoff: None,
+            metrics: MetricsSlot::new(),
+            drop_behavior: AtomicU8::new(DropBehavior::Wait as u8),
+            #[cfg(feature = "std")]
+            drop_timeout: AtomicU64::new(1_000_000_000),
+            _key: PhantomData,
+        }
+    }
+
+    pub(crate) fn set_drop_behavior(&self, behavior: DropBehavior) {
+        self.drop_behavior.store(behavior as u8, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn set_drop_timeout(&self, timeout: Duration) {
+        let nanos = u64::try_from(timeout.as_nanos()).unwrap_or(u64::MAX);
+        self.drop_timeout.store(nanos, Ordering::Relaxed);
+    }
+
+    /// Takes `global` exclusively for `map`, the map owning this, to be dropped.
+    ///
+    /// Guards don't borrow their map, so some may still be alive on other threads. This waits
+    /// for them to go away rather than let the map free values they point at, for as long as
+    /// the map's `DropBehavior` says. If they outlast that, `leak` is called to leak the
+    /// values, then this panics or warns and returns `false`, in which case `self` must be
+    /// leaked too.
+    pub(crate) fn lock_for_drop(&self, map: &'static str, leak: impl FnOnce()) -> bool {
+        let behavior = DropBehavior::from_u8(self.drop_behavior.load(Ordering::Relaxed));
+        if behavior == DropBehavior::Wait {
+            self.global.lock_exclusive();
+            return true;
+        }
+        #[cfg(feature = "std")]
+        let locked = self.global.try_lock_exclusive_for(Duration::from_nanos(
+            self.drop_timeout.load(Ordering::Relaxed),
+        ));
+        #[cfg(not(feature = "std"))]
+        let locked = self.global.try_lock_exclusive();
+        if !locked {
+            leak();
+            if behavior == DropBehavior::Panic {
+                panic!("Dropped {map} which was in use.");
+            }
+            trace::warn(format_args!(
+                "dropped a {map} which was in use, leaking its entries"
+            ));
+        }
+        locked
+    }
+
+    /// Takes `global` and then `structure` exclusively, which leaves the caller as the only
+    /// one touching the map at all.
+    pub(crate) fn lock_exclusive(&self) {
+        self.metrics.time(|| {
+            self.global.lock_exclusive();
+            self.structure.lock_exclusive();
+        });
+    }
+
+    /// Like [`Locks::lock_exclusive`], but gives up if `global` can't be taken within
+    /// `timeout`. `structure` is only ever held briefly, so it is waited for regardless.
+    #[cfg(feature = "std")]
+    pub(crate) fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        if !self.global.try_lock_exclusive_for(timeout) {
+            return false;
+        }
+        self.structure.lock_exclusive();
+        true
+    }
+
+    /// Like [`Locks::lock_exclusive`], waiting on the locks asynchronously.
+    pub(crate) async fn lock_exclusive_async(&self) {
+        self.global.lock_exclusive_async(self.spins()).await;
+        let global = unsafe { ExclusiveShards::all(&self.global) };
+        self.structure.lock_exclusive_async(self.spins()).await;
+        global.keep();
+    }
+
+    /// # Safety
+    /// Both locks must have been taken with [`Locks::lock_exclusive`].
+    pub(crate) unsafe fn unlock_exclusive(&self) {
+        if self.is_fair() {
+            self.structure.unlock_exclusive_fair();
+            self.global.unlock_exclusive_fair();
+        } else {
+            self.structure.unlock_exclusive();
+            self.global.unlock_exclusive();
+        }
+    }
+
+    pub(crate) fn is_fair(&self) -> bool {
+        self.fair.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_fair(&self, fair: bool) {
+        self.fair.store(fair, Ordering::Relaxed);
+    }
+
+    pub(crate) fn spins(&self) -> u32 {
+        self.spins.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_spins(&self, spins: u32) {
+        self.spins.store(spins, Ordering::Relaxed);
+    }
+
+    pub(crate) fn backoff(&self) -> Option<&Arc<dyn Backoff>> {
+        self.backoff.as_ref()
+    }
+
+    pub(crate) fn set_backoff(&mut self, backoff: Option<Arc<dyn Backoff>>) {
+        self.backoff = backoff;
+    }
+
+    fn back_off(&self, retry: &mut u32) {
+        *retry += 1;
+        if let Some(backoff) = &self.backoff {
+            backoff.backoff(*retry);
+        }
+    }
+
+    /// Shrinks `elements` to what the keys still in it need and frees the recycled locks.
+    ///
+    /// # Safety
+    /// Must be called with [`Locks::lock_exclusive`] held, so nothing can still be queued on a
+    /// recycled lock.
+    pub(crate) unsafe fn shrink_to_fit(&self) {
+        self.elements_mut().shrink_to_fit();
+        let mut free = self.free.lock();
+        free.clear();
+        free.shrink_to_fit();
+    }
+
+    /// Takes every lock exclusively to run [`Locks::shrink_to_fit`].
+    pub(crate) fn compact(&self) {
+        self.lock_exclusive();
+        unsafe {
+            self.shrink_to_fit();
+            self.unlock_exclusive();
+        }
+    }
+
+    /// Runs `f` with `global` and `structure` of both `a` and `b` held shared, taking them in
+    /// address order so that two threads doing this to the same pair of maps can't deadlock.
+    ///
+    /// Like [`Locks::scan`], `f` must not wait for element locks: it hands back one it found
+    /// busy instead, which is then waited on with only the `global` shards held, and `f` is
+    /// run again from the start.
+    pub(crate) fn with_both_shared<R>(
+        a: &Self,
+        b: &Self,
+        mut f: impl FnMut() -> Result<R, *const L>,
+    ) -> R {
+        let (first, second) = if (a as *const Self) < (b as *const Self) {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        let globals = [first.global.any_shard(), second.global.any_shard()];
+        let structures = [first.structure.any_shard(), second.structure.any_shard()];
+        // The order is between the maps, their own locks always come in the same order.
+        let order = Ordered::begin();
+        order.taking(first);
+        order.taking(second);
+        let _globals = globals.map(|global| {
+            global.lock_shared();
+            unsafe { Held::new(global, Access::Shared) }
+        });
+        loop {
+            let held = structures.map(|structure| {
+                structure.lock_shared();
+                unsafe { Held::new(structure, Access::Shared) }
+            });
+            let busy = match f() {
+                Ok(result) => return result,
+                Err(busy) => busy,
+            };
+            drop(held);
+            // The `global` shards keep the lock from being freed, even if its entry is removed.
+            let busy = unsafe { &*busy };
+            busy.lock_shared();
+            unsafe { busy.unlock_shared() };
+        }
+    }
+
+    /// The locks for a guard holding `global` shared and `element`.
+    pub(crate) fn guard_locks(&self, global: &L, element: &L) -> GuardLocks<L> {
+        GuardLocks {
+            global: global as *const L as *mut L,
+            element: element as *const L as *mut L,
+            fair: &self.fair,
+        }
+    }
+
+    /// # Safety
+    /// `structure` must be held.
+    pub(crate) unsafe fn elements(&self) -> &E {
+        &*self.elements.get()
+    }
+
+    /// Swaps in another (empty) table of element locks, before any key has been registered.
+    pub(crate) fn set_elements(&mut self, elements: E) {
+        *self.elements.get_mut() = elements;
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn elements_mut(&self) -> &mut E {
+        &mut *self.elements.get()
+    }
+
+    /// # Safety
+    /// `structure` must be held, shared or exclusive.
+    pub(crate) unsafe fn element(&self, key: K) -> Option<&L> {
+        self.elements().get(key)
+    }
+
+    /// Runs `f` on the element lock of `key` without locking it, or returns `None` if `key`
+    /// isn't in the map.
+    pub(crate) fn inspect<R>(&self, key: K, f: impl FnOnce(&L) -> R) -> Option<R> {
+        let global = self.global.shard(key);
+        let structure = self.structure.shard(key);
+        global.lock_shared();
+        structure.lock_shared();
+        let result = unsafe { self.elements() }.get(key).map(f);
+        unsafe {
+            structure.unlock_shared();
+            global.unlock_shared();
+        }
+        result
+    }
+
+    fn is_current(&self, key: K, lock: &L) -> bool {
+        unsafe { self.elements() }
+            .get(key)
+            .is_some_and(|current| core::ptr::eq(current, lock))
+    }
+
+    /// Gives `key` a fresh element lock, evicting whichever key held its slot before unless
+    /// that key is newer than `key`.
+    ///
+    /// # Safety
+    /// `structure` must be held exclusively.
+    pub(crate) unsafe fn register(&self, key: K) -> Registered<K, L> {
+        self.register_with(key, || {
+            self.free.lock().pop().unwrap_or_else(|| Box::new(L::INIT))
+        })
+    }
+
+    /// Like [`Locks::register`], but never reuses a lock from `free`, for callers that lock the
+    /// new entry before releasing `structure`.
+    ///
+    /// A thread which waited on a removed entry's lock can still hold it after it has been
+    /// recycled, while it waits for `structure` to find out that its key is gone. Locking such
+    /// a lock with `structure` held would deadlock against that thread, a new one can't be held
+    /// by anyone.
+    ///
+    /// # Safety
+    /// `structure` must be held exclusively.
+    pub(crate) unsafe fn register_unused(&self, key: K) -> Registered<K, L> {
+        self.register_with(key, || Box::new(L::INIT))
+    }
+
+    unsafe fn register_with(&self, key: K, lock: impl FnOnce() -> Box<L>) -> Registered<K, L> {
+        if key.is_null() {
+            return Registered::Refused;
+        }
+        let elements = self.elements_mut();
+        if let Some(occupant) = elements.occupant(key) {
+            if occupant != key && version(key).wrapping_sub(version(occupant)) >= 1 << 31 {
+                return Registered::Refused;
+            }
+        }
+        match elements.insert(key, lock()) {
+            Some((occupant, lock)) => Registered::Replaced(occupant, lock),
+            None => Registered::Fresh,
+        }
+    }
+
+    /// Unlinks `key`'s element lock, which must then be passed to [`Locks::retire`].
+    ///
+    /// # Safety
+    /// `structure` must be held exclusively.
+    pub(crate) unsafe fn unregister(&self, key: K) -> Option<Box<L>> {
+        self.elements_mut().remove(key)
+    }
+
+    /// Waits until every guard holding `lock` is gone and keeps it for reuse.
+    ///
+    /// # Safety
+    /// `lock` must no longer be reachable through `elements`.
+    pub(crate) unsafe fn retire(&self, lock: Box<L>) {
+        lock.lock_exclusive();
+        lock.unlock_exclusive();
+        self.free.lock().push(lock);
+    }
+
+    /// Like [`Locks::retire`], waiting asynchronously, and also hands back the removed value
+    /// once no guard can be pointing at it anymore.
+    ///
+    /// If the future is dropped before then, the value is dropped too if that turns out to be
+    /// safe, and otherwise leaked along with the lock rather than freed under the guards.
+    ///
+    /// # Safety
+    /// See [`Locks::retire`]. `value` must be the removed value `lock` guarded.
+    pub(crate) async unsafe fn retire_async<V>(&self, lock: Box<L>, value: Box<V>) -> Box<V> {
+        struct Retiring<'a, K: Key, L: RawLock, E, V> {
+            locks: &'a Locks<K, L, E>,
+            lock: Option<Box<L>>,
+            value: Option<Box<V>>,
+        }
+
+        impl<K: Key, L: RawLock, E, V> Drop for Retiring<'_, K, L, E, V> {
+            fn drop(&mut self) {
+                let (Some(lock), Some(value)) = (self.lock.take(), self.value.take()) else {
+                    return;
+                };
+                if lock.try_lock_exclusive() {
+                    unsafe { lock.unlock_exclusive() };
+                    self.locks.free.lock().push(lock);
+                    drop(value);
+                } else {
+                    Box::leak(lock);
+                    Box::leak(value);
+                }
+            }
+        }
+
+        let mut retiring = Retiring {
+            locks: self,
+            lock: Some(lock),
+            value: Some(value),
+        };
+        Access::Exclusive
+            .lock_async(&**retiring.lock.as_ref().unwrap(), self.spins())
+            .await;
+        let lock = retiring.lock.take().unwrap();
+        lock.unlock_exclusive();
+        self.free.lock().push(lock);
+        retiring.value.take().unwrap()
+    }
+
+    /// Locks `key`'s element lock for `access` and returns the value `lookup` finds for it.
+    ///
+    /// If the element lock is busy, it is waited on without holding `structure` and the key
+    /// is looked up again once it is acquired, in case the entry was removed meanwhile.
+    ///
+    /// # Safety
+    /// `lookup` is called with `structure` held shared and must find the value of every key
+    /// that has an element lock.
+    pub(crate) unsafe fn acquire<V>(
+        &self,
+        key: K,
+        access: Access,
+        lookup: impl Fn() -> Option<*mut V>,
+    ) -> Option<Acquired<V, L>> {
+        let global = self.global.shard(key);
+        let structure = self.structure.shard(key);
+        global.lock_shared();
+        loop {
+            structure.lock_shared();
+            let Some(lock) = self.elements().get(key) else {
+                structure.unlock_shared();
+                global.unlock_shared();
+                return None;
+            };
+            if !access.try_lock(lock) {
+                structure.unlock_shared();
+                self.metrics.contended(|| access.lock(lock));
+                // Recursively, so that `lock` isn't kept held while queueing up behind a
+                // writer, which would hold up everyone waiting for `lock` as well.
+                structure.lock_shared_recursive();
+                if !self.is_current(key, lock) {
+                    structure.unlock_shared();
+                    access.unlock(lock);
+                    continue;
+                }
+            }
+            return self.finish(global, structure, lock, access, lookup);
+        }
+    }
+
+    /// Calls `f` on every entry `entries` yields with its element lock held shared, until `f`
+    /// breaks, and returns what it broke with.
+    ///
+    /// `entries` is gone through with a `global` shard and `structure` held shared, but the
+    /// element locks are only tried: waiting for one with `structure` held would deadlock
+    /// against a writer queued on `structure` and the guard holding that element lock, which
+    /// has to retake `structure` before it can return (see [`Locks::acquire`]). Once an entry
+    /// turns out to be busy, the keys left are collected and `structure` is released, and each
+    /// of them is then read with [`Locks::read_held`], skipping any removed in the meantime.
+    ///
+    /// # Safety
+    /// `entries` must go through the values of the map these are the locks of, and `lookup`
+    /// must find them like the one passed to [`Locks::acquire`].
+    pub(crate) unsafe fn scan<'a, V: 'a, I, B>(
+        &self,
+        entries: impl FnOnce() -> I,
+        lookup: impl Fn(K) -> Option<*mut V>,
+        mut f: impl FnMut(K, &V) -> ControlFlow<B>,
+    ) -> Option<B>
+    where
+        I: Iterator<Item = (K, &'a V)>,
+    {
+        let global = self.global.any_shard();
+        let structure = self.structure.any_shard();
+        global.lock_shared();
+        // Everything is released if `f` panics.
+        let _global = Held::new(global, Access::Shared);
+        structure.lock_shared();
+        let held_structure = Held::new(structure, Access::Shared);
+        let mut entries = entries();
+        let busy = loop {
+            let (key, value) = entries.next()?;
+            let lock = self.elements().get(key).unwrap();
+            if !lock.try_lock_shared() {
+                break key;
+            }
+            let held = Held::new(lock, Access::Shared);
+            let flow = f(key, value);
+            drop(held);
+            if let ControlFlow::Break(found) = flow {
+                return Some(found);
+            }
+        };
+        let rest: Vec<K> = core::iter::once(busy)
+            .chain(entries.map(|(key, _)| key))
+            .collect();
+        drop(held_structure);
+        for key in rest {
+            if let Some(ControlFlow::Break(found)) =
+                self.read_held(key, || lookup(key), |value| f(key, value))
+            {
+                return Some(found);
+            }
+        }
+        None
+    }
+
+    /// Calls `f` on the value `lookup` finds for `key` with its element lock held shared, like
+    /// [`Locks::acquire`] but releasing the element lock again as soon as `f` returns.
+    ///
+    /// # Safety
+    /// See [`Locks::acquire`]. The caller must already hold a global shard shared, which is
+    /// left as it is.
+    pub(crate) unsafe fn read_held<V, R>(
+        &self,
+        key: K,
+        lookup: impl Fn() -> Option<*mut V>,
+        f: impl FnOnce(&V) -> R,
+    ) -> Option<R> {
+        let structure = self.structure.shard(key);
+        loop {
+            structure.lock_shared();
+            let Some(lock) = self.elements().get(key) else {
+                structure.unlock_shared();
+                return None;
+            };
+            if !lock.try_lock_shared() {
+                structure.unlock_shared();
+                self.metrics.contended(|| lock.lock_shared());
+                structure.lock_shared_recursive();
+                if !self.is_current(key, lock) {
+                    structure.unlock_shared();
+                    lock.unlock_shared();
+                    continue;
+                }
+            }
+            let value = lookup();
+            structure.unlock_shared();
+            let _held = Held::new(lock, Access::Shared);
+            return value.map(|value| f(&*value));
+        }
+    }
+
+    /// Like [`Locks::acquire`] with shared access, but takes the global shard and element lock
+    /// recursively, so that it doesn't queue up behind a writer which is itself waiting for a
+    /// shared hold the calling thread already has.
+    ///
+    /// The global shard is always the last one rather than `key`'s: a writer waiting for the
+    /// shard a guard of the calling thread holds may already have the shards before it, but
+    /// never the last one.
+    ///
+    /// # Safety
+    /// See [`Locks::acquire`].
+    pub(crate) unsafe fn acquire_recursive<V>(
+        &self,
+        key: K,
+        lookup: impl Fn() -> Option<*mut V>,
+    ) -> Option<Acquired<V, L>> {
+        let global = self.global.last_shard();
+        let structure = self.structure.shard(key);
+        global.lock_shared_recursive();
+        loop {
+            structure.lock_shared();
+            let Some(lock) = self.elements().get(key) else {
+                structure.unlock_shared();
+                global.unlock_shared();
+                return None;
+            };
+            if !lock.try_lock_shared_recursive() {
+                structure.unlock_shared();
+                self.metrics.contended(|| lock.lock_shared_recursive());
+                structure.lock_shared_recursive();
+                if !self.is_current(key, lock) {
+                    structure.unlock_shared();
+                    lock.unlock_shared();
+                    continue;
+                }
+            }
+            return self.finish(global, structure, lock, Access::Shared, lookup);
+        }
+    }
+
+    /// Like [`Locks::acquire`], giving up with `None` once `timeout` has passed.
+    ///
+    /// # Safety
+    /// See [`Locks::acquire`].
+    #[cfg(feature = "std")]
+    pub(crate) unsafe fn try_acquire_for<V>(
+        &self,
+        key: K,
+        access: Access,
+        timeout: Duration,
+        lookup: impl Fn() -> Option<*mut V>,
+    ) -> Option<Option<Acquired<V, L>>> {
+        let deadline = Deadline::after(timeout);
+        let global = self.global.shard(key);
+        let structure = self.structure.shard(key);
+        if !deadline.try_lock_shared(global) {
+            return None;
+        }
+        loop {
+            if !deadline.try_lock_shared(structure) {
+                global.unlock_shared();
+                return None;
+            }
+            let Some(lock) = self.elements().get(key) else {
+                structure.unlock_shared();
+                global.unlock_shared();
+                return Some(None);
+            };
+            if !access.try_lock(lock) {
+                structure.unlock_shared();
+                if !self
+                    .metrics
+                    .contended(|| access.try_lock_until(lock, deadline))
+                {
+                    global.unlock_shared();
+                    return None;
+                }
+                if !deadline.try_lock_shared(structure) {
+                    access.unlock(lock);
+                    global.unlock_shared();
+                    return None;
+                }
+                if !self.is_current(key, lock) {
+                    structure.unlock_shared();
+                    access.unlock(lock);
+                    continue;
+                }
+            }
+            return Some(self.finish(global, structure, lock, access, lookup));
+        }
+    }
+
+    /// Like [`Locks::acquire`], waiting on the locks asynchronously.
+    ///
+    /// # Safety
+    /// See [`Locks::acquire`].
+    pub(crate) async unsafe fn acquire_async<V>(
+        &self,
+        key: K,
+        access: Access,
+        lookup: impl Fn() -> Option<*mut V>,
+    ) -> Option<Acquired<V, L>> {
+        let global = self.global.shard(key);
+        let structure = self.structure.shard(key);
+        Access::Shared.lock_async(global, self.spins()).await;
+        // Everything taken so far is released if the future is dropped at a later `.await`.
+        let held_global = Held::new(global, Access::Shared);
+        loop {
+            Access::Shared.lock_async(structure, self.spins()).await;
+            let Some(lock) = self.elements().get(key) else {
+                structure.unlock_shared();
+                return None;
+            };
+            if !access.try_lock(lock) {
+                structure.unlock_shared();
+                access.lock_async(lock, self.spins()).await;
+                let held_lock = Held::new(lock, access);
+                // Recursively, like in `acquire`.
+                lock_shared_recursive_async(structure, self.spins()).await;
+                if !self.is_current(key, lock) {
+                    structure.unlock_shared();
+                    continue;
+                }
+                held_lock.keep();
+            }
+            held_global.keep();
+            return self.finish(global, structure, lock, access, lookup);
+        }
+    }
+
+    /// Locks the element locks of every key in `keys` for `access` and returns what
+    /// `lookup` finds for each, in the same order, or `None` if any key is missing.
+    ///
+    /// The element locks are taken in address order, so callers locking overlapping sets of
+    /// keys can't deadlock each other. Like [`Locks::acquire`], they are waited on without
+    /// holding `structure` and looked up again afterwards. Every result holds its own key's
+    /// global shard, like a guard from [`Locks::acquire`].
+    ///
+    /// # Safety
+    /// See [`Locks::acquire`]. `keys` must not contain duplicates.
+    pub(crate) unsafe fn acquire_all<V>(
+        &self,
+        keys: &[K],
+        access: Access,
+        lookup: impl Fn(K) -> Option<*mut V>,
+    ) -> Option<Vec<Acquired<V, L>>> {
+        if keys.is_empty() {
+            return Some(Vec::new());
+        }
+        let global = self.global.any_shard();
+        let structure = self.structure.any_shard();
+        global.lock_shared();
+        let mut retry = 0;
+        let locks = loop {
+            structure.lock_shared();
+            let Some(locks) = keys
+                .iter()
+                .map(|&key| self.elements().get(key))
+                .collect::<Option<Vec<_>>>()
+            else {
+                structure.unlock_shared();
+                global.unlock_shared();
+                return None;
+            };
+            structure.unlock_shared();
+            let mut ordered = locks.clone();
+            ordered.sort_by_key(|lock| *lock as *const L);
+            let order = Ordered::begin();
+            for lock in &ordered {
+                order.taking(*lock);
+            }
+            for &lock in &ordered {
+                if !access.try_lock(lock) {
+                    self.metrics.contended(|| access.lock(lock));
+                }
+            }
+            // Recursively, like in `acquire`.
+            structure.lock_shared_recursive();
+            if keys
+                .iter()
+                .zip(&locks)
+                .all(|(&key, lock)| self.is_current(key, lock))
+            {
+                break locks;
+            }
+            structure.unlock_shared();
+            for lock in &ordered {
+                access.unlock(*lock);
+            }
+            self.back_off(&mut retry);
+        };
+        self.finish_all(global, structure, keys, locks, access, lookup)
+    }
+
+    /// Like [`Locks::acquire_all`], giving up with `None` once `deadline` has passed. Every
+    /// element lock taken so far is released before giving up.
+    ///
+    /// # Safety
+    /// See [`Locks::acquire_all`].
+    #[cfg(feature = "std")]
+    pub(crate) unsafe fn try_acquire_all_until<V>(
+        &self,
+        keys: &[K],
+        access: Access,
+        deadline: Deadline,
+        lookup: impl Fn(K) -> Option<*mut V>,
+    ) -> Option<Option<Vec<Acquired<V, L>>>> {
+        if keys.is_empty() {
+            return Some(Some(Vec::new()));
+        }
+        let global = self.global.any_shard();
+        let structure = self.structure.any_shard();
+        if !deadline.try_lock_shared(global) {
+            return None;
+        }
+        let mut retry = 0;
+        let locks = loop {
+            if !deadline.try_lock_shared(structure) {
+                global.unlock_shared();
+                return None;
+            }
+            let Some(locks) = keys
+                .iter()
+                .map(|&key| self.elements().get(key))
+                .collect::<Option<Vec<_>>>()
+            else {
+                structure.unlock_shared();
+                global.unlock_shared();
+                return Some(None);
+            };
+            structure.unlock_shared();
+            let mut ordered = locks.clone();
+            ordered.sort_by_key(|lock| *lock as *const L);
+            let order = Ordered::begin();
+            for lock in &ordered {
+                order.taking(*lock);
+            }
+            let locked = ordered
+                .iter()
+                .take_while(|&&lock| access.try_lock_until(lock, deadline))
+                .count();
+            if locked < ordered.len() || !deadline.try_lock_shared(structure) {
+                for lock in &ordered[..locked] {
+                    access.unlock(*lock);
+                }
+                global.unlock_shared();
+                return None;
+            }
+            if keys
+                .iter()
+                .zip(&locks)
+                .all(|(&key, lock)| self.is_current(key, lock))
+            {
+                break locks;
+            }
+            structure.unlock_shared();
+            for lock in &ordered {
+                access.unlock(*lock);
+            }
+            self.back_off(&mut retry);
+        };
+        Some(self.finish_all(global, structure, keys, locks, access, lookup))
+    }
+
+    // Hands each result a share of its key's global shard, so each can release its own, and
+    // releases `global`.
+    unsafe fn finish_all<V>(
+        &self,
+        global: &L,
+        structure: &L,
+        keys: &[K],
+        locks: Vec<&L>,
+        access: Access,
+        lookup: impl Fn(K) -> Option<*mut V>,
+    ) -> Option<Vec<Acquired<V, L>>> {
+        let values = keys
+            .iter()
+            .map(|&key| lookup(key))
+            .collect::<Option<Vec<_>>>();
+        structure.unlock_shared();
+        let Some(values) = values else {
+            for lock in locks {
+                access.unlock(lock);
+            }
+            global.unlock_shared();
+            return None;
+        };
+        let acquired = keys
+            .iter()
+            .zip(locks)
+            .zip(values)
+            .map(|((&key, lock), value)| {
+                let shard = self.global.lock_shard_from_any(key);
+                (self.guard_locks(shard, lock), value)
+            })
+            .collect();
+        global.unlock_shared();
+        Some(acquired)
+    }
+
+    unsafe fn finish<V>(
+        &self,
+        global: &L,
+        structure: &L,
+        lock: &L,
+        access: Access,
+        lookup: impl Fn() -> Option<*mut V>,
+    ) -> Option<Acquired<V, L>> {
+        let value = lookup();
+        structure.unlock_shared();
+        let Some(value) = value else {
+            access.unlock(lock);
+            global.unlock_shared();
+            return None;
+        };
+        Some((self.guard_locks(global, lock), value))
+    }
+}
@@ -0,0 +1,90 @@
+//! Hooks for exporting a map's activity to a metrics library, see [`Metrics`].
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use crate::raw_lock::RwLock;
+
+/// Told about what a map is doing, registered with
+/// [`SyncSlotMap::set_metrics`](crate::SyncSlotMap::set_metrics), e.g. to count inserts or
+/// record lock waits in a histogram.
+///
+/// Every method does nothing by default, so an implementation only needs the ones it wants.
+/// They are called on whichever thread is using the map, some of them with the map locked, so
+/// they should be quick and must not use the map themselves, which could deadlock.
+pub trait Metrics: Send + Sync {
+    /// A thread waited `waited` for a lock it found busy, or for the whole map.
+    ///
+    /// Only called with `std`, which has a clock to measure the wait with (and not on wasm32,
+    /// where it doesn't).
+    fn on_lock_wait(&self, waited: Duration) {
+        let _ = waited;
+    }
+
+    /// An entry was inserted.
+    fn on_insert(&self) {}
+
+    /// An entry was removed.
+    fn on_remove(&self) {}
+
+    /// A thread found an entry's lock busy and has to wait for it.
+    fn on_contention(&self) {}
+}
+
+/// Where a map keeps its [`Metrics`], if it has any.
+pub(crate) struct MetricsSlot {
+    // Checked first so that maps without metrics don't touch the lock.
+    set: AtomicBool,
+    metrics: RwLock<Option<Arc<dyn Metrics>>>,
+}
+
+impl MetricsSlot {
+    pub(crate) fn new() -> Self {
+        Self {
+            set: AtomicBool::new(false),
+            metrics: RwLock::new(None),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Option<Arc<dyn Metrics>> {
+        self.metrics.read().clone()
+    }
+
+    pub(crate) fn set(&self, metrics: Option<Arc<dyn Metrics>>) {
+        let mut slot = self.metrics.write();
+        self.set.store(metrics.is_some(), Ordering::Relaxed);
+        *slot = metrics;
+    }
+
+    #[inline]
+    pub(crate) fn with(&self, f: impl FnOnce(&dyn Metrics)) {
+        if !self.set.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(metrics) = &*self.metrics.read() {
+            f(&**metrics);
+        }
+    }
+
+    /// Runs `wait`, which waits for a lock, reporting how long that took.
+    #[inline]
+    pub(crate) fn time<R>(&self, wait: impl FnOnce() -> R) -> R {
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+        if self.set.load(Ordering::Relaxed) {
+            let start = std::time::Instant::now();
+            let result = wait();
+            let waited = start.elapsed();
+            self.with(|metrics| metrics.on_lock_wait(waited));
+            return result;
+        }
+        wait()
+    }
+
+    /// Like [`MetricsSlot::time`], for a wait on an entry's lock which was found busy.
+    #[inline]
+    pub(crate) fn contended<R>(&self, wait: impl FnOnce() -> R) -> R {
+        self.with(|metrics| metrics.on_contention());
+        self.time(wait)
+    }
+}
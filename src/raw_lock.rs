@@ -0,0 +1,684 @@
+//! The lock primitives everything else is built on, picked by feature: parking_lot's by
+//! default, ones built on `std::sync` with the `std-locks` feature, or spinlocks that work
+//! without `std` with the `spin-locks` feature.
+
+#[cfg(not(any(feature = "parking_lot", feature = "std-locks", feature = "spin-locks")))]
+compile_error!(
+    "sync-slotmap needs one of the `parking_lot`, `std-locks` or `spin-locks` features enabled."
+);
+
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(feature = "std")]
+use lock_api::RawRwLockTimed;
+use lock_api::{RawRwLockDowngrade, RawRwLockFair, RawRwLockRecursive};
+
+#[cfg(not(any(feature = "std-locks", feature = "spin-locks")))]
+pub(crate) use parking_lot::RawMutex;
+#[cfg(not(any(feature = "std-locks", feature = "spin-locks")))]
+pub use parking_lot::RawRwLock as DefaultRawLock;
+
+#[cfg(feature = "std-locks")]
+pub(crate) use self::std_locks::RawMutex;
+#[cfg(feature = "std-locks")]
+pub use self::std_locks::RawRwLock as DefaultRawLock;
+
+#[cfg(all(feature = "spin-locks", not(feature = "std-locks")))]
+pub(crate) use self::spin_locks::RawMutex;
+#[cfg(all(feature = "spin-locks", not(feature = "std-locks")))]
+pub use self::spin_locks::RawRwLock as DefaultRawLock;
+#[cfg(feature = "spin-locks")]
+pub use self::spin_locks::RawRwLock as SpinRawLock;
+
+pub(crate) type Mutex<T> = lock_api::Mutex<RawMutex, T>;
+pub(crate) type RwLock<T> = lock_api::RwLock<DefaultRawLock, T>;
+
+/// The reader-writer lock the maps and their guards are built from.
+///
+/// Every map takes one as a type parameter defaulting to [`DefaultRawLock`], so a custom
+/// implementation (e.g. one that records contention) can be swapped in with
+/// `SyncSlotMap<K, V, MyLock>`. It's implemented for the lock types of parking_lot and of the
+/// `std-locks` and `spin-locks` features.
+///
+/// # Safety
+/// Implementations must actually provide mutual exclusion: while the lock is held
+/// exclusively, no other shared or exclusive hold may be granted.
+pub unsafe trait RawLock: Send + Sync + 'static {
+    /// An unlocked lock.
+    const INIT: Self;
+
+    fn lock_shared(&self);
+
+    fn try_lock_shared(&self) -> bool;
+
+    /// Like [`RawLock::try_lock_shared`], but keeps trying until `deadline`. Defaults to
+    /// retrying `try_lock_shared`, yielding the thread in between, which a lock that can block
+    /// a thread should override.
+    #[cfg(feature = "std")]
+    fn try_lock_shared_until(&self, deadline: Instant) -> bool {
+        retry_until(deadline, || self.try_lock_shared())
+    }
+
+    /// Takes a shared hold even if a writer is waiting, as long as the caller already holds
+    /// one, so that it can't deadlock against that writer.
+    fn lock_shared_recursive(&self);
+
+    /// Like [`RawLock::lock_shared_recursive`], but gives up instead of blocking.
+    fn try_lock_shared_recursive(&self) -> bool;
+
+    /// # Safety
+    /// The lock must be held shared.
+    unsafe fn unlock_shared(&self);
+
+    fn lock_exclusive(&self);
+
+    fn try_lock_exclusive(&self) -> bool;
+
+    /// Like [`RawLock::try_lock_exclusive`], but keeps trying until `deadline`. Defaults to
+    /// retrying like [`RawLock::try_lock_shared_until`].
+    #[cfg(feature = "std")]
+    fn try_lock_exclusive_until(&self, deadline: Instant) -> bool {
+        retry_until(deadline, || self.try_lock_exclusive())
+    }
+
+    /// # Safety
+    /// The lock must be held exclusively.
+    unsafe fn unlock_exclusive(&self);
+
+    /// Like [`RawLock::unlock_shared`], but hands the lock straight to a waiting thread, if
+    /// any, instead of letting whichever thread comes along first barge in. Defaults to a plain
+    /// unlock.
+    ///
+    /// # Safety
+    /// The lock must be held shared.
+    unsafe fn unlock_shared_fair(&self) {
+        self.unlock_shared()
+    }
+
+    /// Like [`RawLock::unlock_exclusive`], handing the lock over like
+    /// [`RawLock::unlock_shared_fair`]. Defaults to a plain unlock.
+    ///
+    /// # Safety
+    /// The lock must be held exclusively.
+    unsafe fn unlock_exclusive_fair(&self) {
+        self.unlock_exclusive()
+    }
+
+    /// Turns an exclusive hold into a shared one, without letting any other writer in.
+    ///
+    /// # Safety
+    /// The lock must be held exclusively.
+    unsafe fn downgrade(&self);
+
+    fn is_locked(&self) -> bool;
+
+    fn is_locked_exclusive(&self) -> bool;
+
+    /// Notified whenever the lock is unlocked, for the `*_async` methods to wait on rather than
+    /// trying the lock again every time they are polled. Defaults to `None`.
+    #[cfg(feature = "tokio")]
+    fn unlocked(&self) -> Option<&tokio::sync::Notify> {
+        None
+    }
+}
+
+#[cfg(feature = "std")]
+fn retry_until(deadline: Instant, mut try_lock: impl FnMut() -> bool) -> bool {
+    loop {
+        if try_lock() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::yield_now();
+    }
+}
+
+macro_rules! impl_raw_lock {
+    ($lock:ty) => {
+        unsafe impl RawLock for $lock {
+            #[allow(clippy::declare_interior_mutable_const)]
+            const INIT: Self = <$lock as lock_api::RawRwLock>::INIT;
+
+            fn lock_shared(&self) {
+                lock_api::RawRwLock::lock_shared(self)
+            }
+
+            fn try_lock_shared(&self) -> bool {
+                lock_api::RawRwLock::try_lock_shared(self)
+            }
+
+            #[cfg(feature = "std")]
+            fn try_lock_shared_until(&self, deadline: Instant) -> bool {
+                RawRwLockTimed::try_lock_shared_until(self, deadline)
+            }
+
+            fn lock_shared_recursive(&self) {
+                RawRwLockRecursive::lock_shared_recursive(self)
+            }
+
+            fn try_lock_shared_recursive(&self) -> bool {
+                RawRwLockRecursive::try_lock_shared_recursive(self)
+            }
+
+            unsafe fn unlock_shared(&self) {
+                lock_api::RawRwLock::unlock_shared(self)
+            }
+
+            fn lock_exclusive(&self) {
+                lock_api::RawRwLock::lock_exclusive(self)
+            }
+
+            fn try_lock_exclusive(&self) -> bool {
+                lock_api::RawRwLock::try_lock_exclusive(self)
+            }
+
+            #[cfg(feature = "std")]
+            fn try_lock_exclusive_until(&self, deadline: Instant) -> bool {
+                RawRwLockTimed::try_lock_exclusive_until(self, deadline)
+            }
+
+            unsafe fn unlock_exclusive(&self) {
+                lock_api::RawRwLock::unlock_exclusive(self)
+            }
+
+            unsafe fn unlock_shared_fair(&self) {
+                RawRwLockFair::unlock_shared_fair(self)
+            }
+
+            unsafe fn unlock_exclusive_fair(&self) {
+                RawRwLockFair::unlock_exclusive_fair(self)
+            }
+
+            unsafe fn downgrade(&self) {
+                RawRwLockDowngrade::downgrade(self)
+            }
+
+            fn is_locked(&self) -> bool {
+                lock_api::RawRwLock::is_locked(self)
+            }
+
+            fn is_locked_exclusive(&self) -> bool {
+                lock_api::RawRwLock::is_locked_exclusive(self)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "parking_lot")]
+impl_raw_lock!(parking_lot::RawRwLock);
+#[cfg(feature = "std-locks")]
+impl_raw_lock!(std_locks::RawRwLock);
+#[cfg(feature = "spin-locks")]
+impl_raw_lock!(spin_locks::RawRwLock);
+
+#[cfg(feature = "std-locks")]
+mod std_locks {
+    use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+    use std::time::Instant;
+
+    use lock_api::{
+        GuardSend, RawRwLockDowngrade, RawRwLockFair, RawRwLockRecursive, RawRwLockTimed,
+    };
+
+    struct State {
+        readers: usize,
+        writer: bool,
+        waiting_writers: usize,
+    }
+
+    /// A reader-writer lock built from a `std` mutex and condvar, used by the `std-locks`
+    /// feature.
+    ///
+    /// Waiting writers keep new readers out so they can't be starved, except for
+    /// `lock_shared_recursive`, which only waits for an active writer.
+    ///
+    /// Unlike `std::sync::RwLock`, it doesn't poison: a guard dropped while its thread panics,
+    /// or a map method whose closure panics, unlocks the entry like any other, and nothing
+    /// records that it happened, so there's no `is_poisoned` to check afterwards. That's on
+    /// purpose, to keep the maps behaving the same whichever backend they're built with. A
+    /// closure that could leave a value half-updated has to catch its own panic if that matters.
+    pub struct RawRwLock {
+        state: Mutex<State>,
+        changed: Condvar,
+    }
+
+    impl RawRwLock {
+        // The state is only ever touched in short sections that can't panic, so poisoning can
+        // be ignored.
+        fn state(&self) -> MutexGuard<'_, State> {
+            self.state.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        fn wait<'a>(
+            &self,
+            state: MutexGuard<'a, State>,
+            deadline: Option<Instant>,
+        ) -> Option<MutexGuard<'a, State>> {
+            match deadline {
+                None => Some(
+                    self.changed
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner),
+                ),
+                Some(deadline) => {
+                    let timeout = deadline.checked_duration_since(Instant::now())?;
+                    let (state, _) = self
+                        .changed
+                        .wait_timeout(state, timeout)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    Some(state)
+                }
+            }
+        }
+
+        fn lock_shared_until(&self, deadline: Option<Instant>, recursive: bool) -> bool {
+            let mut state = self.state();
+            while state.writer || (!recursive && state.waiting_writers > 0) {
+                match self.wait(state, deadline) {
+                    Some(next) => state = next,
+                    None => return false,
+                }
+            }
+            state.readers += 1;
+            true
+        }
+
+        fn lock_exclusive_until(&self, deadline: Option<Instant>) -> bool {
+            let mut state = self.state();
+            state.waiting_writers += 1;
+            while state.writer || state.readers > 0 {
+                match self.wait(state, deadline) {
+                    Some(next) => state = next,
+                    None => {
+                        let mut state = self.state();
+                        state.waiting_writers -= 1;
+                        drop(state);
+                        self.changed.notify_all();
+                        return false;
+                    }
+                }
+            }
+            state.waiting_writers -= 1;
+            state.writer = true;
+            true
+        }
+    }
+
+    unsafe impl lock_api::RawRwLock for RawRwLock {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = Self {
+            state: Mutex::new(State {
+                readers: 0,
+                writer: false,
+                waiting_writers: 0,
+            }),
+            changed: Condvar::new(),
+        };
+
+        type GuardMarker = GuardSend;
+
+        fn lock_shared(&self) {
+            self.lock_shared_until(None, false);
+        }
+
+        fn try_lock_shared(&self) -> bool {
+            let mut state = self.state();
+            if state.writer || state.waiting_writers > 0 {
+                return false;
+            }
+            state.readers += 1;
+            true
+        }
+
+        unsafe fn unlock_shared(&self) {
+            let mut state = self.state();
+            state.readers -= 1;
+            if state.readers == 0 {
+                drop(state);
+                self.changed.notify_all();
+            }
+        }
+
+        fn lock_exclusive(&self) {
+            self.lock_exclusive_until(None);
+        }
+
+        fn try_lock_exclusive(&self) -> bool {
+            let mut state = self.state();
+            if state.writer || state.readers > 0 {
+                return false;
+            }
+            state.writer = true;
+            true
+        }
+
+        unsafe fn unlock_exclusive(&self) {
+            self.state().writer = false;
+            self.changed.notify_all();
+        }
+
+        fn is_locked(&self) -> bool {
+            let state = self.state();
+            state.writer || state.readers > 0
+        }
+
+        fn is_locked_exclusive(&self) -> bool {
+            self.state().writer
+        }
+    }
+
+    unsafe impl RawRwLockTimed for RawRwLock {
+        type Duration = std::time::Duration;
+        type Instant = Instant;
+
+        fn try_lock_shared_for(&self, timeout: Self::Duration) -> bool {
+            self.lock_shared_until(Some(Instant::now() + timeout), false)
+        }
+
+        fn try_lock_shared_until(&self, deadline: Self::Instant) -> bool {
+            self.lock_shared_until(Some(deadline), false)
+        }
+
+        fn try_lock_exclusive_for(&self, timeout: Self::Duration) -> bool {
+            self.lock_exclusive_until(Some(Instant::now() + timeout))
+        }
+
+        fn try_lock_exclusive_until(&self, deadline: Self::Instant) -> bool {
+            self.lock_exclusive_until(Some(deadline))
+        }
+    }
+
+    unsafe impl RawRwLockRecursive for RawRwLock {
+        fn lock_shared_recursive(&self) {
+            self.lock_shared_until(None, true);
+        }
+
+        fn try_lock_shared_recursive(&self) -> bool {
+            let mut state = self.state();
+            if state.writer {
+                return false;
+            }
+            state.readers += 1;
+            true
+        }
+    }
+
+    // Waiting writers already keep new readers out, and every waiter is woken on unlock, so
+    // there's nothing more a fair unlock could do here.
+    unsafe impl RawRwLockFair for RawRwLock {
+        unsafe fn unlock_shared_fair(&self) {
+            lock_api::RawRwLock::unlock_shared(self)
+        }
+
+        unsafe fn unlock_exclusive_fair(&self) {
+            lock_api::RawRwLock::unlock_exclusive(self)
+        }
+    }
+
+    unsafe impl RawRwLockDowngrade for RawRwLock {
+        unsafe fn downgrade(&self) {
+            let mut state = self.state();
+            state.writer = false;
+            state.readers = 1;
+            drop(state);
+            self.changed.notify_all();
+        }
+    }
+
+    /// A mutex built from a `std` mutex and condvar, see [`RawRwLock`].
+    pub(crate) struct RawMutex {
+        locked: Mutex<bool>,
+        changed: Condvar,
+    }
+
+    unsafe impl lock_api::RawMutex for RawMutex {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = Self {
+            locked: Mutex::new(false),
+            changed: Condvar::new(),
+        };
+
+        type GuardMarker = GuardSend;
+
+        fn lock(&self) {
+            let mut locked = self.locked.lock().unwrap_or_else(PoisonError::into_inner);
+            while *locked {
+                locked = self
+                    .changed
+                    .wait(locked)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            *locked = true;
+        }
+
+        fn try_lock(&self) -> bool {
+            let mut locked = self.locked.lock().unwrap_or_else(PoisonError::into_inner);
+            !std::mem::replace(&mut *locked, true)
+        }
+
+        unsafe fn unlock(&self) {
+            *self.locked.lock().unwrap_or_else(PoisonError::into_inner) = false;
+            self.changed.notify_one();
+        }
+    }
+}
+
+#[cfg(feature = "spin-locks")]
+mod spin_locks {
+    use core::hint::spin_loop;
+    #[cfg(not(feature = "std-locks"))]
+    use core::sync::atomic::AtomicBool;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    #[cfg(feature = "std")]
+    use std::time::Instant;
+
+    #[cfg(feature = "std")]
+    use lock_api::RawRwLockTimed;
+    use lock_api::{GuardSend, RawRwLockDowngrade, RawRwLockFair, RawRwLockRecursive};
+
+    const WRITER: usize = 1;
+    const READER: usize = 2;
+
+    /// A reader-writer spinlock which works without `std`, used by the `spin-locks` feature.
+    ///
+    /// Like the `std-locks` lock, waiting writers keep new readers out so they can't be starved,
+    /// except for `lock_shared_recursive`, which only waits for an active writer. Without `std`
+    /// there's no clock to time out against, so the timed lock methods are left out then.
+    ///
+    /// ```
+    /// use slotmap::DefaultKey;
+    /// use sync_slotmap::{SpinRawLock, SyncSlotMap};
+    ///
+    /// let map = SyncSlotMap::<DefaultKey, u32, SpinRawLock>::with_raw_lock();
+    /// let key = map.insert(1);
+    /// *map.get_mut(key).unwrap() += 1;
+    /// assert_eq!(*map.get(key).unwrap(), 2);
+    /// ```
+    pub struct RawRwLock {
+        // The `WRITER` bit, plus `READER` for every shared hold.
+        state: AtomicUsize,
+        waiting_writers: AtomicUsize,
+    }
+
+    impl RawRwLock {
+        fn try_lock_shared_if(&self, recursive: bool) -> bool {
+            if !recursive && self.waiting_writers.load(Ordering::Relaxed) > 0 {
+                return false;
+            }
+            let mut state = self.state.load(Ordering::Relaxed);
+            while state & WRITER == 0 {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state + READER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(current) => state = current,
+                }
+            }
+            false
+        }
+
+        /// Spins on `try_lock_exclusive` for as long as `keep_waiting` says to, counted as a
+        /// waiting writer all the while.
+        fn lock_exclusive_while(&self, mut keep_waiting: impl FnMut() -> bool) -> bool {
+            use lock_api::RawRwLock;
+
+            self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+            let locked = loop {
+                if self.try_lock_exclusive() {
+                    break true;
+                }
+                if !keep_waiting() {
+                    break false;
+                }
+                spin_loop();
+            };
+            self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+            locked
+        }
+    }
+
+    unsafe impl lock_api::RawRwLock for RawRwLock {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = Self {
+            state: AtomicUsize::new(0),
+            waiting_writers: AtomicUsize::new(0),
+        };
+
+        type GuardMarker = GuardSend;
+
+        fn lock_shared(&self) {
+            while !self.try_lock_shared_if(false) {
+                spin_loop();
+            }
+        }
+
+        fn try_lock_shared(&self) -> bool {
+            self.try_lock_shared_if(false)
+        }
+
+        unsafe fn unlock_shared(&self) {
+            self.state.fetch_sub(READER, Ordering::Release);
+        }
+
+        fn lock_exclusive(&self) {
+            self.lock_exclusive_while(|| true);
+        }
+
+        fn try_lock_exclusive(&self) -> bool {
+            self.state
+                .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        }
+
+        unsafe fn unlock_exclusive(&self) {
+            self.state.fetch_and(!WRITER, Ordering::Release);
+        }
+
+        fn is_locked(&self) -> bool {
+            self.state.load(Ordering::Relaxed) != 0
+        }
+
+        fn is_locked_exclusive(&self) -> bool {
+            self.state.load(Ordering::Relaxed) & WRITER != 0
+        }
+    }
+
+    #[cfg(feature = "std")]
+    unsafe impl RawRwLockTimed for RawRwLock {
+        type Duration = std::time::Duration;
+        type Instant = Instant;
+
+        fn try_lock_shared_for(&self, timeout: Self::Duration) -> bool {
+            self.try_lock_shared_until(Instant::now() + timeout)
+        }
+
+        fn try_lock_shared_until(&self, deadline: Self::Instant) -> bool {
+            loop {
+                if self.try_lock_shared_if(false) {
+                    return true;
+                }
+                if Instant::now() >= deadline {
+                    return false;
+                }
+                spin_loop();
+            }
+        }
+
+        fn try_lock_exclusive_for(&self, timeout: Self::Duration) -> bool {
+            self.try_lock_exclusive_until(Instant::now() + timeout)
+        }
+
+        fn try_lock_exclusive_until(&self, deadline: Self::Instant) -> bool {
+            self.lock_exclusive_while(|| Instant::now() < deadline)
+        }
+    }
+
+    unsafe impl RawRwLockRecursive for RawRwLock {
+        fn lock_shared_recursive(&self) {
+            while !self.try_lock_shared_if(true) {
+                spin_loop();
+            }
+        }
+
+        fn try_lock_shared_recursive(&self) -> bool {
+            self.try_lock_shared_if(true)
+        }
+    }
+
+    // Nobody is parked waiting for the lock to be handed to them, so a fair unlock is just an
+    // unlock.
+    unsafe impl RawRwLockFair for RawRwLock {
+        unsafe fn unlock_shared_fair(&self) {
+            lock_api::RawRwLock::unlock_shared(self)
+        }
+
+        unsafe fn unlock_exclusive_fair(&self) {
+            lock_api::RawRwLock::unlock_exclusive(self)
+        }
+    }
+
+    unsafe impl RawRwLockDowngrade for RawRwLock {
+        unsafe fn downgrade(&self) {
+            // No reader can get in while `WRITER` is set, so this turns it into exactly one.
+            self.state.fetch_add(READER - WRITER, Ordering::Release);
+        }
+    }
+
+    /// A spinlock mutex which works without `std`, see [`RawRwLock`].
+    #[cfg(not(feature = "std-locks"))]
+    pub(crate) struct RawMutex {
+        locked: AtomicBool,
+    }
+
+    #[cfg(not(feature = "std-locks"))]
+    unsafe impl lock_api::RawMutex for RawMutex {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = Self {
+            locked: AtomicBool::new(false),
+        };
+
+        type GuardMarker = GuardSend;
+
+        fn lock(&self) {
+            while !self.try_lock() {
+                spin_loop();
+            }
+        }
+
+        fn try_lock(&self) -> bool {
+            self.locked
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        }
+
+        unsafe fn unlock(&self) {
+            self.locked.store(false, Ordering::Release);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+#[cfg(feature = "std")]
+use std::time::Duration;
+
+use slotmap::Key;
+
+#[cfg(feature = "std")]
+use crate::deadline::Deadline;
+use crate::locks::{slot_index, Access};
+use crate::raw_lock::RawLock;
+
+pub(crate) const SHARDS: usize = 16;
+
+// Each shard gets its own cache line so readers on different shards don't bounce it between cores.
+#[repr(align(64))]
+struct Shard<L>(L);
+
+/// The global lock of a map, split into shards so readers of different keys don't all
+/// hammer the same lock word.
+///
+/// Readers take the shard picked by their key's slot index shared, which is enough to keep
+/// writers out since a writer has to take every shard exclusively (always in the same order).
+pub(crate) struct ShardedRwLock<L> {
+    shards: [Shard<L>; SHARDS],
+}
+
+impl<L: RawLock> ShardedRwLock<L> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            shards: [const { Shard(L::INIT) }; SHARDS],
+        }
+    }
+
+    pub(crate) fn shard<K: Key>(&self, key: K) -> &L {
+        &self.shards[slot_index(key) as usize % SHARDS].0
+    }
+
+    /// A shard for a reader that isn't after any one key, such as a scan. Holding any one shard
+    /// shared keeps writers out, so this is always the first: every such reader (and anything
+    /// that later unlocks or force-unlocks its hold) then agrees on which shard it took.
+    pub(crate) fn any_shard(&self) -> &L {
+        &self.shards[0].0
+    }
+
+    /// Takes `key`'s shard shared for a reader already holding [`ShardedRwLock::any_shard`]
+    /// shared, e.g. once the key of a guard is known. This never blocks: writers take that
+    /// shard first, so none of them can be holding another one.
+    pub(crate) fn lock_shard_from_any<K: Key>(&self, key: K) -> &L {
+        let shard = self.shard(key);
+        shard.lock_shared_recursive();
+        shard
+    }
+
+    /// The shard writers take last. Whoever holds it exclusively holds every shard, so it can
+    /// only be held that way while nobody holds any shard shared.
+    pub(crate) fn last_shard(&self) -> &L {
+        &self.shards[SHARDS - 1].0
+    }
+
+    /// Whether any shard is held or (for locks that mark it) waited on exclusively.
+    pub(crate) fn is_locked_exclusive(&self) -> bool {
+        self.shards
+            .iter()
+            .any(|shard| shard.0.is_locked_exclusive())
+    }
+
+    pub(crate) fn lock_exclusive(&self) {
+        for shard in &self.shards {
+            shard.0.lock_exclusive();
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        self.try_lock_exclusive_until(Deadline::after(timeout))
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn try_lock_exclusive_until(&self, deadline: Deadline) -> bool {
+        for (locked, shard) in self.shards.iter().enumerate() {
+            if !deadline.try_lock_exclusive(&shard.0) {
+                for shard in &self.shards[..locked] {
+                    unsafe { shard.0.unlock_exclusive() };
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn try_lock_exclusive(&self) -> bool {
+        for (locked, shard) in self.shards.iter().enumerate() {
+            if !shard.0.try_lock_exclusive() {
+                for shard in &self.shards[..locked] {
+                    unsafe { shard.0.unlock_exclusive() };
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) async fn lock_exclusive_async(&self, spins: u32) {
+        let mut held = ExclusiveShards {
+            lock: self,
+            locked: 0,
+        };
+        for shard in &self.shards {
+            Access::Exclusive.lock_async(&shard.0, spins).await;
+            held.locked += 1;
+        }
+        held.keep();
+    }
+
+    pub(crate) unsafe fn unlock_exclusive(&self) {
+        for shard in &self.shards {
+            shard.0.unlock_exclusive();
+        }
+    }
+
+    pub(crate) unsafe fn unlock_exclusive_fair(&self) {
+        for shard in &self.shards {
+            shard.0.unlock_exclusive_fair();
+        }
+    }
+}
+
+/// Unlocks the first `locked` shards of an exclusively locked [`ShardedRwLock`] when dropped,
+/// so that dropping a future halfway through an `.await` doesn't leave them locked forever.
+#[must_use]
+pub(crate) struct ExclusiveShards<'a, L: RawLock> {
+    lock: &'a ShardedRwLock<L>,
+    locked: usize,
+}
+
+impl<'a, L: RawLock> ExclusiveShards<'a, L> {
+    /// # Safety
+    /// Every shard of `lock` must be held exclusively.
+    pub(crate) unsafe fn all(lock: &'a ShardedRwLock<L>) -> Self {
+        Self {
+            lock,
+            locked: SHARDS,
+        }
+    }
+
+    /// Keeps the shards locked after all.
+    pub(crate) fn keep(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl<L: RawLock> Drop for ExclusiveShards<'_, L> {
+    fn drop(&mut self) {
+        for shard in &self.lock.shards[..self.locked] {
+            unsafe { shard.0.unlock_exclusive() };
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::ControlFlow;
+#[cfg(feature = "std")]
+use std::time::Duration;
+
+use slotmap::*;
+
+#[cfg(feature = "std")]
+use crate::deadline::Deadline;
+use crate::locks::{Access, Held, HeldExclusive, Locks};
+use crate::raw_lock::{DefaultRawLock, RawLock};
+use crate::sync_slot_map::{DropBehavior, SyncSlotGuard, SyncSlotGuardMut};
+
+/// A [`SyncSlotMap`](crate::SyncSlotMap) backed by `DenseSlotMap`, which keeps its values
+/// contiguous so iterating over them is much faster.
+///
+/// The tradeoff is in removal: it is still O(1), but it swaps the last value into the hole
+/// left behind, so values change address. Guards point straight at their value, so unlike
+/// `SyncSlotMap` (which boxes its values) `remove` here holds the global lock exclusively and
+/// waits for every guard to be dropped. `insert` only does that when the values have to be
+/// reallocated to make room, otherwise it locks like `SyncSlotMap::insert`; use
+/// [`SyncDenseSlotMap::reserve`] up front to keep long-lived guards from holding up inserts.
+pub struct SyncDenseSlotMap<K: Key, V, L: RawLock = DefaultRawLock> {
+    inner: UnsafeCell<DenseSlotMap<K, V>>,
+    locks: *mut Locks<K, L>,
+    // How many more values fit without reallocating, guarded by `structure` like `inner`.
+    // `DenseSlotMap` only tells the capacity of its keys, so this is tracked from what was
+    // reserved.
+    spare: UnsafeCell<usize>,
+}
+
+unsafe impl<K: Key, V, L: RawLock> Send for SyncDenseSlotMap<K, V, L> where V: Send {}
+unsafe impl<K: Key, V, L: RawLock> Sync for SyncDenseSlotMap<K, V, L> where V: Send + Sync {}
+
+impl<K: Key, V, L: RawLock> Drop for SyncDenseSlotMap<K, V, L> {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        let leak = || core::mem::forget(core::mem::take(inner));
+        if unsafe { &*self.locks }.lock_for_drop("SyncDenseSlotMap", leak) {
+            unsafe { drop(Box::from_raw(self.locks)) };
+        }
+    }
+}
+
+impl<K: Key, V, L: RawLock> Default for SyncDenseSlotMap<K, V, L> {
+    fn default() -> Self {
+        Self::with_raw_lock()
+    }
+}
+
+impl<K: Key, V> SyncDenseSlotMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_raw_lock()
+    }
+}
+
+impl<K: Key, V, L: RawLock> SyncDenseSlotMap<K, V, L> {
+    /// Creates an empty map which uses `L` for all of its locks (and its guards').
+    pub fn with_raw_lock() -> Self {
+        let locks = Box::new(Locks::new());
+        let locks = Box::into_raw(locks);
+        Self {
+            inner: UnsafeCell::new(DenseSlotMap::with_key()),
+            locks,
+            spare: UnsafeCell::new(0),
+        }
+    }
+
+    /// Sets whether guards (and whole-map operations) unlock fairly, handing their locks
+    /// straight to a waiting thread rather than letting whichever thread comes along first
+    /// barge in (see [`RawLock::unlock_shared_fair`]). Off by default.
+    ///
+    /// This is slower, but keeps a steady stream of readers from starving writers. It takes
+    /// effect immediately, including for guards which are already held.
+    pub fn set_fair(&self, fair: bool) {
+        unsafe { &*self.locks }.set_fair(fair);
+    }
+
+    /// Sets what dropping the map does while guards on it are still alive, like
+    /// [`SyncSlotMap::set_drop_behavior`](crate::SyncSlotMap::set_drop_behavior). The default
+    /// is to wait.
+    pub fn set_drop_behavior(&self, behavior: DropBehavior) {
+        unsafe { &*self.locks }.set_drop_behavior(behavior);
+    }
+
+    /// Sets how long dropping the map waits for guards before giving up, like
+    /// [`SyncSlotMap::set_drop_timeout`](crate::SyncSlotMap::set_drop_timeout). A second by
+    /// default.
+    #[cfg(feature = "std")]
+    pub fn set_drop_timeout(&self, timeout: Duration) {
+        unsafe { &*self.locks }.set_drop_timeout(timeout);
+    }
+
+    /// Sets how many more times the `*_async` methods try a busy lock, spinning in between,
+    /// before yielding to the async runtime. Zero (the default) yields straight away.
+    ///
+    /// Spinning a little lets locks which are only held briefly be taken without the task
+    /// going back to the runtime's queue, at the cost of keeping the thread busy meanwhile.
+    pub fn set_spin_count(&self, spins: u32) {
+        unsafe { &*self.locks }.set_spins(spins);
+    }
+
+    /// The spin count set with `set_spin_count`.
+    pub fn spin_count(&self) -> u32 {
+        unsafe { &*self.locks }.spins()
+    }
+
+    pub fn insert(&self, value: V) -> K {
+        let locks = unsafe { &*self.locks };
+        let global = locks.global.any_shard();
+        global.lock_shared();
+        locks.structure.lock_exclusive();
+        let value = unsafe { self.insert_in_place(value) };
+        unsafe {
+            locks.structure.unlock_exclusive();
+            global.unlock_shared();
+        }
+        let value = match value {
+            Ok(key) => return key,
+            Err(value) => value,
+        };
+        locks.lock_exclusive();
+        let key = unsafe { self.insert_growing(value) };
+        unsafe { locks.unlock_exclusive() };
+        key
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_insert_for(&self, value: V, timeout: std::time::Duration) -> Option<K> {
+        let locks = unsafe { &*self.locks };
+        let deadline = Deadline::after(timeout);
+        let global = locks.global.any_shard();
+        if !deadline.try_lock_shared(global) {
+            return None;
+        }
+        locks.structure.lock_exclusive();
+        let value = unsafe { self.insert_in_place(value) };
+        unsafe {
+            locks.structure.unlock_exclusive();
+            global.unlock_shared();
+        }
+        let value = match value {
+            Ok(key) => return Some(key),
+            Err(value) => value,
+        };
+        if !locks.global.try_lock_exclusive_until(deadline) {
+            return None;
+        }
+        // With no guards left, the structure lock is only ever held briefly.
+        locks.structure.lock_exclusive();
+        let key = unsafe { self.insert_growing(value) };
+        unsafe {
+            locks.structure.unlock_exclusive();
+            locks.global.unlock_exclusive();
+        }
+        Some(key)
+    }
+
+    pub async fn insert_async(&self, value: V) -> K {
+        let locks = unsafe { &*self.locks };
+        let global = locks.global.any_shard();
+        Access::Shared.lock_async(global, locks.spins()).await;
+        let held_global = unsafe { Held::new(global, Access::Shared) };
+        locks.structure.lock_exclusive_async(locks.spins()).await;
+        held_global.keep();
+        let value = unsafe { self.insert_in_place(value) };
+        unsafe {
+            locks.structure.unlock_exclusive();
+            global.unlock_shared();
+        }
+        let value = match value {
+            Ok(key) => return key,
+            Err(value) => value,
+        };
+        locks.lock_exclusive_async().await;
+        let key = unsafe { self.insert_growing(value) };
+        unsafe { locks.unlock_exclusive() };
+        key
+    }
+
+    /// Makes room for at least `additional` more values, like `DenseSlotMap::reserve`, so that
+    /// inserting them doesn't have to wait for guards.
+    ///
+    /// Growing moves the values, so this locks the whole map like
+    /// [`SyncDenseSlotMap::remove`] and waits for every guard to be dropped.
+    pub fn reserve(&self, additional: usize) {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        unsafe { self.reserve_locked(additional) };
+        unsafe { locks.unlock_exclusive() };
+    }
+
+    // Inserts `value` if there's room for it without moving the other values, which guards
+    // may be pointing at, handing it back otherwise. `structure` must be held exclusively.
+    unsafe fn insert_in_place(&self, value: V) -> Result<K, V> {
+        if *self.spare.get() == 0 {
+            return Err(value);
+        }
+        Ok(self.insert_locked(value))
+    }
+
+    // Inserts `value`, first doubling the room for values if there's none left. The whole map
+    // must be locked exclusively.
+    unsafe fn insert_growing(&self, value: V) -> K {
+        // Someone else may have made room while the map was unlocked.
+        if *self.spare.get() == 0 {
+            self.reserve_locked((*self.inner.get()).len().max(4));
+        }
+        self.insert_locked(value)
+    }
+
+    // The whole map must be locked exclusively.
+    unsafe fn reserve_locked(&self, additional: usize) {
+        (*self.inner.get()).reserve(additional);
+        let spare = &mut *self.spare.get();
+        *spare = (*spare).max(additional);
+    }
+
+    unsafe fn insert_locked(&self, value: V) -> K {
+        let key = (*self.inner.get()).insert(value);
+        *self.spare.get() -= 1;
+        (*self.locks).register(key);
+        key
+    }
+
+    pub fn remove(&self, key: K) -> Option<V> {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.unlock_exclusive() };
+        removed
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_remove_for(&self, key: K, timeout: std::time::Duration) -> Option<Option<V>> {
+        let locks = unsafe { &*self.locks };
+        if !locks.global.try_lock_exclusive_for(timeout) {
+            return None;
+        }
+        locks.structure.lock_exclusive();
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe {
+            locks.structure.unlock_exclusive();
+            locks.global.unlock_exclusive();
+        }
+        Some(removed)
+    }
+
+    pub async fn remove_async(&self, key: K) -> Option<V> {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive_async().await;
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.unlock_exclusive() };
+        removed
+    }
+
+    /// Frees the element locks of removed entries that are kept around for reuse, and shrinks
+    /// the table of element locks down to the highest slot still in use.
+    ///
+    /// The table is indexed by slot, so without this it stays as large as the map has ever
+    /// been. It waits for every guard to be dropped first, and nothing compacts automatically.
+    pub fn compact_locks(&self) {
+        unsafe { &*self.locks }.compact();
+    }
+
+    // With the global lock held exclusively nobody can be holding or waiting on the element
+    // lock, so retiring it doesn't block.
+    unsafe fn remove_locked(&self, key: K) -> Option<V> {
+        let value = (*self.inner.get()).remove(key)?;
+        *self.spare.get() += 1;
+        if let Some(lock) = (*self.locks).unregister(key) {
+            (*self.locks).retire(lock);
+        }
+        Some(value)
+    }
+
+    fn lookup(&self, key: K) -> Option<*mut V> {
+        unsafe { &*self.inner.get() }
+            .get(key)
+            .map(|value| value as *const V as *mut V)
+    }
+
+    pub fn get(&self, key: K) -> Option<SyncSlotGuard<V, L>> {
+        let (locks, value) =
+            unsafe { (*self.locks).acquire(key, Access::Shared, || self.lookup(key)) }?;
+        Some(SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    pub fn get_mut(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        let (locks, value) =
+            unsafe { (*self.locks).acquire(key, Access::Exclusive, || self.lookup(key)) }?;
+        Some(SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_get_for(
+        &self,
+        key: K,
+        timeout: std::time::Duration,
+    ) -> Option<Option<SyncSlotGuard<V, L>>> {
+        let acquired = unsafe {
+            (*self.locks).try_acquire_for(key, Access::Shared, timeout, || self.lookup(key))
+        }?;
+        Some(acquired.map(|(locks, value)| SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        }))
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_get_mut_for(
+        &self,
+        key: K,
+        timeout: std::time::Duration,
+    ) -> Option<Option<SyncSlotGuardMut<V, L>>> {
+        let acquired = unsafe {
+            (*self.locks).try_acquire_for(key, Access::Exclusive, timeout, || self.lookup(key))
+        }?;
+        Some(acquired.map(|(locks, value)| SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        }))
+    }
+
+    pub async fn get_async(&self, key: K) -> Option<SyncSlotGuard<V, L>> {
+        let (locks, value) = unsafe {
+            (*self.locks)
+                .acquire_async(key, Access::Shared, || self.lookup(key))
+                .await
+        }?;
+        Some(SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    pub async fn get_mut_async(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        let (locks, value) = unsafe {
+            (*self.locks)
+                .acquire_async(key, Access::Exclusive, || self.lookup(key))
+                .await
+        }?;
+        Some(SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    /// Calls `f` on every entry, in the order the values are stored in.
+    ///
+    /// Each entry is read-locked while `f` looks at it, so entries held by a
+    /// [`SyncSlotGuardMut`] are waited for, and removals wait until it's done. Inserts only
+    /// wait until it runs into such an entry, like with
+    /// [`SyncSlotMap::for_each`](crate::SyncSlotMap::for_each). Taking every entry's lock costs about as much as reading a small value, see
+    /// [`SyncDenseSlotMap::for_each_mut`] to skip that.
+    pub fn for_each<F: FnMut(K, &V)>(&self, mut f: F) {
+        unsafe {
+            (*self.locks).scan(
+                || (*self.inner.get()).iter(),
+                |key| self.lookup(key),
+                |key, value| {
+                    f(key, value);
+                    ControlFlow::<()>::Continue(())
+                },
+            )
+        };
+    }
+
+    /// Calls `f` on every entry with mutable access, in the order the values are stored in.
+    ///
+    /// The whole map is locked exclusively for the duration (so this waits for every guard to
+    /// be dropped and blocks everything else), which lets it skip the element locks and go
+    /// through the values at the speed of a plain `DenseSlotMap`.
+    pub fn for_each_mut<F: FnMut(K, &mut V)>(&self, mut f: F) {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        let _held = unsafe { HeldExclusive::new(locks) };
+        for (key, value) in unsafe { &mut *self.inner.get() }.iter_mut() {
+            f(key, value);
+        }
+    }
+
+    /// Returns an iterator of read guards over every entry, like
+    /// [`SyncSlotMap::iter`](crate::SyncSlotMap::iter).
+    ///
+    /// The keys are collected up front and each one is then locked as the iterator reaches it,
+    /// skipping any that were removed in the meantime. Use [`SyncDenseSlotMap::for_each`] to
+    /// go through the values faster.
+    pub fn iter(&self) -> impl Iterator<Item = (K, SyncSlotGuard<V, L>)> + '_ {
+        self.keys_snapshot()
+            .into_iter()
+            .filter_map(move |key| Some((key, self.get(key)?)))
+    }
+
+    /// Like [`SyncDenseSlotMap::iter`], without the keys.
+    pub fn values(&self) -> impl Iterator<Item = SyncSlotGuard<V, L>> + '_ {
+        self.iter().map(|(_, guard)| guard)
+    }
+
+    fn keys_snapshot(&self) -> Vec<K> {
+        let structure = unsafe { &*self.locks }.structure.any_shard();
+        structure.lock_shared();
+        let keys = unsafe { &*self.inner.get() }.keys().collect();
+        unsafe { structure.unlock_shared() };
+        keys
+    }
+}
@@ -1,274 +1,807 @@
-use std::ops::{Deref, DerefMut, Index};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::convert::Infallible;
+use core::future::Future;
+#[cfg(feature = "std")]
+use core::hash::{Hash, Hasher};
+#[cfg(feature = "std")]
+use core::ops::ControlFlow;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+#[cfg(feature = "std")]
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "std")]
+use std::time::Duration;
 
-use std::{cell::UnsafeCell, future::Future, task::Poll};
-
-use parking_lot::{
-    lock_api::{RawRwLock as RwLockTrait, RawRwLockTimed},
-    RawRwLock,
-};
 use slotmap::*;
 
-use crate::sync_slot_map::{
-    SyncSlotGuard, SyncSlotGuardMut, UnlockRwLockExclusive, UnlockRwLockShared,
-};
+use crate::lock_order::Ordered;
+use crate::locks::{try_read_both, Access, Held, HeldExclusive, Locks, Registered};
+use crate::raw_lock::{DefaultRawLock, Mutex, RawLock};
+use crate::sharded_lock::ExclusiveShards;
+use crate::sync_slot_map::{DropBehavior, SyncSlotGuard, SyncSlotGuardMut};
+
+pub struct SyncSecondarySlotMap<K: Key, V, L: RawLock = DefaultRawLock> {
+    inner: UnsafeCell<SecondaryMap<K, Box<V>>>,
+    pub(crate) locks: *mut Locks<K, L>,
+    len: AtomicUsize,
+    // The tasks (or threads) in `wait_for` and `wait_for_timeout`, woken by every insert.
+    waiting: Mutex<Waiters>,
+}
 
-pub struct SyncSecondarySlotMap<K: Key, V> {
-    inner: UnsafeCell<SecondaryMap<K, V>>,
-    locks: *mut (RawRwLock, SecondaryMap<K, RawRwLock>),
+// Each waiter gets an id, so that it can take its waker back out when it stops waiting.
+struct Waiters {
+    next: usize,
+    wakers: Vec<(usize, Waker)>,
 }
 
-unsafe impl<K: Key, V> Send for SyncSecondarySlotMap<K, V> where V: Send {}
-unsafe impl<K: Key, V> Sync for SyncSecondarySlotMap<K, V> where V: Send + Sync {}
+unsafe impl<K: Key, V, L: RawLock> Send for SyncSecondarySlotMap<K, V, L> where V: Send {}
+unsafe impl<K: Key, V, L: RawLock> Sync for SyncSecondarySlotMap<K, V, L> where V: Send + Sync {}
 
-impl<K: Key, V> Drop for SyncSecondarySlotMap<K, V> {
+impl<K: Key, V, L: RawLock> Drop for SyncSecondarySlotMap<K, V, L> {
     fn drop(&mut self) {
-        if !unsafe { &*self.locks }
-            .0
-            .try_lock_exclusive_for(std::time::Duration::from_secs(1))
-        {
-            panic!("Dropped SyncSlotMap which was in use.");
+        let inner = self.inner.get_mut();
+        let leak = || core::mem::forget(core::mem::take(inner));
+        if unsafe { &*self.locks }.lock_for_drop("SyncSecondarySlotMap", leak) {
+            unsafe { drop(Box::from_raw(self.locks)) };
         }
-        unsafe { drop(Box::from_raw(self.locks)) };
     }
 }
 
-impl<K: Key, V> Default for SyncSecondarySlotMap<K, V> {
+impl<K: Key, V, L: RawLock> Default for SyncSecondarySlotMap<K, V, L> {
     fn default() -> Self {
-        Self::new()
+        Self::with_raw_lock()
+    }
+}
+
+/// Two maps are equal if they hold equal values under the same keys.
+///
+/// Both maps are locked like for a whole-map read, taking every value's lock shared in turn,
+/// in address order so comparing the same two maps from different threads can't deadlock.
+/// Running into an entry that's locked exclusively makes the comparison wait for it and start
+/// over. A
+/// map is always equal to itself, without its values being looked at.
+impl<K: Key, V: PartialEq, L: RawLock> PartialEq for SyncSecondarySlotMap<K, V, L> {
+    fn eq(&self, other: &Self) -> bool {
+        // Locking the same map twice could deadlock against a waiting writer.
+        if core::ptr::eq(self, other) {
+            return true;
+        }
+        let (locks, other_locks) = unsafe { (&*self.locks, &*other.locks) };
+        Locks::with_both_shared(locks, other_locks, || {
+            let (inner, other_inner) = unsafe { (&*self.inner.get(), &*other.inner.get()) };
+            if inner.len() != other_inner.len() {
+                return Ok(false);
+            }
+            for (key, value) in inner.iter() {
+                let Some(other_value) = other_inner.get(key) else {
+                    return Ok(false);
+                };
+                let (lock, other_lock) = unsafe {
+                    (
+                        locks.element(key).unwrap(),
+                        other_locks.element(key).unwrap(),
+                    )
+                };
+                if !try_read_both(lock, other_lock, || value == other_value)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })
+    }
+}
+
+impl<K: Key, V: Eq, L: RawLock> Eq for SyncSecondarySlotMap<K, V, L> {}
+
+/// Hashes every key along with its value, combined so that the order they're visited in
+/// doesn't matter, which makes maps that are equal hash the same however they were filled.
+///
+/// The map is locked like for [`PartialEq`], taking every value's lock shared in turn.
+#[cfg(feature = "std")]
+impl<K: Key, V: Hash, L: RawLock> Hash for SyncSecondarySlotMap<K, V, L> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let (mut len, mut entries) = (0, 0);
+        unsafe {
+            (*self.locks).scan(
+                || {
+                    (*self.inner.get())
+                        .iter()
+                        .map(|(key, value)| (key, &**value))
+                },
+                |key| self.lookup(key),
+                |key, value| {
+                    let mut entry = DefaultHasher::new();
+                    (key, value).hash(&mut entry);
+                    len += 1;
+                    entries ^= entry.finish();
+                    ControlFlow::<()>::Continue(())
+                },
+            )
+        };
+        state.write_usize(len);
+        state.write_u64(entries);
+    }
+}
+
+impl<K: Key, V, L: RawLock> Extend<(K, V)> for SyncSecondarySlotMap<K, V, L> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        SyncSecondarySlotMap::extend(self, iter);
     }
 }
 
 impl<K: Key, V> SyncSecondarySlotMap<K, V> {
     pub fn new() -> Self {
-        let locks = Box::new((RawRwLock::INIT, SecondaryMap::new()));
+        Self::with_raw_lock()
+    }
+}
+
+impl<K: Key, V, L: RawLock> SyncSecondarySlotMap<K, V, L> {
+    /// Creates an empty map which uses `L` for all of its locks (and its guards').
+    pub fn with_raw_lock() -> Self {
+        let locks = Box::new(Locks::new());
         let locks = Box::into_raw(locks);
         Self {
             inner: UnsafeCell::new(SecondaryMap::new()),
             locks,
+            len: AtomicUsize::new(0),
+            waiting: Mutex::new(Waiters {
+                next: 0,
+                wakers: Vec::new(),
+            }),
         }
     }
 
+    /// Sets whether guards (and whole-map operations) unlock fairly, handing their locks
+    /// straight to a waiting thread rather than letting whichever thread comes along first
+    /// barge in (see [`RawLock::unlock_shared_fair`]). Off by default.
+    ///
+    /// This is slower, but keeps a steady stream of readers from starving writers. It takes
+    /// effect immediately, including for guards which are already held.
+    pub fn set_fair(&self, fair: bool) {
+        unsafe { &*self.locks }.set_fair(fair);
+    }
+
+    /// Sets what dropping the map does while guards on it are still alive, like
+    /// [`SyncSlotMap::set_drop_behavior`](crate::SyncSlotMap::set_drop_behavior). The default
+    /// is to wait.
+    pub fn set_drop_behavior(&self, behavior: DropBehavior) {
+        unsafe { &*self.locks }.set_drop_behavior(behavior);
+    }
+
+    /// Sets how long dropping the map waits for guards before giving up, like
+    /// [`SyncSlotMap::set_drop_timeout`](crate::SyncSlotMap::set_drop_timeout). A second by
+    /// default.
+    #[cfg(feature = "std")]
+    pub fn set_drop_timeout(&self, timeout: Duration) {
+        unsafe { &*self.locks }.set_drop_timeout(timeout);
+    }
+
+    /// Sets how many more times the `*_async` methods try a busy lock, spinning in between,
+    /// before yielding to the async runtime. Zero (the default) yields straight away.
+    ///
+    /// Spinning a little lets locks which are only held briefly be taken without the task
+    /// going back to the runtime's queue, at the cost of keeping the thread busy meanwhile.
+    pub fn set_spin_count(&self, spins: u32) {
+        unsafe { &*self.locks }.set_spins(spins);
+    }
+
+    /// The spin count set with `set_spin_count`.
+    pub fn spin_count(&self) -> u32 {
+        unsafe { &*self.locks }.spins()
+    }
+
     pub fn insert(&self, key: K, value: V) -> Option<V> {
-        unsafe { &*self.locks }.0.lock_exclusive();
-        let output = unsafe { &mut *self.inner.get() }.insert(key, value);
-        unsafe { &mut *self.locks }.1.insert(key, RawRwLock::INIT);
-        unsafe {
-            (*self.locks).0.unlock_exclusive();
-        }
-        output
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive();
+        let replaced = unsafe { self.insert_locked(key, value) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let (old_key, old_value, lock) = replaced?;
+        unsafe { locks.retire(lock) };
+        (old_key == key).then_some(*old_value)
     }
 
+    #[cfg(feature = "std")]
     pub fn try_insert_for(
         &self,
         key: K,
         value: V,
         timeout: std::time::Duration,
     ) -> Option<Option<V>> {
-        if !unsafe { &*self.locks }.0.try_lock_exclusive_for(timeout) {
+        let locks = unsafe { &*self.locks };
+        if !locks.structure.try_lock_exclusive_for(timeout) {
             return None;
         }
-        let output = unsafe { &mut *self.inner.get() }.insert(key, value);
-        unsafe { &mut *self.locks }.1.insert(key, RawRwLock::INIT);
-        unsafe {
-            (*self.locks).0.unlock_exclusive();
-        }
-        Some(output)
+        let replaced = unsafe { self.insert_locked(key, value) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let Some((old_key, old_value, lock)) = replaced else {
+            return Some(None);
+        };
+        unsafe { locks.retire(lock) };
+        Some((old_key == key).then_some(*old_value))
     }
 
     pub async fn insert_async(&self, key: K, value: V) -> Option<V> {
-        UnlockRwLockExclusive {
-            lock: &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive_async(locks.spins()).await;
+        let replaced = unsafe { self.insert_locked(key, value) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let (old_key, old_value, lock) = replaced?;
+        let old_value = unsafe { locks.retire_async(lock, old_value).await };
+        (old_key == key).then_some(*old_value)
+    }
+
+    // A replaced entry (either `key` itself or an older key for the same slot) gets a new lock,
+    // the caller retires the old one so guards still on the old value are waited out.
+    unsafe fn insert_locked(&self, key: K, value: V) -> Option<(K, Box<V>, Box<L>)> {
+        self.insert_locked_with(key, value, false)
+    }
+
+    // `unused_lock` gives the entry a lock nobody can be holding, see `Locks::register_unused`,
+    // for callers that lock it before releasing `structure`.
+    unsafe fn insert_locked_with(
+        &self,
+        key: K,
+        value: V,
+        unused_lock: bool,
+    ) -> Option<(K, Box<V>, Box<L>)> {
+        let inner = &mut *self.inner.get();
+        let locks = &*self.locks;
+        let registered = if unused_lock {
+            locks.register_unused(key)
+        } else {
+            locks.register(key)
+        };
+        match registered {
+            Registered::Refused => None,
+            Registered::Fresh => {
+                inner.insert(key, Box::new(value));
+                self.len.fetch_add(1, Ordering::Relaxed);
+                self.wake_waiting();
+                None
+            }
+            Registered::Replaced(old_key, lock) => {
+                let old_value = inner.remove(old_key)?;
+                inner.insert(key, Box::new(value));
+                self.wake_waiting();
+                Some((old_key, old_value, lock))
+            }
         }
-        .await;
-        let output = unsafe { &mut *self.inner.get() }.insert(key, value);
-        unsafe { &mut *self.locks }.1.insert(key, RawRwLock::INIT);
-        unsafe {
-            (*self.locks).0.unlock_exclusive();
+    }
+
+    /// Write-locks the value under `key`, first inserting `f(key)` if there is none.
+    ///
+    /// Only one caller gets to insert: the new entry is locked before any other thread can see
+    /// it, so concurrent callers for the same missing key run `f` once and the rest get the
+    /// value it made. Returns `None` (after running `f`) if `key` is older than a key already in
+    /// its slot, like [`SyncSecondarySlotMap::insert`] refusing it.
+    ///
+    /// `f` runs with the map's structure locked exclusively, so it must not use the map itself,
+    /// which would deadlock.
+    pub fn get_or_insert_with_key(
+        &self,
+        key: K,
+        f: impl FnOnce(K) -> V,
+    ) -> Option<SyncSlotGuardMut<V, L>> {
+        match self.get_or_try_insert_with_key(key, |key| Ok::<_, Infallible>(f(key))) {
+            Ok(guard) => guard,
         }
-        output
     }
 
-    pub fn remove(&self, key: K) -> Option<V> {
-        unsafe { &*self.locks }.0.lock_exclusive();
-        let result = unsafe { &mut *self.inner.get() }.remove(key);
-        unsafe { &mut *self.locks }.1.remove(key);
+    /// Like [`SyncSecondarySlotMap::get_or_insert_with_key`], for values whose construction can
+    /// fail. If `f` returns an error the map is left as it was and the error is returned.
+    ///
+    /// `f` must not use the map, see [`SyncSecondarySlotMap::get_or_insert_with_key`].
+    pub fn get_or_try_insert_with<E>(
+        &self,
+        key: K,
+        f: impl FnOnce() -> Result<V, E>,
+    ) -> Result<Option<SyncSlotGuardMut<V, L>>, E> {
+        self.get_or_try_insert_with_key(key, |_| f())
+    }
+
+    fn get_or_try_insert_with_key<E>(
+        &self,
+        key: K,
+        f: impl FnOnce(K) -> Result<V, E>,
+    ) -> Result<Option<SyncSlotGuardMut<V, L>>, E> {
+        let locks = unsafe { &*self.locks };
+        let global = loop {
+            if let Some(guard) = self.get_mut(key) {
+                return Ok(Some(guard));
+            }
+            let global = locks.global.shard(key);
+            global.lock_shared();
+            locks.structure.lock_exclusive();
+            if !unsafe { &*self.inner.get() }.contains_key(key) {
+                break global;
+            }
+            unsafe {
+                locks.structure.unlock_exclusive();
+                global.unlock_shared();
+            }
+            // Inserted by someone else meanwhile, and maybe removed again before it's gotten.
+        };
+        // Nothing is registered before `f` succeeds, so an error (or a panic) leaves no trace
+        // once the locks are released.
+        let held_global = unsafe { Held::new(global, Access::Shared) };
+        let held_structure = unsafe { ExclusiveShards::all(&locks.structure) };
+        let value = f(key)?;
+        held_structure.keep();
+        held_global.keep();
+        let replaced = unsafe { self.insert_locked_with(key, value, true) };
+        let guard = unsafe { locks.element(key) }.map(|lock| {
+            // Nobody else can reach the new lock until `structure` is released.
+            lock.lock_exclusive();
+            SyncSlotGuardMut {
+                value: self.lookup(key).unwrap(),
+                locks: locks.guard_locks(global, lock),
+                key: key.data(),
+            }
+        });
         unsafe {
-            (*self.locks).0.unlock_exclusive();
+            locks.structure.unlock_exclusive();
+            if guard.is_none() {
+                global.unlock_shared();
+            }
+        }
+        if let Some((_, _, lock)) = replaced {
+            unsafe { locks.retire(lock) };
+        }
+        Ok(guard)
+    }
+
+    /// Write-locks the value under `key`, first inserting `default` if there is none.
+    ///
+    /// Like [`SyncSecondarySlotMap::get_or_insert_with_key`], but for defaults cheap enough to
+    /// make up front, which are just dropped if the key turns out to be there already.
+    pub fn get_mut_or_insert(&self, key: K, default: V) -> Option<SyncSlotGuardMut<V, L>> {
+        self.get_or_insert_with_key(key, |_| default)
+    }
+
+    /// Write-locks the value under `key`, first inserting `V::default()` if there is none.
+    ///
+    /// Unlike [`SyncSlotMap::get_mut_or_default`](crate::SyncSlotMap::get_mut_or_default), this
+    /// really does insert: a secondary map can hold a value under any key of its primary map.
+    /// Only one concurrent caller inserts, like with
+    /// [`SyncSecondarySlotMap::get_or_insert_with_key`].
+    pub fn get_mut_or_default(&self, key: K) -> Option<SyncSlotGuardMut<V, L>>
+    where
+        V: Default,
+    {
+        self.get_or_insert_with_key(key, |_| V::default())
+    }
+
+    /// Moves every entry of `other` into this map, leaving `other` empty.
+    ///
+    /// Keys only in `other` are inserted (subject to the same rules as
+    /// [`SyncSecondarySlotMap::insert`]), and for keys in both `on_conflict` gets this map's
+    /// value along with `other`'s to combine. Both maps are locked exclusively for the duration,
+    /// in address order so that merging two maps into each other from different threads can't
+    /// deadlock.
+    pub fn merge(&self, other: &Self, on_conflict: impl Fn(&mut V, V)) {
+        if core::ptr::eq(self, other) {
+            return;
+        }
+        let (locks, other_locks) = unsafe { (&*self.locks, &*other.locks) };
+        let (first, second) = if self.locks < other.locks {
+            (locks, other_locks)
+        } else {
+            (other_locks, locks)
+        };
+        let order = Ordered::begin();
+        order.taking(first);
+        order.taking(second);
+        first.lock_exclusive();
+        let _first = unsafe { HeldExclusive::new(first) };
+        second.lock_exclusive();
+        let _second = unsafe { HeldExclusive::new(second) };
+        // Draining empties `other` even if `on_conflict` panics halfway.
+        other.len.store(0, Ordering::Relaxed);
+        let inner = unsafe { &mut *self.inner.get() };
+        for (key, value) in unsafe { &mut *other.inner.get() }.drain() {
+            if let Some(lock) = unsafe { other_locks.unregister(key) } {
+                unsafe { other_locks.retire(lock) };
+            }
+            match inner.get_mut(key) {
+                Some(existing) => on_conflict(existing, *value),
+                None => {
+                    // No guards exist while `global` is held exclusively, so retiring is free.
+                    if let Some((_, _, lock)) = unsafe { self.insert_locked(key, *value) } {
+                        unsafe { locks.retire(lock) };
+                    }
+                }
+            }
+        }
+    }
+
+    /// Inserts every pair from `iter`, like calling [`SyncSecondarySlotMap::insert`] on each
+    /// but locking the map just once, exclusively, for all of them.
+    ///
+    /// This takes `&self` so it can be used on a shared map; the [`Extend`] impl is there for
+    /// generic code holding a `&mut` one. Replaced values are dropped once the map is unlocked
+    /// again.
+    pub fn extend<I: IntoIterator<Item = (K, V)>>(&self, iter: I) {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        let held = unsafe { HeldExclusive::new(locks) };
+        let mut replaced = Vec::new();
+        for (key, value) in iter {
+            // No guards exist while `global` is held exclusively, so retiring is free.
+            if let Some((_, old_value, lock)) = unsafe { self.insert_locked(key, value) } {
+                unsafe { locks.retire(lock) };
+                replaced.push(old_value);
+            }
         }
-        result
+        drop(held);
+        drop(replaced);
+    }
+
+    /// Overwrites the value under `key` in place, returning the old one, or `None` without
+    /// inserting anything if `key` isn't in the map, like
+    /// [`SyncSlotMap::replace`](crate::SyncSlotMap::replace).
+    ///
+    /// Unlike [`SyncSecondarySlotMap::insert`], this only takes the entry's own lock
+    /// exclusively, and the entry keeps its lock rather than getting a new one.
+    pub fn swap_value(&self, key: K, value: V) -> Option<V> {
+        let mut guard = self.get_mut(key)?;
+        Some(core::mem::replace(&mut *guard, value))
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_swap_value_for(
+        &self,
+        key: K,
+        value: V,
+        timeout: std::time::Duration,
+    ) -> Option<Option<V>> {
+        let guard = self.try_get_mut_for(key, timeout)?;
+        Some(guard.map(|mut guard| core::mem::replace(&mut *guard, value)))
+    }
+
+    pub async fn swap_value_async(&self, key: K, value: V) -> Option<V> {
+        let mut guard = self.get_mut_async(key).await?;
+        Some(core::mem::replace(&mut *guard, value))
+    }
+
+    pub fn remove(&self, key: K) -> Option<V> {
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive();
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let (value, lock) = removed?;
+        unsafe { locks.retire(lock) };
+        Some(*value)
     }
 
+    #[cfg(feature = "std")]
     pub fn try_remove_for(&self, key: K, timeout: std::time::Duration) -> Option<Option<V>> {
-        if !unsafe { &*self.locks }.0.try_lock_exclusive_for(timeout) {
+        let locks = unsafe { &*self.locks };
+        if !locks.structure.try_lock_exclusive_for(timeout) {
             return None;
         }
-        let result = unsafe { &mut *self.inner.get() }.remove(key);
-        unsafe { &mut *self.locks }.1.remove(key);
-        unsafe {
-            (*self.locks).0.unlock_exclusive();
-        }
-        Some(result)
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let Some((value, lock)) = removed else {
+            return Some(None);
+        };
+        unsafe { locks.retire(lock) };
+        Some(Some(*value))
     }
 
     pub async fn remove_async(&self, key: K) -> Option<V> {
-        UnlockRwLockExclusive {
-            lock: &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-        }
-        .await;
-        let result = unsafe { &mut *self.inner.get() }.remove(key);
-        unsafe { &mut *self.locks }.1.remove(key);
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive_async(locks.spins()).await;
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let (value, lock) = removed?;
+        let value = unsafe { locks.retire_async(lock, value).await };
+        Some(*value)
+    }
+
+    // The entry is unlinked here, the caller then retires its lock, which waits out any guards
+    // still pointing at the value before it is moved out of its box.
+    unsafe fn remove_locked(&self, key: K) -> Option<(Box<V>, Box<L>)> {
+        let value = (*self.inner.get()).remove(key)?;
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        let lock = (*self.locks).unregister(key)?;
+        Some((value, lock))
+    }
+
+    /// How many slots the map has room for before it has to grow, like
+    /// `SecondaryMap::capacity`.
+    pub fn capacity(&self) -> usize {
+        let structure = unsafe { &*self.locks }.structure.any_shard();
+        structure.lock_shared();
+        let capacity = unsafe { &*self.inner.get() }.capacity();
+        unsafe { structure.unlock_shared() };
+        capacity
+    }
+
+    /// Makes room for at least `new_capacity` slots, like `SecondaryMap::set_capacity`.
+    ///
+    /// Only the map's structure is locked, like for [`SyncSecondarySlotMap::insert`].
+    pub fn set_capacity(&self, new_capacity: usize) {
+        let structure = &unsafe { &*self.locks }.structure;
+        structure.lock_exclusive();
+        unsafe { &mut *self.inner.get() }.set_capacity(new_capacity);
+        unsafe { structure.unlock_exclusive() };
+    }
+
+    /// The number of entries, read without taking any lock.
+    ///
+    /// It's updated as inserts and removals happen, so with those going on concurrently it may
+    /// already be out of date by the time it's returned.
+    pub fn approx_len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Gives memory left over from removed entries back to the allocator, waiting for every
+    /// guard to be dropped first.
+    pub fn shrink_to_fit(&self) {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
         unsafe {
-            (*self.locks).0.unlock_exclusive();
+            let inner = &mut *self.inner.get();
+            *inner = inner.drain().collect();
+            locks.shrink_to_fit();
+            locks.unlock_exclusive();
         }
-        result
-    }
-
-    pub fn get(&self, key: K) -> Option<SyncSlotGuard<V>> {
-        let (lock, value) = unsafe {
-            (*self.locks).0.lock_shared();
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return None; };
-            lock.lock_shared();
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_shared();
-                return None;
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(SyncSlotGuard { value, locks })
-    }
-
-    pub fn get_mut(&self, key: K) -> Option<SyncSlotGuardMut<V>> {
-        let (lock, value) = unsafe {
-            (*self.locks).0.lock_shared();
-            let Some(lock) = (*self.locks).1.get(key) else {(*self.locks).0.unlock_shared(); return None; };
-            lock.lock_exclusive();
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_exclusive();
-                return None;
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(SyncSlotGuardMut { value, locks })
     }
 
+    /// Frees the element locks of removed entries that are kept around for reuse, and shrinks
+    /// the table of element locks down to the highest slot still in use.
+    ///
+    /// The table is indexed by slot, so without this it stays as large as the map has ever
+    /// been. It waits for every guard to be dropped first, like [`SyncSecondarySlotMap::shrink_to_fit`].
+    /// Nothing compacts automatically except in [`SyncSecondarySlotMap::shrink_to_fit`].
+    pub fn compact_locks(&self) {
+        unsafe { &*self.locks }.compact();
+    }
+
+    fn lookup(&self, key: K) -> Option<*mut V> {
+        unsafe { &*self.inner.get() }
+            .get(key)
+            .map(|value| &**value as *const V as *mut V)
+    }
+
+    pub fn get(&self, key: K) -> Option<SyncSlotGuard<V, L>> {
+        let (locks, value) =
+            unsafe { (*self.locks).acquire(key, Access::Shared, || self.lookup(key)) }?;
+        Some(SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    pub fn get_mut(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        let (locks, value) =
+            unsafe { (*self.locks).acquire(key, Access::Exclusive, || self.lookup(key)) }?;
+        Some(SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    #[cfg(feature = "std")]
     pub fn try_get_for(
         &self,
         key: K,
         timeout: std::time::Duration,
-    ) -> Option<Option<SyncSlotGuard<V>>> {
-        let (lock, value) = unsafe {
-            if !(*self.locks).0.try_lock_shared_for(timeout) {
-                return None;
-            }
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return Some(None); };
-            if !lock.try_lock_shared_for(timeout) {
-                (*self.locks).0.unlock_shared();
-                return None;
-            }
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_shared();
-                return Some(None);
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(Some(SyncSlotGuard { value, locks }))
+    ) -> Option<Option<SyncSlotGuard<V, L>>> {
+        let acquired = unsafe {
+            (*self.locks).try_acquire_for(key, Access::Shared, timeout, || self.lookup(key))
+        }?;
+        Some(acquired.map(|(locks, value)| SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        }))
     }
 
+    #[cfg(feature = "std")]
     pub fn try_get_mut_for(
         &self,
         key: K,
         timeout: std::time::Duration,
-    ) -> Option<Option<SyncSlotGuardMut<V>>> {
-        let (lock, value) = unsafe {
-            if !(*self.locks).0.try_lock_shared_for(timeout) {
-                return None;
-            }
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return Some(None); };
-            if !lock.try_lock_exclusive_for(timeout) {
-                (*self.locks).0.unlock_shared();
-                return None;
-            }
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_exclusive();
-                return Some(None);
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(Some(SyncSlotGuardMut { value, locks }))
-    }
-
-    pub async fn get_async(&self, key: K) -> Option<SyncSlotGuard<V>> {
-        let (lock, value) = unsafe {
-            UnlockRwLockShared {
-                lock: &(*self.locks).0 as *const RawRwLock as *mut RawRwLock,
+    ) -> Option<Option<SyncSlotGuardMut<V, L>>> {
+        let acquired = unsafe {
+            (*self.locks).try_acquire_for(key, Access::Exclusive, timeout, || self.lookup(key))
+        }?;
+        Some(acquired.map(|(locks, value)| SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        }))
+    }
+
+    pub async fn get_async(&self, key: K) -> Option<SyncSlotGuard<V, L>> {
+        let (locks, value) = unsafe {
+            (*self.locks)
+                .acquire_async(key, Access::Shared, || self.lookup(key))
+                .await
+        }?;
+        Some(SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    pub async fn get_mut_async(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        let (locks, value) = unsafe {
+            (*self.locks)
+                .acquire_async(key, Access::Exclusive, || self.lookup(key))
+                .await
+        }?;
+        Some(SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+    /// Waits for a value to be inserted under `key`, then read-locks it like
+    /// [`SyncSecondarySlotMap::get_async`].
+    ///
+    /// The future is woken by inserts rather than polled over and over, so a consumer can ask
+    /// for a key before its producer has inserted anything under it. It waits forever if
+    /// nothing ever is (including when `key` is older than one already in its slot, so inserts
+    /// under it are refused); bound it with a runtime's timeout, or use
+    /// [`SyncSecondarySlotMap::wait_for_timeout`] from synchronous code.
+    pub async fn wait_for(&self, key: K) -> SyncSlotGuard<V, L> {
+        loop {
+            if let Some(guard) = self.get_async(key).await {
+                return guard;
             }
-            .await;
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return None; };
-            UnlockRwLockShared {
-                lock: lock as *const RawRwLock as *mut RawRwLock,
+            Inserted {
+                map: self,
+                key,
+                waiter: None,
             }
             .await;
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_shared();
-                return None;
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(SyncSlotGuard { value, locks })
-    }
-    pub async fn get_mut_async(&self, key: K) -> Option<SyncSlotGuardMut<V>> {
-        let (lock, value) = unsafe {
-            UnlockRwLockShared {
-                lock: &(*self.locks).0 as *const RawRwLock as *mut RawRwLock,
+        }
+    }
+
+    /// Like [`SyncSecondarySlotMap::wait_for`], blocking the current thread for up to
+    /// `timeout` instead, and giving up with `None` after that.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn wait_for_timeout(
+        &self,
+        key: K,
+        timeout: std::time::Duration,
+    ) -> Option<SyncSlotGuard<V, L>> {
+        struct Unpark(std::thread::Thread);
+
+        impl std::task::Wake for Unpark {
+            fn wake(self: std::sync::Arc<Self>) {
+                self.0.unpark();
             }
-            .await;
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return None; };
-            UnlockRwLockExclusive {
-                lock: lock as *const RawRwLock as *mut RawRwLock,
+        }
+
+        let deadline = std::time::Instant::now() + timeout;
+        let waker = Waker::from(std::sync::Arc::new(Unpark(std::thread::current())));
+        let mut waiter = None;
+        let guard = loop {
+            // Registering first means an insert right after the lookup isn't missed.
+            self.wake_on_insert(&mut waiter, &waker);
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            match self.try_get_for(key, remaining) {
+                Some(Some(guard)) => break Some(guard),
+                None => break None,
+                Some(None) if remaining.is_zero() => break None,
+                Some(None) => std::thread::park_timeout(remaining),
             }
-            .await;
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_exclusive();
-                return None;
-            };
-            (lock, value)
         };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(SyncSlotGuardMut { value, locks })
+        self.stop_waking(waiter);
+        guard
+    }
+
+    // Has `waker` woken by the next insert, replacing whatever `waiter` registered before. The
+    // first call gives `waiter` its id.
+    fn wake_on_insert(&self, waiter: &mut Option<usize>, waker: &Waker) {
+        let waiting = &mut *self.waiting.lock();
+        let id = *waiter.get_or_insert_with(|| {
+            waiting.next += 1;
+            waiting.next
+        });
+        match waiting.wakers.iter_mut().find(|(other, _)| *other == id) {
+            Some((_, registered)) if !registered.will_wake(waker) => *registered = waker.clone(),
+            Some(_) => {}
+            None => waiting.wakers.push((id, waker.clone())),
+        }
+    }
+
+    // Takes out the waker `waiter` registered, if it hasn't been woken yet.
+    fn stop_waking(&self, waiter: Option<usize>) {
+        if let Some(id) = waiter {
+            self.waiting.lock().wakers.retain(|(other, _)| *other != id);
+        }
+    }
+
+    fn wake_waiting(&self) {
+        let waiting = core::mem::take(&mut self.waiting.lock().wakers);
+        for (_, waker) in waiting {
+            waker.wake();
+        }
+    }
+
+    // Only `structure` is needed to look, and it's never held for long, so this doesn't have to
+    // wait for guards or whole-map operations.
+    fn contains_key(&self, key: K) -> bool {
+        let structure = unsafe { &*self.locks }.structure.shard(key);
+        structure.lock_shared();
+        let contains = unsafe { &*self.inner.get() }.contains_key(key);
+        unsafe { structure.unlock_shared() };
+        contains
+    }
+}
+
+// Resolves once `key` is in `map`, having it woken by inserts until then. Dropping it takes its
+// waker back out, so futures given up on don't pile up in the map.
+struct Inserted<'a, K: Key, V, L: RawLock> {
+    map: &'a SyncSecondarySlotMap<K, V, L>,
+    key: K,
+    waiter: Option<usize>,
+}
+
+// Nothing in it is ever pinned.
+impl<K: Key, V, L: RawLock> Unpin for Inserted<'_, K, V, L> {}
+
+impl<K: Key, V, L: RawLock> Future for Inserted<'_, K, V, L> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.map.wake_on_insert(&mut this.waiter, cx.waker());
+        if this.map.contains_key(this.key) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<K: Key, V, L: RawLock> Drop for Inserted<'_, K, V, L> {
+    fn drop(&mut self) {
+        self.map.stop_waking(self.waiter);
+    }
+}
+
+/// A [`SyncSecondarySlotMap`] locked exclusively by [`with_locked2`](crate::with_locked2), so
+/// its entries can be used without locking each of them.
+pub struct LockedSecondaryMap<'a, K: Key, V, L: RawLock = DefaultRawLock> {
+    // The whole map is locked exclusively for as long as this lives.
+    pub(crate) map: &'a SyncSecondarySlotMap<K, V, L>,
+}
+
+impl<K: Key, V, L: RawLock> LockedSecondaryMap<'_, K, V, L> {
+    pub fn get(&self, key: K) -> Option<&V> {
+        self.map.lookup(key).map(|value| unsafe { &*value })
+    }
+
+    pub fn get_mut(&mut self, key: K) -> Option<&mut V> {
+        self.map.lookup(key).map(|value| unsafe { &mut *value })
+    }
+
+    pub fn contains_key(&self, key: K) -> bool {
+        self.map.lookup(key).is_some()
+    }
+
+    /// See [`SyncSecondarySlotMap::insert`].
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let (old_key, old_value, lock) = unsafe { self.map.insert_locked(key, value) }?;
+        // No guards exist while `global` is held exclusively, so retiring is free.
+        unsafe { (*self.map.locks).retire(lock) };
+        (old_key == key).then_some(*old_value)
+    }
+
+    pub fn remove(&mut self, key: K) -> Option<V> {
+        let (value, lock) = unsafe { self.map.remove_locked(key) }?;
+        unsafe { (*self.map.locks).retire(lock) };
+        Some(*value)
     }
 }
@@ -1,321 +1,2498 @@
-use std::fmt::{Debug, Display};
-use std::ops::{Deref, DerefMut, Index, IndexMut};
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::borrow::{Borrow, BorrowMut};
+use core::fmt::{Debug, Display};
+use core::hash::{Hash, Hasher};
+use core::marker::PhantomData;
+use core::ops::{AddAssign, ControlFlow, Deref, DerefMut};
 
-use std::{cell::UnsafeCell, future::Future, task::Poll};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::{cell::UnsafeCell, future::Future, task::Poll};
+#[cfg(feature = "std")]
+use std::sync::OnceLock;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
-use parking_lot::{
-    lock_api::{RawRwLock as RwLockTrait, RawRwLockTimed},
-    RawRwLock,
-};
 use slotmap::*;
 
-pub struct SyncSlotMap<K: Key, V> {
-    inner: UnsafeCell<SlotMap<K, V>>,
-    locks: *mut (RawRwLock, SecondaryMap<K, RawRwLock>),
-}
+use crate::backoff::Backoff;
+#[cfg(feature = "std")]
+use crate::deadline::Deadline;
+use crate::lock_order::Ordered;
+#[cfg(feature = "std")]
+use crate::locks::SlotLocks;
+use crate::locks::{
+    slot_index, try_read_both, version, Access, GuardLocks, Held, HeldExclusive, Locks,
+};
+use crate::metrics::Metrics;
+use crate::raw_lock::{DefaultRawLock, RawLock, RwLock};
+use crate::sharded_lock::ExclusiveShards;
+use crate::sync_secondary_map::{LockedSecondaryMap, SyncSecondarySlotMap};
+use crate::trace;
+
+/// A change to the keys of a [`SyncSlotMap`], as passed to [`SyncSlotMap::on_change`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeEvent<K> {
+    Inserted(K),
+    Removed(K),
+}
+
+/// A best-effort picture of how busy a [`SyncSlotMap`]'s locks are, from
+/// [`SyncSlotMap::lock_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockStats {
+    /// Whether something holds, or is waiting for, the whole map exclusively.
+    pub global_is_writer_waiting: bool,
+    /// How many entries are currently held by at least one guard.
+    pub locked_keys: usize,
+}
+
+/// Whether [`SyncSlotMap::insert_reporting`] put its value in a new slot or reused one left by
+/// a removed entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SlotOrigin {
+    Fresh,
+    Recycled,
+}
+
+/// What [`SyncSlotMap::get_checked`] found for a key.
+pub enum GetResult<V, L: RawLock = DefaultRawLock> {
+    /// The key's entry is in the map.
+    Live(SyncSlotGuard<V, L>),
+    /// The key's slot exists but holds a newer entry or none, so the key's entry was removed
+    /// (or has expired).
+    Stale,
+    /// The map never made the key's slot, so the key is null or from another map.
+    Unknown,
+}
+
+impl<V: Debug, L: RawLock> Debug for GetResult<V, L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Live(guard) => f.debug_tuple("Live").field(guard).finish(),
+            Self::Stale => f.write_str("Stale"),
+            Self::Unknown => f.write_str("Unknown"),
+        }
+    }
+}
+
+/// What dropping a map does while guards on it are still alive, set with
+/// [`SyncSlotMap::set_drop_behavior`] (or the same method of the other maps).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DropBehavior {
+    /// Wait for the guards to be dropped, however long that takes.
+    ///
+    /// A guard held by the thread dropping the map is never dropped while it waits, so that
+    /// hangs forever rather than panicking like it used to. Use [`DropBehavior::Panic`] or
+    /// [`DropBehavior::Warn`] where that could happen.
+    #[default]
+    Wait,
+    /// Wait up to the drop timeout, then leak the map's entries and panic.
+    Panic,
+    /// Wait up to the drop timeout, then leak the map's entries with a warning, which is a
+    /// `tracing` event. Without the `tracing` feature they are leaked silently.
+    Warn,
+}
+
+impl DropBehavior {
+    pub(crate) fn from_u8(behavior: u8) -> Self {
+        match behavior {
+            1 => DropBehavior::Panic,
+            2 => DropBehavior::Warn,
+            _ => DropBehavior::Wait,
+        }
+    }
+}
+
+/// Why [`SyncSlotMap::compare_and_set`] didn't store its new value, which is handed back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CasError<V> {
+    /// The current value wasn't equal to the expected one.
+    Mismatch(V),
+    /// The key isn't in the map.
+    NotFound(V),
+}
+
+type Observer<K> = Box<dyn Fn(ChangeEvent<K>) + Send + Sync>;
+
+type GuardMutPair<V, L> = (
+    Option<SyncSlotGuardMut<V, L>>,
+    Option<SyncSlotGuardMut<V, L>>,
+);
+
+/// A [`SyncSlotMap`] which can be created in a `const` context, such as a `static`.
+///
+/// A map's locks live in their own allocation, so `SyncSlotMap::new` can't be `const`. This
+/// holds off creating the map until it is first used, through `Deref`, after which it is an
+/// ordinary map.
+///
+/// ```
+/// use slotmap::DefaultKey;
+/// use sync_slotmap::LazySyncSlotMap;
+///
+/// static NAMES: LazySyncSlotMap<DefaultKey, &str> = LazySyncSlotMap::new();
+///
+/// let key = NAMES.insert("ferris");
+/// assert_eq!(*NAMES.get(key).unwrap(), "ferris");
+/// ```
+#[cfg(feature = "std")]
+pub struct LazySyncSlotMap<K: Key, V, L: RawLock = DefaultRawLock> {
+    map: OnceLock<SyncSlotMap<K, V, L>>,
+}
+
+#[cfg(feature = "std")]
+impl<K: Key, V, L: RawLock> LazySyncSlotMap<K, V, L> {
+    pub const fn new() -> Self {
+        Self {
+            map: OnceLock::new(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: Key, V, L: RawLock> Default for LazySyncSlotMap<K, V, L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: Key, V, L: RawLock> Deref for LazySyncSlotMap<K, V, L> {
+    type Target = SyncSlotMap<K, V, L>;
+
+    fn deref(&self) -> &Self::Target {
+        self.map.get_or_init(SyncSlotMap::with_raw_lock)
+    }
+}
+
+/// Configures a [`SyncSlotMap`] before creating it, from [`SyncSlotMap::builder`].
+pub struct SyncSlotMapBuilder<K: Key, V, L: RawLock = DefaultRawLock> {
+    capacity: usize,
+    #[cfg(feature = "std")]
+    default_timeout: Duration,
+    fair: bool,
+    backoff: Option<Arc<dyn Backoff>>,
+    #[cfg(feature = "std")]
+    sparse_locks: bool,
+    _map: PhantomData<(K, V, L)>,
+}
+
+impl<K: Key, V, L: RawLock> SyncSlotMapBuilder<K, V, L> {
+    /// Makes room for `capacity` entries up front.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets how long [`SyncSlotMap::try_get`] and [`SyncSlotMap::try_get_mut`] wait for an
+    /// entry. Without one they don't wait at all.
+    #[cfg(feature = "std")]
+    pub fn default_timeout(mut self, timeout: Duration) -> Self {
+        self.default_timeout = timeout;
+        self
+    }
+
+    /// Makes guards unlock fairly, handing their locks straight to a waiting thread rather
+    /// than letting whichever thread comes along first barge in (see
+    /// [`RawLock::unlock_shared_fair`]).
+    ///
+    /// This is slower, but keeps a steady stream of readers from starving writers of the same
+    /// entries, or of the whole map. It can be changed later with [`SyncSlotMap::set_fair`].
+    pub fn fair(mut self, fair: bool) -> Self {
+        self.fair = fair;
+        self
+    }
+
+    /// Sets what the methods that lock several entries at once, such as
+    /// [`SyncSlotMap::get_disjoint_mut_many`] and [`SyncSlotMap::swap`], do before starting
+    /// over when an entry changed under them, see the [`backoff`](crate::backoff) module.
+    ///
+    /// ```
+    /// use slotmap::DefaultKey;
+    /// use sync_slotmap::backoff::Exponential;
+    /// use sync_slotmap::SyncSlotMap;
+    ///
+    /// let map = SyncSlotMap::<DefaultKey, i32>::builder()
+    ///     .backoff(Exponential::default())
+    ///     .build();
+    /// ```
+    pub fn backoff(mut self, backoff: impl Backoff + 'static) -> Self {
+        self.backoff = Some(Arc::new(backoff));
+        self
+    }
+
+    /// Keeps the entries' locks in a hash map rather than a table indexed by slot, for maps with
+    /// far fewer entries than slots, e.g. after most of a large batch has been removed again.
+    ///
+    /// With 64-bit keys and pointers, the table takes 16 bytes for every slot up to the highest
+    /// one in use, whether that slot has an entry or not. The hash map takes 25 bytes per
+    /// bucket and has between 8/7 and 16/7 buckets per entry, so 29 to 57 bytes per entry.
+    /// Measured with 100,000 slots of which every 100th is in use (after
+    /// [`SyncSlotMap::compact_locks`]), the lock table takes 1.6 MB and the hash map 51 kB; with
+    /// every slot in use it's the other way round, 2.1 MB against 3.3 MB. Looking up an entry's
+    /// lock costs a hash instead of an index; the value slots themselves are unaffected.
+    #[cfg(feature = "std")]
+    pub fn sparse_locks(mut self, sparse: bool) -> Self {
+        self.sparse_locks = sparse;
+        self
+    }
+
+    pub fn build(self) -> SyncSlotMap<K, V, L> {
+        let mut map = SyncSlotMap::with_raw_lock();
+        map.inner.get_mut().reserve(self.capacity);
+        #[cfg(feature = "std")]
+        {
+            map.default_timeout = self.default_timeout;
+            if self.sparse_locks {
+                unsafe { &mut *map.locks }.set_elements(SlotLocks::sparse());
+            }
+        }
+        unsafe { &*map.locks }.set_fair(self.fair);
+        unsafe { &mut *map.locks }.set_backoff(self.backoff);
+        map
+    }
+}
+
+pub struct SyncSlotMap<K: Key, V, L: RawLock = DefaultRawLock> {
+    inner: UnsafeCell<SlotMap<K, Box<V>>>,
+    locks: *mut Locks<K, L>,
+    observer: RwLock<Option<Observer<K>>>,
+    // When entries inserted with `insert_with_ttl` expire, guarded by `structure` like `inner`.
+    #[cfg(feature = "std")]
+    expiries: UnsafeCell<SecondaryMap<K, Instant>>,
+    // Kept next to `inner` so `approx_len` doesn't need any lock.
+    len: AtomicUsize,
+    // The most `len` has been since the map was made or `reset_peak` was last called.
+    peak: AtomicUsize,
+    // One past the highest slot index `inner` has handed out, slots are never given back.
+    slots: AtomicU32,
+    // How long `try_get` and `try_get_mut` wait, see `SyncSlotMapBuilder::default_timeout`.
+    #[cfg(feature = "std")]
+    default_timeout: Duration,
+    // Overrides of `default_timeout` from `set_key_timeout`, guarded by `structure` like `inner`.
+    #[cfg(feature = "std")]
+    key_timeouts: UnsafeCell<SecondaryMap<K, Duration>>,
+}
+
+unsafe impl<K: Key, V, L: RawLock> Send for SyncSlotMap<K, V, L> where V: Send {}
+unsafe impl<K: Key, V, L: RawLock> Sync for SyncSlotMap<K, V, L> where V: Send + Sync {}
+
+impl<K: Key, V, L: RawLock> Drop for SyncSlotMap<K, V, L> {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        let leak = || core::mem::forget(core::mem::take(inner));
+        if unsafe { &*self.locks }.lock_for_drop("SyncSlotMap", leak) {
+            unsafe { drop(Box::from_raw(self.locks)) };
+        }
+    }
+}
+
+impl<K: Key, V, L: RawLock> Default for SyncSlotMap<K, V, L> {
+    fn default() -> Self {
+        Self::with_raw_lock()
+    }
+}
+
+/// Two maps are equal if they hold equal values under the same (unexpired) keys.
+///
+/// Keys only mean something within the map that made them, so this is only useful for maps
+/// whose keys share an origin, such as a map and the result of [`SyncSlotMap::map_values`] on
+/// it. Both maps are locked as for [`SyncSlotMap::for_each`], in address order so comparing
+/// the same two maps from different threads can't deadlock. Running into an entry that's
+/// locked exclusively makes the comparison wait for it and start over. A map is always equal to itself,
+/// without its values being looked at.
+impl<K: Key, V: PartialEq, L: RawLock> PartialEq for SyncSlotMap<K, V, L> {
+    fn eq(&self, other: &Self) -> bool {
+        // Locking the same map twice could deadlock against a waiting writer.
+        if core::ptr::eq(self, other) {
+            return true;
+        }
+        let (locks, other_locks) = unsafe { (&*self.locks, &*other.locks) };
+        Locks::with_both_shared(locks, other_locks, || {
+            let live = |map: &Self| {
+                unsafe { &*map.inner.get() }
+                    .keys()
+                    .filter(|&key| !map.is_expired(key))
+                    .count()
+            };
+            if live(self) != live(other) {
+                return Ok(false);
+            }
+            for (key, value) in unsafe { self.live_entries() } {
+                let Some(other_value) = other.lookup(key) else {
+                    return Ok(false);
+                };
+                let (lock, other_lock) = unsafe {
+                    (
+                        locks.element(key).unwrap(),
+                        other_locks.element(key).unwrap(),
+                    )
+                };
+                if !try_read_both(lock, other_lock, || value == unsafe { &*other_value })? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })
+    }
+}
+
+impl<K: Key, V> SyncSlotMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_raw_lock()
+    }
+}
+
+impl<K: Key, V, L: RawLock> SyncSlotMap<K, V, L> {
+    /// Creates an empty map which uses `L` for all of its locks (and its guards').
+    pub fn with_raw_lock() -> Self {
+        let locks = Box::new(Locks::new());
+        let locks = Box::into_raw(locks);
+        Self {
+            inner: UnsafeCell::new(SlotMap::with_key()),
+            locks,
+            observer: RwLock::new(None),
+            #[cfg(feature = "std")]
+            expiries: UnsafeCell::new(SecondaryMap::new()),
+            len: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            slots: AtomicU32::new(0),
+            #[cfg(feature = "std")]
+            default_timeout: Duration::ZERO,
+            #[cfg(feature = "std")]
+            key_timeouts: UnsafeCell::new(SecondaryMap::new()),
+        }
+    }
+
+    /// Starts configuring a map, see [`SyncSlotMapBuilder`].
+    pub fn builder() -> SyncSlotMapBuilder<K, V, L> {
+        SyncSlotMapBuilder {
+            capacity: 0,
+            #[cfg(feature = "std")]
+            default_timeout: Duration::ZERO,
+            fair: false,
+            backoff: None,
+            #[cfg(feature = "std")]
+            sparse_locks: false,
+            _map: PhantomData,
+        }
+    }
+
+    /// Consumes the map, returning an iterator over its values in slot order.
+    pub fn into_values(self) -> impl Iterator<Item = V> {
+        self.into_slot_map().into_iter().map(|(_, value)| *value)
+    }
+
+    /// Consumes the map, returning an iterator over its keys in slot order.
+    pub fn into_keys(self) -> impl Iterator<Item = K> {
+        self.into_slot_map().into_iter().map(|(key, _)| key)
+    }
+
+    // Guards don't borrow the map, so owning it doesn't rule them out. Like `drop`, wait for
+    // them to go away before taking the values they point at.
+    fn into_slot_map(mut self) -> SlotMap<K, Box<V>> {
+        let global = &unsafe { &*self.locks }.global;
+        global.lock_exclusive();
+        unsafe { global.unlock_exclusive() };
+        core::mem::take(self.inner.get_mut())
+    }
+
+    /// Sets whether guards (and whole-map operations) unlock fairly, handing their locks
+    /// straight to a waiting thread rather than letting whichever thread comes along first
+    /// barge in (see [`RawLock::unlock_shared_fair`]). Off by default.
+    ///
+    /// This is slower, but keeps a steady stream of readers from starving writers. It takes
+    /// effect immediately, including for guards which are already held.
+    pub fn set_fair(&self, fair: bool) {
+        unsafe { &*self.locks }.set_fair(fair);
+    }
+
+    /// Sets what dropping the map does while guards on it are still alive (they don't borrow
+    /// the map, so that can happen). See [`DropBehavior`]; the default is to wait.
+    pub fn set_drop_behavior(&self, behavior: DropBehavior) {
+        unsafe { &*self.locks }.set_drop_behavior(behavior);
+    }
+
+    /// Sets how long dropping the map waits for guards before giving up, with
+    /// [`DropBehavior::Panic`] or [`DropBehavior::Warn`]. A second by default.
+    ///
+    /// Without `std` there's no clock to wait against, so those only try once.
+    #[cfg(feature = "std")]
+    pub fn set_drop_timeout(&self, timeout: Duration) {
+        unsafe { &*self.locks }.set_drop_timeout(timeout);
+    }
+
+    /// Sets how many more times the `*_async` methods try a busy lock, spinning in between,
+    /// before yielding to the async runtime. Zero (the default) yields straight away.
+    ///
+    /// Spinning a little lets locks which are only held briefly be taken without the task
+    /// going back to the runtime's queue, at the cost of keeping the thread busy meanwhile.
+    pub fn set_spin_count(&self, spins: u32) {
+        unsafe { &*self.locks }.set_spins(spins);
+    }
+
+    /// The spin count set with `set_spin_count`.
+    pub fn spin_count(&self) -> u32 {
+        unsafe { &*self.locks }.spins()
+    }
+
+    /// Sets the callback told about every key that gets inserted or removed, replacing any
+    /// previous one.
+    ///
+    /// The callback runs on the inserting or removing thread right after the map's locks are
+    /// released, so it may use the map itself; it just may not call `on_change`. Since the locks
+    /// are already released, events from concurrent calls can arrive in a different order than
+    /// the changes were made.
+    pub fn on_change(&self, f: impl Fn(ChangeEvent<K>) + Send + Sync + 'static) {
+        *self.observer.write() = Some(Box::new(f));
+    }
+
+    /// Registers `metrics` to be told about inserts, removals and lock waits from now on,
+    /// replacing any previous collector.
+    ///
+    /// Without one, the map only checks a flag where it would call it.
+    pub fn set_metrics(&self, metrics: Arc<dyn Metrics>) {
+        unsafe { &*self.locks }.metrics.set(Some(metrics));
+    }
+
+    fn notify(&self, event: ChangeEvent<K>) {
+        if let Some(observer) = &*self.observer.read() {
+            observer(event);
+        }
+    }
+
+    pub fn insert(&self, value: V) -> K {
+        let locks = unsafe { &*self.locks };
+        trace::acquire("insert", None, || locks.structure.lock_exclusive());
+        let key = unsafe { self.insert_locked(value) };
+        unsafe { locks.structure.unlock_exclusive() };
+        trace::released("insert", Some(key.data()));
+        self.notify(ChangeEvent::Inserted(key));
+        key
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_insert_for(&self, value: V, timeout: Duration) -> Option<K> {
+        let locks = unsafe { &*self.locks };
+        if !locks.structure.try_lock_exclusive_for(timeout) {
+            return None;
+        }
+        let key = unsafe { self.insert_locked(value) };
+        unsafe { locks.structure.unlock_exclusive() };
+        self.notify(ChangeEvent::Inserted(key));
+        Some(key)
+    }
+
+    pub async fn insert_async(&self, value: V) -> K {
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive_async(locks.spins()).await;
+        let key = unsafe { self.insert_locked(value) };
+        unsafe { locks.structure.unlock_exclusive() };
+        self.notify(ChangeEvent::Inserted(key));
+        key
+    }
+
+    /// Like [`SyncSlotMap::insert`], also telling whether the entry got a slot of its own or
+    /// reused a removed entry's, e.g. to see how much churn a workload causes.
+    ///
+    /// `SlotMap` bumps a slot's version every time it is filled or emptied, so only a slot that
+    /// is filled for the first time has the initial version.
+    pub fn insert_reporting(&self, value: V) -> (K, SlotOrigin) {
+        let key = self.insert(value);
+        let origin = if version(key) == 1 {
+            SlotOrigin::Fresh
+        } else {
+            SlotOrigin::Recycled
+        };
+        (key, origin)
+    }
+
+    /// Inserts `value` and write-locks it, returning its key with the guard.
+    ///
+    /// The new entry is locked before the map's structure is unlocked, so no other thread can
+    /// see, change or remove it until the guard is dropped, unlike calling
+    /// [`SyncSlotMap::get_mut`] after [`SyncSlotMap::insert`].
+    pub fn insert_and_get(&self, value: V) -> (K, SyncSlotGuardMut<V, L>) {
+        let locks = unsafe { &*self.locks };
+        // The key isn't known yet to pick its shard, but any shard keeps writers out.
+        let global = locks.global.any_shard();
+        global.lock_shared();
+        trace::acquire("insert", None, || locks.structure.lock_exclusive());
+        let key = unsafe { self.insert_locked_with(value, true) };
+        let lock = unsafe { locks.element(key) }.unwrap();
+        // Nobody else can reach the new lock until `structure` is released.
+        lock.lock_exclusive();
+        // The guard holds its key's shard like any other, see `force_unlock_key`.
+        let shard = locks.global.lock_shard_from_any(key);
+        let guard = SyncSlotGuardMut {
+            value: self.lookup(key).unwrap(),
+            locks: locks.guard_locks(shard, lock),
+            key: key.data(),
+        };
+        unsafe {
+            global.unlock_shared();
+            locks.structure.unlock_exclusive();
+        }
+        trace::released("insert", Some(key.data()));
+        self.notify(ChangeEvent::Inserted(key));
+        (key, guard)
+    }
+
+    /// Like [`SyncSlotMap::insert_and_get`], waiting on the map's locks asynchronously.
+    ///
+    /// Dropping the future before it completes drops `value` and releases whatever it locked,
+    /// without inserting anything.
+    pub async fn insert_and_get_async(&self, value: V) -> (K, SyncSlotGuardMut<V, L>) {
+        let locks = unsafe { &*self.locks };
+        let global = locks.global.any_shard();
+        Access::Shared.lock_async(global, locks.spins()).await;
+        let held_global = unsafe { Held::new(global, Access::Shared) };
+        locks.structure.lock_exclusive_async(locks.spins()).await;
+        held_global.keep();
+        let key = unsafe { self.insert_locked_with(value, true) };
+        let lock = unsafe { locks.element(key) }.unwrap();
+        // A new lock, so this never blocks.
+        lock.lock_exclusive();
+        let shard = locks.global.lock_shard_from_any(key);
+        let guard = SyncSlotGuardMut {
+            value: self.lookup(key).unwrap(),
+            locks: locks.guard_locks(shard, lock),
+            key: key.data(),
+        };
+        unsafe {
+            global.unlock_shared();
+            locks.structure.unlock_exclusive();
+        }
+        self.notify(ChangeEvent::Inserted(key));
+        (key, guard)
+    }
+
+    // Only the layout changes, nothing moves, so guards on other keys don't need to be waited for.
+    unsafe fn insert_locked(&self, value: V) -> K {
+        self.insert_locked_with(value, false)
+    }
+
+    // `unused_lock` gives the entry a lock nobody can be holding, see `Locks::register_unused`,
+    // for callers that lock it before releasing `structure`.
+    unsafe fn insert_locked_with(&self, value: V, unused_lock: bool) -> K {
+        let key = (*self.inner.get()).insert(Box::new(value));
+        if unused_lock {
+            (*self.locks).register_unused(key);
+        } else {
+            (*self.locks).register(key);
+        }
+        (*self.locks).metrics.with(|metrics| metrics.on_insert());
+        let len = self.len.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(len, Ordering::Relaxed);
+        self.slots.fetch_max(slot_index(key) + 1, Ordering::Relaxed);
+        key
+    }
+
+    /// Inserts a value which expires after `ttl`.
+    ///
+    /// Once expired, the entry can't be gotten anymore and scans such as
+    /// [`SyncSlotMap::for_each`] or [`SyncSlotMap::retain`] skip it, but it is only actually
+    /// removed (and the value dropped) by [`SyncSlotMap::reap_expired`] or
+    /// [`SyncSlotMap::remove`].
+    #[cfg(feature = "std")]
+    pub fn insert_with_ttl(&self, value: V, ttl: Duration) -> K {
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive();
+        let key = unsafe { self.insert_locked(value) };
+        unsafe { (*self.expiries.get()).insert(key, Instant::now() + ttl) };
+        unsafe { locks.structure.unlock_exclusive() };
+        self.notify(ChangeEvent::Inserted(key));
+        key
+    }
+
+    /// Removes every expired entry, returning how many there were.
+    ///
+    /// The whole map is locked exclusively while they are found and unlinked, so this waits for
+    /// every guard to be dropped, and the locks are compacted (see
+    /// [`SyncSlotMap::compact_locks`]) while it is. The values are dropped after it is unlocked
+    /// again.
+    #[cfg(feature = "std")]
+    pub fn reap_expired(&self) -> usize {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        let now = Instant::now();
+        let expired: Vec<K> = unsafe { &*self.expiries.get() }
+            .iter()
+            .filter(|(_, &expiry)| expiry <= now)
+            .map(|(key, _)| key)
+            .collect();
+        let mut removed = Vec::with_capacity(expired.len());
+        for &key in &expired {
+            let (value, lock) = unsafe { self.remove_locked(key) }.unwrap();
+            // There are no guards while `global` is held exclusively, so this doesn't wait.
+            unsafe { locks.retire(lock) };
+            removed.push(value);
+        }
+        unsafe {
+            locks.shrink_to_fit();
+            locks.unlock_exclusive();
+        }
+        drop(removed);
+        for &key in &expired {
+            self.notify(ChangeEvent::Removed(key));
+        }
+        expired.len()
+    }
+
+    pub fn remove(&self, key: K) -> Option<V> {
+        let locks = unsafe { &*self.locks };
+        trace::acquire("remove", Some(key.data()), || {
+            locks.structure.lock_exclusive()
+        });
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.structure.unlock_exclusive() };
+        trace::released("remove", Some(key.data()));
+        let (value, lock) = removed?;
+        self.notify(ChangeEvent::Removed(key));
+        // Retiring the lock waits out any guards still holding the removed entry.
+        trace::acquire("remove_retire", Some(key.data()), || unsafe {
+            locks.retire(lock)
+        });
+        Some(*value)
+    }
+
+    /// Removes `key` and passes its value to `f` before unlocking the map, returning what `f`
+    /// returned, or `None` (without calling `f`) if `key` isn't in the map.
+    ///
+    /// The whole map is locked exclusively like [`SyncSlotMap::remove_many`], so `f` runs
+    /// serialized with every other operation on the map, inserts included. `f` must not use the
+    /// map itself, which would deadlock.
+    pub fn remove_with<R, F: FnOnce(V) -> R>(&self, key: K, f: F) -> Option<R> {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        let held = unsafe { HeldExclusive::new(locks) };
+        let result = unsafe { self.remove_locked(key) }.map(|(value, lock)| {
+            // No guards exist while `global` is held exclusively, so retiring is free.
+            unsafe { locks.retire(lock) };
+            f(*value)
+        });
+        drop(held);
+        if result.is_some() {
+            self.notify(ChangeEvent::Removed(key));
+        }
+        result
+    }
+
+    /// Removes `key` if `pred` accepts its value, returning the value, or `None` (leaving the
+    /// entry alone) if `pred` rejects it or `key` isn't in the map.
+    ///
+    /// `pred` runs with the entry write-locked and the map's structure locked exclusively, like
+    /// [`SyncSlotMap::remove`], so nothing can change the value or remove the entry between the
+    /// check and the removal. Taking the structure lock while already holding the entry could
+    /// deadlock against a scan such as [`SyncSlotMap::for_each`], which holds the structure
+    /// while it waits for each entry, so the structure is taken first and the entry only
+    /// tried; if it's busy the structure is let go while waiting for it. Like a guard, this
+    /// holds a share of the map's global lock throughout, rather than locking the whole map,
+    /// which this thread's own guards would keep from ever happening. `pred` must not use the
+    /// map itself, which would deadlock.
+    pub fn remove_if<F: FnOnce(&V) -> bool>(&self, key: K, pred: F) -> Option<V> {
+        let locks = unsafe { &*self.locks };
+        // Keeps the recycled locks from being freed while one is waited on below.
+        let global = locks.global.shard(key);
+        global.lock_shared();
+        let lock = loop {
+            trace::acquire("remove", Some(key.data()), || {
+                locks.structure.lock_exclusive()
+            });
+            let Some(lock) = (unsafe { locks.element(key) }) else {
+                unsafe {
+                    locks.structure.unlock_exclusive();
+                    global.unlock_shared();
+                }
+                return None;
+            };
+            if lock.try_lock_exclusive() {
+                break lock;
+            }
+            unsafe { locks.structure.unlock_exclusive() };
+            // If `key` is removed meanwhile its lock is recycled, which can't be freed while
+            // `global` is held.
+            lock.lock_exclusive();
+            unsafe { lock.unlock_exclusive() };
+        };
+        // Everything is released if `pred` panics.
+        let held = unsafe {
+            (
+                Held::new(global, Access::Shared),
+                Held::new(lock, Access::Exclusive),
+                ExclusiveShards::all(&locks.structure),
+            )
+        };
+        let removed = match self.lookup(key) {
+            Some(value) if pred(unsafe { &*value }) => unsafe { self.remove_locked(key) },
+            _ => None,
+        };
+        drop(held);
+        trace::released("remove", Some(key.data()));
+        let (value, lock) = removed?;
+        self.notify(ChangeEvent::Removed(key));
+        unsafe { locks.retire(lock) };
+        Some(*value)
+    }
+
+    /// Removes every key in `keys`, returning what was under each of them in the same order.
+    ///
+    /// The whole map is locked exclusively once for the batch, which waits for every guard to
+    /// be dropped but makes retiring the removed entries' locks free. A key listed twice is
+    /// only found the first time.
+    pub fn remove_many(&self, keys: &[K]) -> Vec<Option<V>> {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        unsafe { self.remove_many_locked(keys) }
+    }
+
+    /// Like [`SyncSlotMap::remove_many`], but gives up (returning `None`) if the map can't be
+    /// locked within `timeout`.
+    #[cfg(feature = "std")]
+    pub fn try_remove_many_for(&self, keys: &[K], timeout: Duration) -> Option<Vec<Option<V>>> {
+        let locks = unsafe { &*self.locks };
+        if !locks.try_lock_exclusive_for(timeout) {
+            return None;
+        }
+        Some(unsafe { self.remove_many_locked(keys) })
+    }
+
+    // Unlocks the map, which must have been locked with `Locks::lock_exclusive`.
+    unsafe fn remove_many_locked(&self, keys: &[K]) -> Vec<Option<V>> {
+        let locks = &*self.locks;
+        let removed: Vec<Option<V>> = keys
+            .iter()
+            .map(|&key| {
+                let (value, lock) = self.remove_locked(key)?;
+                locks.retire(lock);
+                Some(*value)
+            })
+            .collect();
+        locks.unlock_exclusive();
+        for (&key, value) in keys.iter().zip(&removed) {
+            if value.is_some() {
+                self.notify(ChangeEvent::Removed(key));
+            }
+        }
+        removed
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_remove_for(&self, key: K, timeout: Duration) -> Option<Option<V>> {
+        let locks = unsafe { &*self.locks };
+        if !locks.structure.try_lock_exclusive_for(timeout) {
+            return None;
+        }
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let Some((value, lock)) = removed else {
+            return Some(None);
+        };
+        self.notify(ChangeEvent::Removed(key));
+        unsafe { locks.retire(lock) };
+        Some(Some(*value))
+    }
+
+    pub async fn remove_async(&self, key: K) -> Option<V> {
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive_async(locks.spins()).await;
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let (value, lock) = removed?;
+        self.notify(ChangeEvent::Removed(key));
+        let value = unsafe { locks.retire_async(lock, value).await };
+        Some(*value)
+    }
+
+    // The entry is unlinked here, the caller then retires its lock, which waits out any guards
+    // still pointing at the value before it is moved out of its box.
+    unsafe fn remove_locked(&self, key: K) -> Option<(Box<V>, Box<L>)> {
+        let value = (*self.inner.get()).remove(key)?;
+        #[cfg(feature = "std")]
+        {
+            (*self.expiries.get()).remove(key);
+            (*self.key_timeouts.get()).remove(key);
+        }
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        (*self.locks).metrics.with(|metrics| metrics.on_remove());
+        let lock = (*self.locks).unregister(key)?;
+        Some((value, lock))
+    }
+
+    /// Gives memory left over from removed entries back to the allocator, waiting for every
+    /// guard to be dropped first.
+    ///
+    /// This trims the map's locks and the bookkeeping for [`SyncSlotMap::insert_with_ttl`] and
+    /// [`SyncSlotMap::set_key_timeout`].
+    /// The value slots themselves can't shrink: `SlotMap` keeps every slot it has ever made so
+    /// that old keys can never match a new entry, and offers no way to give them back.
+    pub fn shrink_to_fit(&self) {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        unsafe {
+            #[cfg(feature = "std")]
+            {
+                let expiries = &mut *self.expiries.get();
+                *expiries = expiries.drain().collect();
+                let key_timeouts = &mut *self.key_timeouts.get();
+                *key_timeouts = key_timeouts.drain().collect();
+            }
+            locks.shrink_to_fit();
+            locks.unlock_exclusive();
+        }
+    }
+
+    /// Frees the element locks of removed entries that are kept around for reuse, and shrinks
+    /// the table of element locks down to the highest slot still in use.
+    ///
+    /// The table is indexed by slot, so without this it stays as large as the map has ever
+    /// been. It waits for every guard to be dropped first, like [`SyncSlotMap::shrink_to_fit`].
+    /// Nothing compacts automatically except in [`SyncSlotMap::shrink_to_fit`] and
+    /// [`SyncSlotMap::reap_expired`], which have the whole map locked anyway.
+    pub fn compact_locks(&self) {
+        unsafe { &*self.locks }.compact();
+    }
+
+    /// Whether `key` is in the map. Doesn't wait for guards on the entry.
+    pub fn contains_key(&self, key: K) -> bool {
+        let structure = unsafe { &*self.locks }.structure.shard(key);
+        structure.lock_shared();
+        let contains = self.lookup(key).is_some();
+        unsafe { structure.unlock_shared() };
+        contains
+    }
+
+    /// A view of the map that can read and modify its entries but not insert or remove any,
+    /// for handing to code that shouldn't change which entries there are.
+    ///
+    /// ```
+    /// use slotmap::DefaultKey;
+    /// use sync_slotmap::SyncSlotMap;
+    ///
+    /// let map = SyncSlotMap::<DefaultKey, u32>::new();
+    /// let key = map.insert(1);
+    /// let view = map.view();
+    /// *view.get_mut(key).unwrap() += 1;
+    /// assert_eq!(*map.get(key).unwrap(), 2);
+    /// ```
+    ///
+    /// ```compile_fail
+    /// use slotmap::DefaultKey;
+    /// use sync_slotmap::SyncSlotMap;
+    ///
+    /// let map = SyncSlotMap::<DefaultKey, u32>::new();
+    /// map.view().insert(1);
+    /// ```
+    pub fn view(&self) -> ReadWriteView<'_, K, V, L> {
+        ReadWriteView { map: self }
+    }
+
+    /// A handle to a map shared through an `Arc` that doesn't keep it alive, e.g. for a
+    /// background task that should stop once everything else is done with the map.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use slotmap::DefaultKey;
+    /// use sync_slotmap::SyncSlotMap;
+    ///
+    /// let map = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+    /// let weak = SyncSlotMap::downgrade(&map);
+    /// assert!(weak.upgrade().is_some());
+    /// drop(map);
+    /// assert!(weak.upgrade().is_none());
+    /// ```
+    pub fn downgrade(this: &Arc<Self>) -> WeakSyncSlotMap<K, V, L> {
+        WeakSyncSlotMap {
+            map: Arc::downgrade(this),
+        }
+    }
+
+    /// How many entries the map can hold before it has to grow, like `SlotMap::capacity`.
+    pub fn capacity(&self) -> usize {
+        let structure = unsafe { &*self.locks }.structure.any_shard();
+        structure.lock_shared();
+        let capacity = unsafe { &*self.inner.get() }.capacity();
+        unsafe { structure.unlock_shared() };
+        capacity
+    }
+
+    /// Makes room for at least `additional` more entries, like `SlotMap::reserve`.
+    ///
+    /// Only the map's structure is locked, like for [`SyncSlotMap::insert`]; values don't move
+    /// when it grows, so guards don't need to be waited for.
+    pub fn reserve(&self, additional: usize) {
+        let structure = &unsafe { &*self.locks }.structure;
+        structure.lock_exclusive();
+        unsafe { &mut *self.inner.get() }.reserve(additional);
+        unsafe { structure.unlock_exclusive() };
+    }
+
+    /// The number of entries, read without taking any lock.
+    ///
+    /// It's updated as inserts and removals happen, so with those going on concurrently it may
+    /// already be out of date by the time it's returned. Expired entries count until they are
+    /// reaped.
+    pub fn approx_len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// The most entries there have been at once since the map was created or
+    /// [`SyncSlotMap::reset_peak`] was last called, e.g. to size the next map's capacity.
+    ///
+    /// Like [`SyncSlotMap::approx_len`], it's read without taking any lock.
+    pub fn peak_len(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Starts tracking [`SyncSlotMap::peak_len`] over again from the current number of entries.
+    pub fn reset_peak(&self) {
+        self.peak
+            .store(self.len.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    // Must be called with `structure` held.
+    #[cfg(feature = "std")]
+    fn is_expired(&self, key: K) -> bool {
+        unsafe { &*self.expiries.get() }
+            .get(key)
+            .is_some_and(|&expiry| expiry <= Instant::now())
+    }
+
+    // Entries can only be given a TTL with `std`.
+    #[cfg(not(feature = "std"))]
+    fn is_expired(&self, _key: K) -> bool {
+        false
+    }
+
+    // The entries that haven't expired, in slot order. `structure` must be held for as long as
+    // the iterator and the values it returns are used.
+    unsafe fn live_entries(&self) -> impl Iterator<Item = (K, &V)> {
+        (*self.inner.get())
+            .iter()
+            .filter(|&(key, _)| !self.is_expired(key))
+            .map(|(key, value)| (key, &**value))
+    }
+
+    // Like `live_entries`, with the whole map locked exclusively instead.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn live_entries_mut(&self) -> impl Iterator<Item = (K, &mut V)> {
+        (*self.inner.get())
+            .iter_mut()
+            .filter(|&(key, _)| !self.is_expired(key))
+            .map(|(key, value)| (key, &mut **value))
+    }
+
+    fn lookup(&self, key: K) -> Option<*mut V> {
+        if self.is_expired(key) {
+            return None;
+        }
+        unsafe { &*self.inner.get() }
+            .get(key)
+            .map(|value| &**value as *const V as *mut V)
+    }
+
+    pub fn get(&self, key: K) -> Option<SyncSlotGuard<V, L>> {
+        let (locks, value) = trace::acquire("get", Some(key.data()), || unsafe {
+            (*self.locks).acquire(key, Access::Shared, || self.lookup(key))
+        })?;
+        Some(SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    /// Like [`SyncSlotMap::get`], but tells a key whose entry has been removed apart from one
+    /// the map never made, e.g. to catch keys used after removing their entry.
+    ///
+    /// Only slot indices are remembered, so a key from another map is reported as stale if this
+    /// map has a slot with its index.
+    ///
+    /// ```
+    /// use slotmap::{DefaultKey, Key};
+    /// use sync_slotmap::{GetResult, SyncSlotMap};
+    ///
+    /// let map = SyncSlotMap::<DefaultKey, i32>::new();
+    /// let old = map.insert(1);
+    /// map.remove(old);
+    /// let new = map.insert(2);
+    /// assert!(matches!(map.get_checked(new), GetResult::Live(guard) if *guard == 2));
+    /// assert!(matches!(map.get_checked(old), GetResult::Stale));
+    /// assert!(matches!(map.get_checked(DefaultKey::null()), GetResult::Unknown));
+    /// ```
+    pub fn get_checked(&self, key: K) -> GetResult<V, L> {
+        if let Some(guard) = self.get(key) {
+            return GetResult::Live(guard);
+        }
+        // Whoever handed out `key` did so after its slot was made.
+        if !key.is_null() && slot_index(key) < self.slots.load(Ordering::Relaxed) {
+            GetResult::Stale
+        } else {
+            GetResult::Unknown
+        }
+    }
+
+    pub fn get_mut(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        let (locks, value) = trace::acquire("get_mut", Some(key.data()), || unsafe {
+            (*self.locks).acquire(key, Access::Exclusive, || self.lookup(key))
+        })?;
+        Some(SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    /// Write-locks the value under `key` and checks it with `valid`, removing the entry and
+    /// returning `None` if it fails, e.g. to evict stale cache entries as they are looked up.
+    ///
+    /// Taking the structure lock to unlink the entry while holding its guard could deadlock
+    /// against a scan such as [`SyncSlotMap::for_each`], so a failed entry is unlocked and then
+    /// removed with [`SyncSlotMap::remove_if`], which checks it with `valid` again. It's only
+    /// removed if it still fails then, so a value another thread fixed up in between is kept,
+    /// and the key's version makes sure an entry inserted into the same slot meanwhile is left
+    /// alone.
+    pub fn get_mut_validated<F: Fn(&V) -> bool>(
+        &self,
+        key: K,
+        valid: F,
+    ) -> Option<SyncSlotGuardMut<V, L>> {
+        let guard = self.get_mut(key)?;
+        if valid(&guard) {
+            return Some(guard);
+        }
+        drop(guard);
+        self.remove_if(key, |value| !valid(value));
+        None
+    }
+
+    /// The same as [`SyncSlotMap::get_mut`], for symmetry with
+    /// [`SyncSecondarySlotMap::get_mut_or_default`](crate::SyncSecondarySlotMap::get_mut_or_default).
+    ///
+    /// A slot map hands out its own keys, so one that isn't in the map has either been removed
+    /// or belongs to another map, and a value can never be inserted under it. This returns
+    /// `None` for those rather than making up a default. Use a secondary map to get defaults
+    /// filled in for keys of a primary map.
+    pub fn get_mut_or_default(&self, key: K) -> Option<SyncSlotGuardMut<V, L>>
+    where
+        V: Default,
+    {
+        self.get_mut(key)
+    }
+
+    /// Like [`SyncSlotMap::get`], taking anything that converts into a key, such as a wrapper
+    /// around one.
+    pub fn get_by<Q: Into<K>>(&self, key: Q) -> Option<SyncSlotGuard<V, L>> {
+        self.get(key.into())
+    }
+
+    /// Like [`SyncSlotMap::get_mut`], taking anything that converts into a key.
+    pub fn get_mut_by<Q: Into<K>>(&self, key: Q) -> Option<SyncSlotGuardMut<V, L>> {
+        self.get_mut(key.into())
+    }
+
+    /// Like [`SyncSlotMap::get`], but safe to call while the current thread already holds
+    /// [`SyncSlotGuard`]s, including ones for `key` itself.
+    ///
+    /// A plain `get` can deadlock there: if another thread is waiting to write (to `key`, or to
+    /// the map as a whole), `get` queues up behind it while that writer waits for the guard the
+    /// current thread holds. This skips the queue instead, at the cost of possibly starving
+    /// writers if it is used all the time. A writer locking the whole map may already have
+    /// locked part of it while it waits, so this always takes the part such a writer locks
+    /// last, which it can't have yet.
+    ///
+    /// It doesn't help if the current thread holds a [`SyncSlotGuardMut`] for `key`, which
+    /// always deadlocks, as does calling `get_mut` for a key the thread holds any guard for.
+    pub fn get_recursive(&self, key: K) -> Option<SyncSlotGuard<V, L>> {
+        let (locks, value) = unsafe { (*self.locks).acquire_recursive(key, || self.lookup(key)) }?;
+        Some(SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    /// Like [`SyncSlotMap::get`], also returning the key the value is stored under.
+    pub fn get_key_value(&self, key: K) -> Option<(K, SyncSlotGuard<V, L>)> {
+        let (locks, value) =
+            unsafe { (*self.locks).acquire(key, Access::Shared, || self.lookup(key)) }?;
+        Some((
+            key,
+            SyncSlotGuard {
+                value,
+                locks,
+                key: key.data(),
+            },
+        ))
+    }
+
+    /// Like [`SyncSlotMap::get_mut`], also returning the key the value is stored under.
+    pub fn get_key_value_mut(&self, key: K) -> Option<(K, SyncSlotGuardMut<V, L>)> {
+        let (locks, value) =
+            unsafe { (*self.locks).acquire(key, Access::Exclusive, || self.lookup(key)) }?;
+        Some((
+            key,
+            SyncSlotGuardMut {
+                value,
+                locks,
+                key: key.data(),
+            },
+        ))
+    }
+
+    /// Read-locks the first entry in slot order, returning it along with its key.
+    ///
+    /// Slot order follows insertion order only until something is removed, as freed slots are
+    /// reused by later inserts.
+    pub fn first_key_value(&self) -> Option<(K, SyncSlotGuard<V, L>)> {
+        self.key_value_at(|keys| keys.next())
+    }
+
+    /// Read-locks the last entry in slot order, returning it along with its key.
+    ///
+    /// Like [`SyncSlotMap::first_key_value`], but this walks the slots to find it.
+    pub fn last_key_value(&self) -> Option<(K, SyncSlotGuard<V, L>)> {
+        self.key_value_at(|keys| keys.last())
+    }
+
+    fn key_value_at(
+        &self,
+        pick: impl Fn(&mut dyn Iterator<Item = K>) -> Option<K>,
+    ) -> Option<(K, SyncSlotGuard<V, L>)> {
+        let structure = unsafe { &*self.locks }.structure.any_shard();
+        loop {
+            structure.lock_shared();
+            let key = pick(
+                &mut unsafe { &*self.inner.get() }
+                    .keys()
+                    .filter(|&key| !self.is_expired(key)),
+            );
+            unsafe { structure.unlock_shared() };
+            // The key may have been removed before it could be locked, pick again if so.
+            if let Some(found) = self.get_key_value(key?) {
+                return Some(found);
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_get_for(&self, key: K, timeout: Duration) -> Option<Option<SyncSlotGuard<V, L>>> {
+        let acquired = unsafe {
+            (*self.locks).try_acquire_for(key, Access::Shared, timeout, || self.lookup(key))
+        }?;
+        Some(acquired.map(|(locks, value)| SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        }))
+    }
+
+    /// Like [`SyncSlotMap::try_get_for`], waiting for the timeout set for `key` with
+    /// [`SyncSlotMap::set_key_timeout`], or else the default timeout set with
+    /// [`SyncSlotMapBuilder::default_timeout`] (or not at all if there is neither).
+    #[cfg(feature = "std")]
+    pub fn try_get(&self, key: K) -> Option<Option<SyncSlotGuard<V, L>>> {
+        self.try_get_for(key, self.timeout_for(key))
+    }
+
+    /// Like [`SyncSlotMap::try_get_mut_for`], waiting as long as [`SyncSlotMap::try_get`].
+    #[cfg(feature = "std")]
+    pub fn try_get_mut(&self, key: K) -> Option<Option<SyncSlotGuardMut<V, L>>> {
+        self.try_get_mut_for(key, self.timeout_for(key))
+    }
+
+    /// Sets how long [`SyncSlotMap::try_get`] and [`SyncSlotMap::try_get_mut`] wait for `key`
+    /// in particular, overriding the map's default timeout, e.g. for entries known to be held
+    /// for long. Returns `false` if `key` isn't in the map.
+    ///
+    /// The override goes away with the entry.
+    #[cfg(feature = "std")]
+    pub fn set_key_timeout(&self, key: K, timeout: Duration) -> bool {
+        let structure = &unsafe { &*self.locks }.structure;
+        structure.lock_exclusive();
+        let set = unsafe { &*self.inner.get() }.contains_key(key);
+        if set {
+            unsafe { (*self.key_timeouts.get()).insert(key, timeout) };
+        }
+        unsafe { structure.unlock_exclusive() };
+        set
+    }
+
+    /// Removes the timeout set for `key` with [`SyncSlotMap::set_key_timeout`], returning it.
+    #[cfg(feature = "std")]
+    pub fn clear_key_timeout(&self, key: K) -> Option<Duration> {
+        let structure = &unsafe { &*self.locks }.structure;
+        structure.lock_exclusive();
+        let timeout = unsafe { (*self.key_timeouts.get()).remove(key) };
+        unsafe { structure.unlock_exclusive() };
+        timeout
+    }
+
+    #[cfg(feature = "std")]
+    fn timeout_for(&self, key: K) -> Duration {
+        let structure = unsafe { &*self.locks }.structure.shard(key);
+        structure.lock_shared();
+        let timeout = unsafe { &*self.key_timeouts.get() }.get(key).copied();
+        unsafe { structure.unlock_shared() };
+        timeout.unwrap_or(self.default_timeout)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_get_mut_for(
+        &self,
+        key: K,
+        timeout: Duration,
+    ) -> Option<Option<SyncSlotGuardMut<V, L>>> {
+        let acquired = unsafe {
+            (*self.locks).try_acquire_for(key, Access::Exclusive, timeout, || self.lookup(key))
+        }?;
+        Some(acquired.map(|(locks, value)| SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        }))
+    }
+
+    /// Clones the value under `key`, holding its locks only for as long as that takes.
+    pub fn get_cloned(&self, key: K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.get(key).map(|guard| V::clone(&guard))
+    }
+
+    /// Clones the values under `keys`, in the same order, with `None` for keys that aren't in
+    /// the map.
+    ///
+    /// The whole map is kept from being locked exclusively for the duration, so no
+    /// [`SyncSlotMap::for_each_mut`] and the like can run in the middle, but each value is only
+    /// read-locked while it is cloned, so writers of single entries aren't held up for long.
+    pub fn get_many_cloned(&self, keys: &[K]) -> Vec<Option<V>>
+    where
+        V: Clone,
+    {
+        let locks = unsafe { &*self.locks };
+        let global = locks.global.any_shard();
+        global.lock_shared();
+        let values = keys
+            .iter()
+            .map(|&key| unsafe { locks.read_held(key, || self.lookup(key), V::clone) })
+            .collect();
+        unsafe { global.unlock_shared() };
+        values
+    }
+
+    /// Like [`SyncSlotMap::get_cloned`], but gives up (returning `None`) if the value can't be
+    /// read-locked within `timeout`.
+    #[cfg(feature = "std")]
+    pub fn try_get_cloned_for(&self, key: K, timeout: Duration) -> Option<Option<V>>
+    where
+        V: Clone,
+    {
+        self.try_get_for(key, timeout)
+            .map(|guard| guard.map(|guard| V::clone(&guard)))
+    }
+
+    pub async fn get_async(&self, key: K) -> Option<SyncSlotGuard<V, L>> {
+        let (locks, value) = unsafe {
+            (*self.locks)
+                .acquire_async(key, Access::Shared, || self.lookup(key))
+                .await
+        }?;
+        Some(SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    /// Like [`SyncSlotMap::get_async`], but resolves to `None` as soon as `token` is cancelled
+    /// if the entry hasn't been locked by then.
+    ///
+    /// Locks taken on the way are released when the wait is given up, just like when dropping
+    /// the future of `get_async`.
+    #[cfg(feature = "tokio-util")]
+    pub async fn get_async_cancellable(
+        &self,
+        key: K,
+        token: &tokio_util::sync::CancellationToken,
+    ) -> Option<SyncSlotGuard<V, L>> {
+        token
+            .run_until_cancelled(self.get_async(key))
+            .await
+            .flatten()
+    }
+
+    pub async fn get_mut_async(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        let (locks, value) = unsafe {
+            (*self.locks)
+                .acquire_async(key, Access::Exclusive, || self.lookup(key))
+                .await
+        }?;
+        Some(SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    /// Like [`SyncSlotMap::get_mut_async`], but gives up (returning `None`) once `timeout` has
+    /// passed. Giving up, like dropping the future, releases every lock taken so far.
+    ///
+    /// The deadline is checked whenever the wait is woken up. The default locks yield to the
+    /// runtime between tries, so that's often; `TokioRawLock` (with the `tokio` feature) sleeps
+    /// until the lock is released, so for a hard deadline wrap the call in the runtime's own
+    /// timeout instead, e.g. `tokio::time::timeout`.
+    #[cfg(feature = "std")]
+    pub async fn get_mut_async_for(
+        &self,
+        key: K,
+        timeout: Duration,
+    ) -> Option<Option<SyncSlotGuardMut<V, L>>> {
+        let deadline = Deadline::after(timeout);
+        let mut acquire = core::pin::pin!(self.get_mut_async(key));
+        core::future::poll_fn(|cx| match acquire.as_mut().poll(cx) {
+            Poll::Ready(guard) => Poll::Ready(Some(guard)),
+            Poll::Pending if deadline.has_passed() => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        })
+        .await
+    }
+
+    /// Whether `key` is currently held by a [`SyncSlotGuardMut`], or `None` if it isn't in the
+    /// map.
+    ///
+    /// This never blocks on the element itself, and the answer may already be stale by the
+    /// time it is returned, so only use it as a hint (e.g. to skip busy keys).
+    pub fn is_locked_exclusive(&self, key: K) -> Option<bool> {
+        unsafe { &*self.locks }.inspect(key, |lock| lock.is_locked_exclusive())
+    }
+
+    /// Whether `key` is currently held by any guard, or `None` if it isn't in the map.
+    ///
+    /// Like [`SyncSlotMap::is_locked_exclusive`], this is advisory only.
+    pub fn is_locked(&self, key: K) -> Option<bool> {
+        unsafe { &*self.locks }.inspect(key, |lock| lock.is_locked())
+    }
+
+    /// Unlocks an entry whose guard was given up with [`SyncSlotGuardMut::leak`].
+    ///
+    /// Every [`SyncSlotGuardMut`] of the map holds the part of its global lock that `key` picks,
+    /// whichever method it came from (`get_mut`, `insert_and_get`, `get_disjoint_mut_many` and
+    /// the rest), so that is what gets released along with the entry.
+    ///
+    /// # Safety
+    /// The entry must be locked by a leaked [`SyncSlotGuardMut`] of this map for this same
+    /// `key`, which mustn't be unlocked twice. `key` must not have been removed in the meantime
+    /// (a removal waits for the entry to be unlocked, so it can't be found by key anymore).
+    pub unsafe fn unlock_exclusive_raw(&self, key: K) {
+        let locks = &*self.locks;
+        let structure = locks.structure.shard(key);
+        structure.lock_shared();
+        let lock = locks
+            .element(key)
+            .expect("unlock_exclusive_raw called for a key which isn't in the map");
+        let global = locks.global.shard(key);
+        locks.guard_locks(global, lock).unlock(Access::Exclusive);
+        structure.unlock_shared();
+    }
+
+    /// Releases the hold a guard for `key` had, for recovering from a guard that was lost
+    /// without being dropped (e.g. forgotten, or stuck in a thread that will never finish).
+    ///
+    /// `exclusive` says whether that was a [`SyncSlotGuardMut`] or a [`SyncSlotGuard`]; whether
+    /// the element lock is held exclusively can't be told apart reliably from a writer waiting
+    /// on it, so it has to be known.
+    ///
+    /// This works for guards from any of the map's methods apart from
+    /// [`SyncSlotMap::get_recursive`], whose guards hold another part of the map's global lock
+    /// than the one `key` picks, and can't be force-unlocked.
+    ///
+    /// # Safety
+    /// This is a last resort. There must be a hold of that kind on `key` that nothing is ever
+    /// going to release, or some live guard is left pointing at an entry it no longer has
+    /// locked, which is undefined behaviour as soon as anyone else locks the entry. `key` must
+    /// still be in the map, and the hold must not come from [`SyncSlotMap::get_recursive`].
+    pub unsafe fn force_unlock_key(&self, key: K, exclusive: bool) {
+        let locks = &*self.locks;
+        let structure = locks.structure.shard(key);
+        structure.lock_shared();
+        let lock = locks
+            .element(key)
+            .expect("force_unlock_key called for a key which isn't in the map");
+        let access = if exclusive {
+            Access::Exclusive
+        } else {
+            Access::Shared
+        };
+        locks
+            .guard_locks(locks.global.shard(key), lock)
+            .unlock(access);
+        structure.unlock_shared();
+    }
+
+    /// Unlocks the whole map after a whole-map operation got stuck holding it exclusively, e.g.
+    /// a closure passed to [`SyncSlotMap::for_each_mut`] that never returns. (One that panics
+    /// unlocks the map as it unwinds.)
+    ///
+    /// # Safety
+    /// This is a last resort. The map must be locked exclusively as a whole by something that
+    /// is never going to unlock it, or that something is left using the map while others can
+    /// too.
+    pub unsafe fn force_unlock_global(&self) {
+        (*self.locks).unlock_exclusive();
+    }
+
+    /// Counts the entries held by guards, for metrics.
+    ///
+    /// Nothing is locked apart from briefly keeping the set of keys still, so this doesn't wait
+    /// for guards or writers and the numbers may be stale by the time they are returned.
+    pub fn lock_stats(&self) -> LockStats {
+        let locks = unsafe { &*self.locks };
+        let global_is_writer_waiting = locks.global.is_locked_exclusive();
+        let structure = locks.structure.any_shard();
+        structure.lock_shared();
+        let locked_keys = unsafe { &*self.inner.get() }
+            .keys()
+            .filter(|&key| unsafe { locks.element(key) }.is_some_and(|lock| lock.is_locked()))
+            .count();
+        unsafe { structure.unlock_shared() };
+        LockStats {
+            global_is_writer_waiting,
+            locked_keys,
+        }
+    }
+
+    /// The keys of the entries held by guards, shared or exclusive, e.g. to see which entries
+    /// are contended.
+    ///
+    /// Locks like [`SyncSlotMap::lock_stats`], so the keys may be stale by the time they are
+    /// returned.
+    pub fn busy_keys(&self) -> Vec<K> {
+        let locks = unsafe { &*self.locks };
+        let structure = locks.structure.any_shard();
+        structure.lock_shared();
+        let keys = unsafe { &*self.inner.get() }
+            .keys()
+            .filter(|&key| unsafe { locks.element(key) }.is_some_and(|lock| lock.is_locked()))
+            .collect();
+        unsafe { structure.unlock_shared() };
+        keys
+    }
+
+    /// Overwrites the value under `key`, returning the old one, or `None` without inserting
+    /// anything if `key` isn't in the map.
+    pub fn replace(&self, key: K, value: V) -> Option<V> {
+        let mut guard = self.get_mut(key)?;
+        Some(core::mem::replace(&mut *guard, value))
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_replace_for(&self, key: K, value: V, timeout: Duration) -> Option<Option<V>> {
+        let guard = self.try_get_mut_for(key, timeout)?;
+        Some(guard.map(|mut guard| core::mem::replace(&mut *guard, value)))
+    }
+
+    pub async fn replace_async(&self, key: K, value: V) -> Option<V> {
+        let mut guard = self.get_mut_async(key).await?;
+        Some(core::mem::replace(&mut *guard, value))
+    }
+
+    /// Adds `by` to the value under `key`, returning the new value, or `None` if `key` isn't
+    /// in the map. The entry is only held exclusively for the addition itself.
+    pub fn increment(&self, key: K, by: V) -> Option<V>
+    where
+        V: AddAssign + Copy,
+    {
+        let mut guard = self.get_mut(key)?;
+        *guard += by;
+        Some(*guard)
+    }
+
+    pub async fn increment_async(&self, key: K, by: V) -> Option<V>
+    where
+        V: AddAssign + Copy,
+    {
+        let mut guard = self.get_mut_async(key).await?;
+        *guard += by;
+        Some(*guard)
+    }
+
+    /// Replaces the value under `key` with `new` if it's equal to `expected`, all while holding
+    /// the entry exclusively so no other writer can get in between.
+    pub fn compare_and_set(&self, key: K, expected: &V, new: V) -> Result<(), CasError<V>>
+    where
+        V: PartialEq,
+    {
+        let Some(mut guard) = self.get_mut(key) else {
+            return Err(CasError::NotFound(new));
+        };
+        if *guard != *expected {
+            return Err(CasError::Mismatch(new));
+        }
+        *guard = new;
+        Ok(())
+    }
+
+    /// Swaps the values stored under `a` and `b`, returning `false` (and leaving both alone)
+    /// if either key is missing or they are the same key.
+    ///
+    /// Both element locks are taken exclusively, in address order so that concurrent swaps of
+    /// the same pair can't deadlock.
+    pub fn swap(&self, a: K, b: K) -> bool {
+        if a == b {
+            return false;
+        }
+        let Some(acquired) = (unsafe {
+            (*self.locks).acquire_all(&[a, b], Access::Exclusive, |key| self.lookup(key))
+        }) else {
+            return false;
+        };
+        let mut guards = [a, b]
+            .into_iter()
+            .zip(acquired)
+            .map(|(key, (locks, value))| SyncSlotGuardMut {
+                value,
+                locks,
+                key: key.data(),
+            });
+        let (mut a, mut b) = (guards.next().unwrap(), guards.next().unwrap());
+        core::mem::swap(&mut *a, &mut *b);
+        true
+    }
+
+    /// Read-locks every entry at once, giving a consistent view of the whole map, or returns
+    /// `None` if that couldn't be done within `timeout`.
+    ///
+    /// It's all or nothing: on timeout every element lock taken so far is released again.
+    /// Entries inserted while this is waiting aren't included.
+    #[cfg(feature = "std")]
+    pub fn try_lock_all_for(&self, timeout: Duration) -> Option<Vec<(K, SyncSlotGuard<V, L>)>> {
+        let deadline = Deadline::after(timeout);
+        loop {
+            let keys = self.keys_snapshot();
+            let acquired = unsafe {
+                (*self.locks)
+                    .try_acquire_all_until(&keys, Access::Shared, deadline, |key| self.lookup(key))
+            }?;
+            // Some key was removed in the meantime, start over with the current keys.
+            let Some(acquired) = acquired else {
+                continue;
+            };
+            return Some(
+                keys.into_iter()
+                    .zip(acquired)
+                    .map(|(key, (locks, value))| {
+                        (
+                            key,
+                            SyncSlotGuard {
+                                value,
+                                locks,
+                                key: key.data(),
+                            },
+                        )
+                    })
+                    .collect(),
+            );
+        }
+    }
+
+    /// Builds a secondary map holding `f` of every value under the same keys, e.g. to derive
+    /// an index of the entries. Locks like [`SyncSlotMap::for_each`], so inserts and removals
+    /// made after the scan runs into an entry held by a writer may be missed.
+    ///
+    /// Expired entries are left out, since they can't be gotten from this map anymore.
+    pub fn project_into_secondary<W, F: Fn(&V) -> W>(&self, f: F) -> SyncSecondarySlotMap<K, W, L> {
+        let secondary = SyncSecondarySlotMap::with_raw_lock();
+        self.for_each(|key, value| {
+            if !self.is_expired(key) {
+                secondary.insert(key, f(value));
+            }
+        });
+        secondary
+    }
+
+    /// Write-locks every key in `keys` at once, returning the guards in the same order, or
+    /// `None` if any key is missing or appears twice.
+    ///
+    /// The element locks are taken in address order, like [`SyncSlotMap::swap`], so
+    /// overlapping calls from different threads can't deadlock.
+    pub fn get_disjoint_mut_many(&self, keys: &[K]) -> Option<Vec<SyncSlotGuardMut<V, L>>> {
+        let mut sorted = keys.to_vec();
+        sorted.sort_unstable_by_key(|key| key.data().as_ffi());
+        if sorted.windows(2).any(|pair| pair[0] == pair[1]) {
+            return None;
+        }
+        let acquired =
+            unsafe { (*self.locks).acquire_all(keys, Access::Exclusive, |key| self.lookup(key)) }?;
+        Some(
+            keys.iter()
+                .zip(acquired)
+                .map(|(key, (locks, value))| SyncSlotGuardMut {
+                    value,
+                    locks,
+                    key: key.data(),
+                })
+                .collect(),
+        )
+    }
+
+    /// Write-locks every entry whose value satisfies `f`, returning the guards in slot order.
+    ///
+    /// The entries are first picked out with a scan that locks like [`SyncSlotMap::for_each`],
+    /// then write-locked all at once like [`SyncSlotMap::get_disjoint_mut_many`]. Other threads
+    /// can change entries in between, so `f` is checked again under the write locks and entries
+    /// that stopped matching are unlocked and left out. The result is thus every entry that
+    /// matched both during the scan and once locked; an entry that only started matching after
+    /// the scan saw it isn't included. If a picked entry was removed in the meantime the scan
+    /// starts over.
+    pub fn get_all_mut_where<F: Fn(&V) -> bool>(&self, f: F) -> Vec<(K, SyncSlotGuardMut<V, L>)> {
+        loop {
+            let mut keys = Vec::new();
+            self.for_each(|key, value| {
+                if f(value) {
+                    keys.push(key);
+                }
+            });
+            if let Some(guards) = self.get_disjoint_mut_many(&keys) {
+                return keys
+                    .into_iter()
+                    .zip(guards)
+                    .filter(|(_, guard)| f(guard))
+                    .collect();
+            }
+        }
+    }
+
+    /// Write-locks `a` and `b` at once, for callers to whom passing the same key twice is a bug.
+    ///
+    /// Each guard is `None` if its key is missing. The element locks are taken in address order
+    /// like [`SyncSlotMap::get_disjoint_mut_many`]. Passing equal keys panics in debug builds;
+    /// in release builds the second guard is `None` instead, since the entry is already locked
+    /// by the first.
+    pub fn get2_mut(&self, a: K, b: K) -> GuardMutPair<V, L> {
+        debug_assert!(a != b, "get2_mut called with the same key twice");
+        if a == b {
+            return (self.get_mut(a), None);
+        }
+        if let Some(mut guards) = self.get_disjoint_mut_many(&[a, b]) {
+            let b = guards.pop();
+            return (guards.pop(), b);
+        }
+        // One of the keys is gone, and keys never come back once removed, so locking them one
+        // at a time can't end up holding both.
+        let a = self.get_mut(a);
+        if a.is_some() {
+            return (a, None);
+        }
+        (None, self.get_mut(b))
+    }
+
+    /// Builds a new map holding `f` applied to every value, stored under the same keys.
+    ///
+    /// Every live key of `self` is valid in the returned map and refers to the transformed
+    /// value. Vacant slots are recreated as free slots, so keys handed out by later inserts may
+    /// differ between the two maps.
+    ///
+    /// `SlotMap` cannot insert at a chosen key, so each slot is recreated by removing and
+    /// reinserting its (boxed) value until it reaches the version of the source key: once for
+    /// every time the slot was reused in `self`. Besides the calls to `f`, this takes time
+    /// proportional to how many slots `self` has plus how many of its removals were followed by
+    /// an insert into the same slot, over its whole life rather than its current length. That's
+    /// cheap per removal, but a long-lived map that has churned through a billion entries costs
+    /// a billion rounds; if the keys don't have to carry over, collect the values with
+    /// [`SyncSlotMap::for_each`] into a new map instead.
+    ///
+    /// `SlotMap` doesn't say which version a vacant slot is at, so those slots start over at a
+    /// lower version than in `self`. A key that was already removed from `self` is not in the
+    /// returned map either, but may become valid again once inserts reuse its slot, referring to
+    /// whatever was inserted. Keys that were stale in `self` must therefore not be used with the
+    /// returned map.
+    pub fn map_values<W, F: FnMut(&V) -> W>(&self, mut f: F) -> SyncSlotMap<K, W, L> {
+        let locks = unsafe { &*self.locks };
+        let mut map = SyncSlotMap::with_raw_lock();
+        let mut mapped: Vec<(K, W)> = Vec::new();
+        self.for_each(|key, value| mapped.push((key, f(value))));
+        let structure = locks.structure.any_shard();
+        unsafe {
+            structure.lock_shared();
+            #[cfg(feature = "std")]
+            {
+                *map.key_timeouts.get_mut() = (*self.key_timeouts.get()).clone();
+            }
+            (*map.locks).set_elements(locks.elements().empty_like());
+            // Only the expiries of the entries that were mapped, the others were skipped.
+            #[cfg(feature = "std")]
+            {
+                let expiries = &*self.expiries.get();
+                let mapped_expiries = map.expiries.get_mut();
+                for (key, _) in &mapped {
+                    if let Some(&expiry) = expiries.get(*key) {
+                        mapped_expiries.insert(*key, expiry);
+                    }
+                }
+            }
+            structure.unlock_shared();
+        }
+
+        #[cfg(feature = "std")]
+        {
+            map.default_timeout = self.default_timeout;
+        }
+        unsafe { &*map.locks }.set_fair(locks.is_fair());
+        unsafe { &mut *map.locks }.set_backoff(locks.backoff().cloned());
+        unsafe { &*map.locks }.metrics.set(locks.metrics.get());
+        *map.len.get_mut() = mapped.len();
+        *map.peak.get_mut() = mapped.len();
+        *map.slots.get_mut() = self.slots.load(Ordering::Relaxed);
+        let inner = map.inner.get_mut();
+        let mut gaps = Vec::new();
+        let mut last = None;
+        for (key, value) in mapped {
+            let (idx, version) = (slot_index(key), (key.data().as_ffi() >> 32) as u32);
+            let mut value = Box::new(value);
+            // Slot 0 is slotmap's sentinel, so the next fresh slot is one past every slot made so far.
+            while inner.len() + gaps.len() + 1 < idx as usize {
+                let gap = inner.insert(value);
+                value = inner.detach(gap).unwrap();
+                gaps.push(gap);
+            }
+            let mut new_key = inner.insert(value);
+            for _ in 0..(version - 1) / 2 {
+                let value = inner.remove(new_key).unwrap();
+                new_key = inner.insert(value);
+            }
+            debug_assert!(new_key == key);
+            unsafe { (*map.locks).register(key) };
+            last = Some(key);
+        }
+        // Gaps were detached to keep them off the free list while later slots were created,
+        // borrow a live value to reattach and then free each of them.
+        if let Some(last) = last {
+            for gap in gaps {
+                let value = inner.detach(last).unwrap();
+                inner.reattach(gap, value);
+                let value = inner.remove(gap).unwrap();
+                inner.reattach(last, value);
+            }
+        }
+        map
+    }
+
+    /// Calls `f` on every entry in slot order.
+    ///
+    /// Each entry is read-locked while `f` looks at it, so entries held by a
+    /// [`SyncSlotGuardMut`] are waited for. Inserts and removals wait until the scan reaches
+    /// the first such entry; from then on the rest of the keys are gone through one at a time
+    /// like [`SyncSlotMap::get`], skipping those removed in the meantime, and entries inserted
+    /// after that aren't seen.
+    pub fn for_each<F: FnMut(K, &V)>(&self, mut f: F) {
+        self.visit(|key, value| {
+            f(key, value);
+            ControlFlow::<()>::Continue(())
+        });
+    }
+
+    /// Calls `f` on every entry in slot order, with mutable access.
+    ///
+    /// The whole map is locked exclusively for the duration (so this waits for every guard to
+    /// be dropped and blocks everything else), which is what lets it skip the element locks.
+    pub fn for_each_mut<F: FnMut(K, &mut V)>(&self, mut f: F) {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        let _held = unsafe { HeldExclusive::new(locks) };
+        for (key, value) in unsafe { self.live_entries_mut() } {
+            f(key, value);
+        }
+    }
+
+    /// Calls `f` on every entry in slot order, with mutable access, returning what it returned
+    /// for each key in the same order.
+    ///
+    /// Locks like [`SyncSlotMap::for_each_mut`].
+    pub fn apply_all<R, F: FnMut(K, &mut V) -> R>(&self, mut f: F) -> Vec<(K, R)> {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        let _held = unsafe { HeldExclusive::new(locks) };
+        unsafe { self.live_entries_mut() }
+            .map(|(key, value)| (key, f(key, value)))
+            .collect()
+    }
+
+    /// Removes every entry for which `f` returns `false`, letting it modify the ones it keeps.
+    ///
+    /// Locks like [`SyncSlotMap::for_each_mut`]. The removed values are dropped once the map is
+    /// unlocked again.
+    pub fn retain<F: FnMut(K, &mut V) -> bool>(&self, mut f: F) {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        let held = unsafe { HeldExclusive::new(locks) };
+        let rejected: Vec<K> = unsafe { self.live_entries_mut() }
+            .filter_map(|(key, value)| (!f(key, value)).then_some(key))
+            .collect();
+        held.keep();
+        unsafe { self.remove_many_locked(&rejected) };
+    }
+
+    /// Removes every entry for which `f` returns `true`, returning them in slot order.
+    ///
+    /// Locks like [`SyncSlotMap::for_each_mut`].
+    pub fn remove_where<F: FnMut(K, &V) -> bool>(&self, f: F) -> Vec<(K, V)> {
+        unsafe { &*self.locks }.lock_exclusive();
+        unsafe { self.remove_where_locked(f) }
+    }
+
+    /// Like [`SyncSlotMap::remove_where`], but gives up (returning `None`) if the map can't be
+    /// locked within `timeout`.
+    #[cfg(feature = "std")]
+    pub fn try_remove_where_for<F: FnMut(K, &V) -> bool>(
+        &self,
+        f: F,
+        timeout: Duration,
+    ) -> Option<Vec<(K, V)>> {
+        if !unsafe { &*self.locks }.try_lock_exclusive_for(timeout) {
+            return None;
+        }
+        Some(unsafe { self.remove_where_locked(f) })
+    }
+
+    // Unlocks the map, which must have been locked with `Locks::lock_exclusive`.
+    unsafe fn remove_where_locked<F: FnMut(K, &V) -> bool>(&self, mut f: F) -> Vec<(K, V)> {
+        let held = HeldExclusive::new(&*self.locks);
+        let matching: Vec<K> = self
+            .live_entries()
+            .filter_map(|(key, value)| f(key, value).then_some(key))
+            .collect();
+        held.keep();
+        let removed = self.remove_many_locked(&matching);
+        matching
+            .into_iter()
+            .zip(removed)
+            .map(|(key, value)| (key, value.unwrap()))
+            .collect()
+    }
+
+    /// Removes up to `max` entries, the first ones in slot order, returning them in that order.
+    ///
+    /// Locks like [`SyncSlotMap::remove_many`], but waits for the lock asynchronously; dropping
+    /// the future while it waits leaves the map untouched. Calling this repeatedly pulls the
+    /// entries out in batches of at most `max`.
+    pub async fn drain_async(&self, max: usize) -> Vec<(K, V)> {
+        unsafe { &*self.locks }.lock_exclusive_async().await;
+        let keys: Vec<K> = unsafe { self.live_entries() }
+            .map(|(key, _)| key)
+            .take(max)
+            .collect();
+        let removed = unsafe { self.remove_many_locked(&keys) };
+        keys.into_iter()
+            .zip(removed)
+            .map(|(key, value)| (key, value.unwrap()))
+            .collect()
+    }
+
+    /// Like [`SyncSlotMap::retain`], but goes through the map a chunk of entries at a time,
+    /// unlocking it and yielding to the async runtime in between so that other tasks (and
+    /// threads) get a turn.
+    ///
+    /// Because of that it doesn't see the map at a single point in time: the keys are taken
+    /// up front, so entries inserted while it runs are kept without being passed to `f`, and
+    /// entries removed while it runs are skipped.
+    pub async fn retain_async<F: FnMut(K, &mut V) -> bool>(&self, mut f: F) {
+        const CHUNK: usize = 64;
+        let locks = unsafe { &*self.locks };
+        for chunk in self.keys_snapshot().chunks(CHUNK) {
+            locks.lock_exclusive_async().await;
+            let held = unsafe { HeldExclusive::new(locks) };
+            let rejected: Vec<K> = chunk
+                .iter()
+                .copied()
+                .filter(|&key| match self.lookup(key) {
+                    Some(value) => !f(key, unsafe { &mut *value }),
+                    None => false,
+                })
+                .collect();
+            held.keep();
+            drop(unsafe { self.remove_many_locked(&rejected) });
+            YieldNow(false).await;
+        }
+    }
+
+    /// Counts the entries whose value satisfies `f`. Locks like [`SyncSlotMap::for_each`].
+    pub fn count<F: Fn(&V) -> bool>(&self, f: F) -> usize {
+        self.fold(0, |count, _, value| count + f(value) as usize)
+    }
+
+    /// Folds every entry in slot order into an accumulator. Locks like
+    /// [`SyncSlotMap::for_each`].
+    pub fn fold<B, F: FnMut(B, K, &V) -> B>(&self, init: B, mut f: F) -> B {
+        let mut acc = Some(init);
+        self.for_each(|key, value| acc = Some(f(acc.take().unwrap(), key, value)));
+        acc.unwrap()
+    }
+
+    /// Clones every entry out into a `Vec`, in slot order, leaving the map as it is. Locks
+    /// like [`SyncSlotMap::for_each`].
+    pub fn snapshot(&self) -> Vec<(K, V)>
+    where
+        V: Clone,
+    {
+        let mut entries = Vec::new();
+        self.for_each(|key, value| entries.push((key, value.clone())));
+        entries
+    }
+
+    /// Like [`SyncSlotMap::snapshot`], without the keys.
+    pub fn values_cloned(&self) -> Vec<V>
+    where
+        V: Clone,
+    {
+        let mut values = Vec::new();
+        self.for_each(|_, value| values.push(value.clone()));
+        values
+    }
+
+    /// Like [`SyncSlotMap::snapshot`], but sorted by `f` of each value, with entries `f` ties
+    /// on left in slot order.
+    ///
+    /// The entries are all cloned in the same pass over the map, and only sorted after it's
+    /// unlocked again.
+    pub fn sorted_snapshot_by_key<T: Ord, F: Fn(&V) -> T>(&self, f: F) -> Vec<(K, V)>
+    where
+        V: Clone,
+    {
+        let mut entries = self.snapshot();
+        entries.sort_by_key(|(_, value)| f(value));
+        entries
+    }
+
+    /// Whether any entry holds a value equal to `value`.
+    ///
+    /// This scans the whole map like [`SyncSlotMap::for_each`], read-locking each entry while
+    /// comparing it.
+    pub fn contains_value(&self, value: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        self.find_key(value).is_some()
+    }
+
+    /// Returns the key of the first entry (in slot order) holding a value equal to `value`.
+    ///
+    /// Locks like [`SyncSlotMap::contains_value`].
+    pub fn find_key(&self, value: &V) -> Option<K>
+    where
+        V: PartialEq,
+    {
+        self.visit(|key, candidate| {
+            if candidate == value {
+                ControlFlow::Break(key)
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+    }
+
+    /// Calls `f` on every entry in slot order until it returns `Some`, and returns that.
+    ///
+    /// Locks like [`SyncSlotMap::contains_value`], stopping as soon as `f` finds something.
+    pub fn find_map<R, F: FnMut(K, &V) -> Option<R>>(&self, mut f: F) -> Option<R> {
+        self.visit(|key, value| match f(key, value) {
+            Some(found) => ControlFlow::Break(found),
+            None => ControlFlow::Continue(()),
+        })
+    }
+
+    /// Finds the first entry (in slot order) for which `select` returns `true` and calls `modify`
+    /// on it with mutable access, returning what it returned, or `None` if no entry matches.
+    ///
+    /// The scan locks like [`SyncSlotMap::for_each`], and the chosen entry is then locked like
+    /// [`SyncSlotMap::get_mut`]. In between nothing is held, so another thread can change or
+    /// remove the entry first; to not act on a stale choice, `select` is called again once the
+    /// entry is locked exclusively, and if it no longer matches the scan starts over. `modify`
+    /// therefore only ever sees an entry `select` accepts, which makes something like
+    /// `map.scan_then_modify(|_, slot| slot.is_free(), |slot| slot.claim())` safe to run from
+    /// several threads at once without two of them claiming the same slot.
+    pub fn scan_then_modify<R, F: Fn(K, &V) -> bool, G: FnOnce(&mut V) -> R>(
+        &self,
+        select: F,
+        modify: G,
+    ) -> Option<R> {
+        loop {
+            let key = self.visit(|key, value| {
+                if select(key, value) {
+                    ControlFlow::Break(key)
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })?;
+            if let Some(mut guard) = self.get_mut(key) {
+                if select(key, &guard) {
+                    return Some(modify(&mut guard));
+                }
+            }
+        }
+    }
+
+    // Calls `f` on every entry in slot order until it breaks, read-locking each entry while
+    // `f` looks at it, see `Locks::scan`.
+    fn visit<B>(&self, f: impl FnMut(K, &V) -> ControlFlow<B>) -> Option<B> {
+        unsafe { (*self.locks).scan(|| self.live_entries(), |key| self.lookup(key), f) }
+    }
+
+    /// Returns an iterator of read guards over every entry, also used by `for ... in &map`.
+    ///
+    /// Like `stream`, the keys are collected up front and each one is then
+    /// locked as the iterator reaches it, skipping any that were removed in the meantime.
+    ///
+    /// ```
+    /// # use sync_slotmap::SyncSlotMap;
+    /// # use slotmap::DefaultKey;
+    /// let map = SyncSlotMap::<DefaultKey, u32>::new();
+    /// map.insert(1);
+    /// map.insert(2);
+    /// let mut sum = 0;
+    /// for (_key, value) in &map {
+    ///     sum += *value;
+    /// }
+    /// assert_eq!(sum, 3);
+    /// ```
+    pub fn iter(&self) -> SyncSlotIter<'_, K, V, L> {
+        SyncSlotIter {
+            map: self,
+            keys: self.keys_snapshot().into_iter(),
+        }
+    }
 
-unsafe impl<K: Key, V> Send for SyncSlotMap<K, V> where V: Send {}
-unsafe impl<K: Key, V> Sync for SyncSlotMap<K, V> where V: Send + Sync {}
+    /// Like [`SyncSlotMap::iter`], but only yields the entries whose value `f` accepts.
+    ///
+    /// Each entry is read-locked to be checked, and unlocked again straight away if it's
+    /// skipped, so only the guards that are yielded keep their entries locked.
+    pub fn iter_filter<'a, F: Fn(&V) -> bool + 'a>(
+        &'a self,
+        f: F,
+    ) -> impl Iterator<Item = (K, SyncSlotGuard<V, L>)> + 'a {
+        self.iter().filter(move |(_, guard)| f(guard))
+    }
 
-impl<K: Key, V> Drop for SyncSlotMap<K, V> {
-    fn drop(&mut self) {
-        if !unsafe { &*self.locks }
-            .0
-            .try_lock_exclusive_for(std::time::Duration::from_secs(1))
-        {
-            panic!("Dropped SyncSlotMap which was in use.");
+    /// Returns a stream of read guards over every entry, for use with `futures` combinators.
+    ///
+    /// The keys are collected up front; each one is then locked with [`Self::get_async`] as
+    /// the stream is polled, skipping any that were removed in the meantime. Each guard is
+    /// independent, so dropping it before polling for the next releases it.
+    #[cfg(feature = "stream")]
+    pub fn stream(&self) -> impl futures::Stream<Item = SyncSlotGuard<V, L>> + '_ {
+        use futures::StreamExt;
+
+        futures::stream::iter(self.keys_snapshot()).filter_map(move |key| self.get_async(key))
+    }
+
+    fn keys_snapshot(&self) -> Vec<K> {
+        let structure = unsafe { &*self.locks }.structure.any_shard();
+        structure.lock_shared();
+        let keys = unsafe { &*self.inner.get() }
+            .keys()
+            .filter(|&key| !self.is_expired(key))
+            .collect();
+        unsafe { structure.unlock_shared() };
+        keys
+    }
+
+    /// Calls `f` on every entry, spread across the rayon thread pool.
+    ///
+    /// The whole map is locked exclusively for the duration (so this waits for every guard to
+    /// be dropped and blocks everything else), which is what lets it skip the element locks.
+    #[cfg(feature = "rayon")]
+    pub fn par_for_each_mut<F: Fn(K, &mut V) + Sync>(&self, f: F)
+    where
+        K: Send,
+        V: Send,
+    {
+        use rayon::prelude::*;
+
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        let _held = unsafe { HeldExclusive::new(locks) };
+        let entries: Vec<(K, &mut V)> = unsafe { self.live_entries_mut() }.collect();
+        entries
+            .into_par_iter()
+            .for_each(|(key, value)| f(key, value));
+    }
+
+    /// Calls `f` on every entry, spread across the rayon thread pool.
+    ///
+    /// Each entry is read-locked while `f` looks at it, so entries held by a `SyncSlotGuardMut`
+    /// are waited for. Inserts and removals wait until the entries that weren't busy are done,
+    /// the busy ones are then read one at a time on the calling thread like
+    /// [`SyncSlotMap::for_each`] does once it runs into one.
+    #[cfg(feature = "rayon")]
+    pub fn par_for_each<F: Fn(K, &V) + Sync>(&self, f: F)
+    where
+        K: Send,
+        V: Sync,
+    {
+        use rayon::prelude::*;
+
+        let locks = unsafe { &*self.locks };
+        let (global, structure) = (locks.global.any_shard(), locks.structure.any_shard());
+        global.lock_shared();
+        let _global = unsafe { Held::new(global, Access::Shared) };
+        structure.lock_shared();
+        let held_structure = unsafe { Held::new(structure, Access::Shared) };
+        let entries: Vec<(K, &L, &V)> = unsafe { self.live_entries() }
+            .map(|(key, value)| (key, unsafe { locks.element(key) }.unwrap(), value))
+            .collect();
+        // Busy entries can't be waited for with `structure` held, see `Locks::scan`.
+        let busy: Vec<K> = entries
+            .into_par_iter()
+            .filter_map(|(key, lock, value)| {
+                if !lock.try_lock_shared() {
+                    return Some(key);
+                }
+                let _held = unsafe { Held::new(lock, Access::Shared) };
+                f(key, value);
+                None
+            })
+            .collect();
+        drop(held_structure);
+        for key in busy {
+            unsafe { locks.read_held(key, || self.lookup(key), |value| f(key, value)) };
         }
-        unsafe { drop(Box::from_raw(self.locks)) };
     }
 }
 
-impl<K: Key, V> Default for SyncSlotMap<K, V> {
-    fn default() -> Self {
-        Self::new()
+/// Runs `f` with `a` and `b` both locked exclusively, for updating entries of both as one
+/// step, e.g. an entity and its metadata.
+///
+/// Each map is locked like [`SyncSlotMap::for_each_mut`], so this waits for every guard on
+/// either map to be dropped and blocks everything else on them while `f` runs. The maps are
+/// locked in address order, so threads running this on the same two maps can't deadlock each
+/// other. `f` must not use the maps other than through the handles it is given, which would
+/// deadlock.
+pub fn with_locked2<K: Key, A, B, L: RawLock, R>(
+    a: &SyncSlotMap<K, A, L>,
+    b: &SyncSecondarySlotMap<K, B, L>,
+    f: impl FnOnce(&mut LockedSlotMap<K, A, L>, &mut LockedSecondaryMap<K, B, L>) -> R,
+) -> R {
+    let (first, second) = unsafe {
+        if a.locks < b.locks {
+            (&*a.locks, &*b.locks)
+        } else {
+            (&*b.locks, &*a.locks)
+        }
+    };
+    let order = Ordered::begin();
+    order.taking(first);
+    order.taking(second);
+    first.lock_exclusive();
+    let held_first = unsafe { HeldExclusive::new(first) };
+    second.lock_exclusive();
+    let held_second = unsafe { HeldExclusive::new(second) };
+    let mut locked = LockedSlotMap {
+        map: a,
+        changes: Vec::new(),
+    };
+    let result = f(&mut locked, &mut LockedSecondaryMap { map: b });
+    drop(held_second);
+    drop(held_first);
+    for event in locked.changes {
+        a.notify(event);
     }
+    result
 }
 
-impl<K: Key, V> SyncSlotMap<K, V> {
-    pub fn new() -> Self {
-        let locks = Box::new((RawRwLock::INIT, SecondaryMap::new()));
-        let locks = Box::into_raw(locks);
-        Self {
-            inner: UnsafeCell::new(SlotMap::with_key()),
-            locks,
-        }
+/// Write-locks the entries under `key` in both `primary` and `secondary`, e.g. an entity and
+/// one of its components, or returns `None` if either map doesn't have it.
+///
+/// Each entry is locked like [`SyncSlotMap::get_mut`], one map after the other in address
+/// order of their locks (like [`with_locked2`]), so threads calling this on the same two maps
+/// can't deadlock each other, whichever way round they pass them.
+///
+/// ```
+/// use slotmap::DefaultKey;
+/// use sync_slotmap::{get_entity_and_meta_mut, SyncSecondarySlotMap, SyncSlotMap};
+///
+/// let positions = SyncSlotMap::<DefaultKey, (f32, f32)>::new();
+/// let velocities = SyncSecondarySlotMap::<DefaultKey, (f32, f32)>::new();
+/// let entity = positions.insert((0.0, 0.0));
+/// assert!(get_entity_and_meta_mut(&positions, &velocities, entity).is_none());
+///
+/// velocities.insert(entity, (1.0, 2.0));
+/// let (mut position, velocity) =
+///     get_entity_and_meta_mut(&positions, &velocities, entity).unwrap();
+/// position.0 += velocity.0;
+/// position.1 += velocity.1;
+/// assert_eq!(*position, (1.0, 2.0));
+/// ```
+pub fn get_entity_and_meta_mut<'a, K: Key, A, B, L: RawLock>(
+    primary: &'a SyncSlotMap<K, A, L>,
+    secondary: &'a SyncSecondarySlotMap<K, B, L>,
+    key: K,
+) -> Option<(SyncSlotGuardMut<A, L>, SyncSlotGuardMut<B, L>)> {
+    let order = Ordered::begin();
+    if primary.locks < secondary.locks {
+        order.taking(primary.locks);
+        order.taking(secondary.locks);
+        let entity = primary.get_mut(key)?;
+        Some((entity, secondary.get_mut(key)?))
+    } else {
+        order.taking(secondary.locks);
+        order.taking(primary.locks);
+        let meta = secondary.get_mut(key)?;
+        Some((primary.get_mut(key)?, meta))
     }
+}
 
-    pub fn insert(&self, value: V) -> K {
-        unsafe { &*self.locks }.0.lock_exclusive();
-        let key = unsafe { &mut *self.inner.get() }.insert(value);
-        unsafe { &mut *self.locks }.1.insert(key, RawRwLock::INIT);
-        unsafe {
-            (*self.locks).0.unlock_exclusive();
-        }
-        key
+/// A [`SyncSlotMap`] locked exclusively by [`with_locked2`], so its entries can be used without
+/// locking each of them.
+///
+/// The [`SyncSlotMap::on_change`] observer hears about entries inserted or removed through it
+/// once [`with_locked2`] has unlocked both maps again.
+pub struct LockedSlotMap<'a, K: Key, V, L: RawLock = DefaultRawLock> {
+    // The whole map is locked exclusively for as long as this lives.
+    map: &'a SyncSlotMap<K, V, L>,
+    changes: Vec<ChangeEvent<K>>,
+}
+
+impl<K: Key, V, L: RawLock> LockedSlotMap<'_, K, V, L> {
+    pub fn get(&self, key: K) -> Option<&V> {
+        self.map.lookup(key).map(|value| unsafe { &*value })
     }
 
-    pub fn try_insert_for(&self, value: V, timeout: std::time::Duration) -> Option<K> {
-        if !unsafe { &*self.locks }.0.try_lock_exclusive_for(timeout) {
-            return None;
-        }
-        let key = unsafe { &mut *self.inner.get() }.insert(value);
-        unsafe { &mut *self.locks }.1.insert(key, RawRwLock::INIT);
-        unsafe {
-            (*self.locks).0.unlock_exclusive();
-        }
-        Some(key)
+    pub fn get_mut(&mut self, key: K) -> Option<&mut V> {
+        self.map.lookup(key).map(|value| unsafe { &mut *value })
     }
 
-    pub async fn insert_async(&self, value: V) -> K {
-        UnlockRwLockExclusive {
-            lock: &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-        }
-        .await;
-        let key = unsafe { &mut *self.inner.get() }.insert(value);
-        unsafe { &mut *self.locks }.1.insert(key, RawRwLock::INIT);
-        unsafe {
-            (*self.locks).0.unlock_exclusive();
-        }
+    pub fn contains_key(&self, key: K) -> bool {
+        self.map.lookup(key).is_some()
+    }
+
+    /// See [`SyncSlotMap::insert`].
+    pub fn insert(&mut self, value: V) -> K {
+        let key = unsafe { self.map.insert_locked(value) };
+        self.changes.push(ChangeEvent::Inserted(key));
         key
     }
 
-    pub fn remove(&self, key: K) -> Option<V> {
-        unsafe { &*self.locks }.0.lock_exclusive();
-        let result = unsafe { &mut *self.inner.get() }.remove(key);
-        unsafe { &mut *self.locks }.1.remove(key);
-        unsafe {
-            (*self.locks).0.unlock_exclusive();
-        }
-        result
+    /// See [`SyncSlotMap::remove`].
+    pub fn remove(&mut self, key: K) -> Option<V> {
+        let (value, lock) = unsafe { self.map.remove_locked(key) }?;
+        // No guards exist while `global` is held exclusively, so retiring is free.
+        unsafe { (*self.map.locks).retire(lock) };
+        self.changes.push(ChangeEvent::Removed(key));
+        Some(*value)
     }
+}
 
-    pub fn try_remove_for(&self, key: K, timeout: std::time::Duration) -> Option<Option<V>> {
-        if !unsafe { &*self.locks }.0.try_lock_exclusive_for(timeout) {
-            return None;
-        }
-        let result = unsafe { &mut *self.inner.get() }.remove(key);
-        unsafe { &mut *self.locks }.1.remove(key);
-        unsafe {
-            (*self.locks).0.unlock_exclusive();
-        }
-        Some(result)
+/// An iterator of read guards over a [`SyncSlotMap`], from [`SyncSlotMap::iter`].
+pub struct SyncSlotIter<'a, K: Key, V, L: RawLock = DefaultRawLock> {
+    map: &'a SyncSlotMap<K, V, L>,
+    keys: alloc::vec::IntoIter<K>,
+}
+
+impl<K: Key, V, L: RawLock> Iterator for SyncSlotIter<'_, K, V, L> {
+    type Item = (K, SyncSlotGuard<V, L>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.keys.find_map(|key| self.map.get_key_value(key))
     }
 
-    pub async fn remove_async(&self, key: K) -> Option<V> {
-        UnlockRwLockExclusive {
-            lock: &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-        }
-        .await;
-        let result = unsafe { &mut *self.inner.get() }.remove(key);
-        unsafe { &mut *self.locks }.1.remove(key);
-        unsafe {
-            (*self.locks).0.unlock_exclusive();
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.keys.size_hint().1)
+    }
+}
+
+impl<'a, K: Key, V, L: RawLock> IntoIterator for &'a SyncSlotMap<K, V, L> {
+    type Item = (K, SyncSlotGuard<V, L>);
+    type IntoIter = SyncSlotIter<'a, K, V, L>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// A handle to a [`SyncSlotMap`] that doesn't keep it alive, from [`SyncSlotMap::downgrade`].
+pub struct WeakSyncSlotMap<K: Key, V, L: RawLock = DefaultRawLock> {
+    map: Weak<SyncSlotMap<K, V, L>>,
+}
+
+impl<K: Key, V, L: RawLock> Clone for WeakSyncSlotMap<K, V, L> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
         }
-        result
     }
+}
 
-    pub fn get(&self, key: K) -> Option<SyncSlotGuard<V>> {
-        let (lock, value) = unsafe {
-            (*self.locks).0.lock_shared();
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return None; };
-            lock.lock_shared();
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_shared();
-                return None;
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(SyncSlotGuard { value, locks })
-    }
-
-    pub fn get_mut(&self, key: K) -> Option<SyncSlotGuardMut<V>> {
-        let (lock, value) = unsafe {
-            (*self.locks).0.lock_shared();
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return None; };
-            lock.lock_exclusive();
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_exclusive();
-                return None;
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(SyncSlotGuardMut { value, locks })
+impl<K: Key, V, L: RawLock> WeakSyncSlotMap<K, V, L> {
+    /// The map, unless every `Arc` to it has been dropped.
+    pub fn upgrade(&self) -> Option<Arc<SyncSlotMap<K, V, L>>> {
+        self.map.upgrade()
     }
+}
 
-    pub fn try_get_for(
-        &self,
-        key: K,
-        timeout: std::time::Duration,
-    ) -> Option<Option<SyncSlotGuard<V>>> {
-        let (lock, value) = unsafe {
-            if !(*self.locks).0.try_lock_shared_for(timeout) {
-                return None;
-            }
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return Some(None); };
-            if !lock.try_lock_shared_for(timeout) {
-                (*self.locks).0.unlock_shared();
-                return None;
-            }
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_shared();
-                return Some(None);
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(Some(SyncSlotGuard { value, locks }))
+/// A [`SyncSlotMap`] that only lets its entries be read and modified, from
+/// [`SyncSlotMap::view`].
+pub struct ReadWriteView<'a, K: Key, V, L: RawLock = DefaultRawLock> {
+    map: &'a SyncSlotMap<K, V, L>,
+}
+
+impl<K: Key, V, L: RawLock> Clone for ReadWriteView<'_, K, V, L> {
+    fn clone(&self) -> Self {
+        *self
     }
+}
 
-    pub fn try_get_mut_for(
-        &self,
-        key: K,
-        timeout: std::time::Duration,
-    ) -> Option<Option<SyncSlotGuardMut<V>>> {
-        let (lock, value) = unsafe {
-            if !(*self.locks).0.try_lock_shared_for(timeout) {
-                return None;
-            }
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return Some(None); };
-            if !lock.try_lock_exclusive_for(timeout) {
-                (*self.locks).0.unlock_shared();
-                return None;
-            }
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_exclusive();
-                return Some(None);
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(Some(SyncSlotGuardMut { value, locks }))
-    }
-
-    pub async fn get_async(&self, key: K) -> Option<SyncSlotGuard<V>> {
-        let (lock, value) = unsafe {
-            UnlockRwLockShared {
-                lock: &(*self.locks).0 as *const RawRwLock as *mut RawRwLock,
-            }
-            .await;
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return None; };
-            UnlockRwLockShared {
-                lock: lock as *const RawRwLock as *mut RawRwLock,
-            }
-            .await;
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_shared();
-                return None;
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(SyncSlotGuard { value, locks })
-    }
-    pub async fn get_mut_async(&self, key: K) -> Option<SyncSlotGuardMut<V>> {
-        let (lock, value) = unsafe {
-            UnlockRwLockShared {
-                lock: &(*self.locks).0 as *const RawRwLock as *mut RawRwLock,
-            }
-            .await;
-            let Some(lock) = (*self.locks).1.get(key) else { (*self.locks).0.unlock_shared(); return None; };
-            UnlockRwLockExclusive {
-                lock: lock as *const RawRwLock as *mut RawRwLock,
-            }
-            .await;
-            let Some(value) = (*self.inner.get()).get(key) else {
-                (*self.locks).0.unlock_shared();
-                lock.unlock_exclusive();
-                return None;
-            };
-            (lock, value)
-        };
-        let locks = (
-            &unsafe { &*self.locks }.0 as *const RawRwLock as *mut RawRwLock,
-            lock as *const RawRwLock as *mut RawRwLock,
-        );
-        let value = value as *const V as *mut V;
-        Some(SyncSlotGuardMut { value, locks })
+impl<K: Key, V, L: RawLock> Copy for ReadWriteView<'_, K, V, L> {}
+
+impl<K: Key, V, L: RawLock> ReadWriteView<'_, K, V, L> {
+    /// See [`SyncSlotMap::get`].
+    pub fn get(&self, key: K) -> Option<SyncSlotGuard<V, L>> {
+        self.map.get(key)
+    }
+
+    /// See [`SyncSlotMap::get_mut`].
+    pub fn get_mut(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        self.map.get_mut(key)
+    }
+
+    /// See [`SyncSlotMap::contains_key`].
+    pub fn contains_key(&self, key: K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// See [`SyncSlotMap::approx_len`].
+    pub fn approx_len(&self) -> usize {
+        self.map.approx_len()
     }
 }
 
-pub struct SyncSlotGuard<V> {
+pub struct SyncSlotGuard<V, L: RawLock = DefaultRawLock> {
     pub(crate) value: *mut V,
-    pub(crate) locks: (*mut RawRwLock, *mut RawRwLock),
+    pub(crate) locks: GuardLocks<L>,
+    // Untyped so guards don't need a key type parameter, see `key`.
+    pub(crate) key: KeyData,
 }
 
-impl<V: Debug> Debug for SyncSlotGuard<V> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<V: Debug, L: RawLock> Debug for SyncSlotGuard<V, L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("SyncSlotGuard")
             .field("value", unsafe { &*self.value })
             .finish()
     }
 }
 
-impl<V> Drop for SyncSlotGuard<V> {
+impl<V, L: RawLock> Drop for SyncSlotGuard<V, L> {
     fn drop(&mut self) {
-        unsafe {
-            (*self.locks.1).unlock_shared();
-            (*self.locks.0).unlock_shared()
-        }
+        unsafe { self.locks.unlock(Access::Shared) }
     }
 }
 
-impl<V: Display> Display for SyncSlotGuard<V> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<V: Display, L: RawLock> Display for SyncSlotGuard<V, L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         unsafe { &(*self.value) }.fmt(f)
     }
 }
 
-impl<V> Clone for SyncSlotGuard<V> {
+// Guards compare by the values they point at, which stay locked for as long as the guards live.
+impl<V: PartialEq, L: RawLock> PartialEq for SyncSlotGuard<V, L> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<V: Eq, L: RawLock> Eq for SyncSlotGuard<V, L> {}
+
+impl<V: PartialOrd, L: RawLock> PartialOrd for SyncSlotGuard<V, L> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<V: Ord, L: RawLock> Ord for SyncSlotGuard<V, L> {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<V: Hash, L: RawLock> Hash for SyncSlotGuard<V, L> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<V, L: RawLock> Clone for SyncSlotGuard<V, L> {
     fn clone(&self) -> Self {
+        // This guard already holds both locks, so a writer waiting for either mustn't keep the
+        // clone waiting too, which would deadlock.
         unsafe {
-            (*self.locks.0).lock_shared();
-            (*self.locks.1).lock_shared();
+            (*self.locks.global).lock_shared_recursive();
+            (*self.locks.element).lock_shared_recursive();
         }
         Self {
-            value: self.value.clone(),
-            locks: self.locks.clone(),
+            value: self.value,
+            locks: self.locks,
+            key: self.key,
         }
     }
 }
 
-unsafe impl<V> Sync for SyncSlotGuard<V> {}
-unsafe impl<V: Send> Send for SyncSlotGuard<V> {}
+unsafe impl<V, L: RawLock> Sync for SyncSlotGuard<V, L> {}
+unsafe impl<V: Send, L: RawLock> Send for SyncSlotGuard<V, L> {}
 
-impl<V> SyncSlotGuard<V> {
+impl<V, L: RawLock> SyncSlotGuard<V, L> {
     pub fn get(&self) -> &V {
         unsafe { &*self.value }
     }
+
+    /// The key the guarded entry was gotten with. Guards don't know the map's key type, so it
+    /// has to be named, e.g. `let key: MyKey = guard.key();`.
+    pub fn key<K: Key>(&self) -> K {
+        self.key.into()
+    }
+
+    /// Unlocks the entry now rather than at the end of the scope.
+    pub fn release(self) {}
+
+    /// Narrows the guard down to a part of the value, keeping the entry locked until the
+    /// returned guard is dropped.
+    ///
+    /// The part can be unsized, such as the trait object in a boxed value:
+    ///
+    /// ```
+    /// use slotmap::DefaultKey;
+    /// use sync_slotmap::SyncSlotMap;
+    ///
+    /// trait Shape: Send + Sync {
+    ///     fn area(&self) -> f64;
+    /// }
+    ///
+    /// struct Square(f64);
+    ///
+    /// impl Shape for Square {
+    ///     fn area(&self) -> f64 {
+    ///         self.0 * self.0
+    ///     }
+    /// }
+    ///
+    /// let shapes = SyncSlotMap::<DefaultKey, Box<dyn Shape>>::new();
+    /// let key = shapes.insert(Box::new(Square(2.0)));
+    /// // Method calls go through the box either way.
+    /// assert_eq!(shapes.get(key).unwrap().area(), 4.0);
+    /// let shape = shapes.get(key).unwrap().map(|shape| &**shape);
+    /// assert_eq!(shape.area(), 4.0);
+    /// ```
+    pub fn map<U: ?Sized, F: FnOnce(&V) -> &U>(self, f: F) -> MappedSyncSlotGuard<U, L> {
+        let this = core::mem::ManuallyDrop::new(self);
+        MappedSyncSlotGuard {
+            value: f(unsafe { &*this.value }),
+            locks: this.locks,
+        }
+    }
 }
 
-impl<V> Deref for SyncSlotGuard<V> {
+impl<V, L: RawLock> Deref for SyncSlotGuard<V, L> {
     type Target = V;
 
     fn deref(&self) -> &Self::Target {
@@ -323,48 +2500,123 @@ impl<V> Deref for SyncSlotGuard<V> {
     }
 }
 
-pub struct SyncSlotGuardMut<V> {
+impl<V, L: RawLock> AsRef<V> for SyncSlotGuard<V, L> {
+    fn as_ref(&self) -> &V {
+        self.get()
+    }
+}
+
+impl<V, L: RawLock> Borrow<V> for SyncSlotGuard<V, L> {
+    fn borrow(&self) -> &V {
+        self.get()
+    }
+}
+
+pub struct SyncSlotGuardMut<V, L: RawLock = DefaultRawLock> {
     pub(crate) value: *mut V,
-    pub(crate) locks: (*mut RawRwLock, *mut RawRwLock),
+    pub(crate) locks: GuardLocks<L>,
+    // Untyped so guards don't need a key type parameter, see `key`.
+    pub(crate) key: KeyData,
 }
 
-unsafe impl<V: Sync> Sync for SyncSlotGuardMut<V> {}
-unsafe impl<V: Send> Send for SyncSlotGuardMut<V> {}
+unsafe impl<V: Sync, L: RawLock> Sync for SyncSlotGuardMut<V, L> {}
+unsafe impl<V: Send, L: RawLock> Send for SyncSlotGuardMut<V, L> {}
 
-impl<V: Debug> Debug for SyncSlotGuardMut<V> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<V: Debug, L: RawLock> Debug for SyncSlotGuardMut<V, L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("SyncSlotGuardMut")
             .field("value", unsafe { &*self.value })
             .finish()
     }
 }
 
-impl<V: Display> Display for SyncSlotGuardMut<V> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<V: Display, L: RawLock> Display for SyncSlotGuardMut<V, L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         unsafe { &(*self.value) }.fmt(f)
     }
 }
 
-impl<V> Drop for SyncSlotGuardMut<V> {
+impl<V: PartialEq, L: RawLock> PartialEq for SyncSlotGuardMut<V, L> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<V: Eq, L: RawLock> Eq for SyncSlotGuardMut<V, L> {}
+
+impl<V: PartialOrd, L: RawLock> PartialOrd for SyncSlotGuardMut<V, L> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<V: Ord, L: RawLock> Ord for SyncSlotGuardMut<V, L> {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<V: Hash, L: RawLock> Hash for SyncSlotGuardMut<V, L> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<V, L: RawLock> Drop for SyncSlotGuardMut<V, L> {
     fn drop(&mut self) {
-        unsafe {
-            (*self.locks.1).unlock_exclusive();
-            (*self.locks.0).unlock_shared()
-        }
+        unsafe { self.locks.unlock(Access::Exclusive) }
     }
 }
 
-impl<V> SyncSlotGuardMut<V> {
+impl<V, L: RawLock> SyncSlotGuardMut<V, L> {
     pub fn get(&self) -> &V {
         unsafe { &*self.value }
     }
 
+    /// The key the guarded entry was gotten with. Guards don't know the map's key type, so it
+    /// has to be named, e.g. `let key: MyKey = guard.key();`.
+    pub fn key<K: Key>(&self) -> K {
+        self.key.into()
+    }
+
     pub fn get_mut(&mut self) -> &mut V {
         unsafe { &mut *self.value }
     }
+
+    /// Unlocks the entry now rather than at the end of the scope.
+    pub fn release(self) {}
+
+    /// Narrows the guard down to a part of the value, keeping the entry locked until the
+    /// returned guard is dropped.
+    pub fn map<U: ?Sized, F: FnOnce(&mut V) -> &mut U>(self, f: F) -> MappedSyncSlotGuardMut<U, L> {
+        let this = core::mem::ManuallyDrop::new(self);
+        let value = this.value;
+        MappedSyncSlotGuardMut {
+            value: f(unsafe { &mut *value }),
+            locks: this.locks,
+        }
+    }
+
+    /// Forgets the guard without unlocking the entry, leaving it locked until
+    /// [`SyncSlotMap::unlock_exclusive_raw`] is called for its key.
+    pub fn leak(self) {
+        core::mem::forget(self);
+    }
+
+    /// Gives up write access while keeping the entry read-locked, without letting any other
+    /// writer in between.
+    pub fn release_and_get(self) -> SyncSlotGuard<V, L> {
+        let this = core::mem::ManuallyDrop::new(self);
+        unsafe { (*this.locks.element).downgrade() };
+        SyncSlotGuard {
+            value: this.value,
+            locks: this.locks,
+            key: this.key,
+        }
+    }
 }
 
-impl<V> Deref for SyncSlotGuardMut<V> {
+impl<V, L: RawLock> Deref for SyncSlotGuardMut<V, L> {
     type Target = V;
 
     fn deref(&self) -> &Self::Target {
@@ -372,24 +2624,167 @@ impl<V> Deref for SyncSlotGuardMut<V> {
     }
 }
 
-impl<V> DerefMut for SyncSlotGuardMut<V> {
+impl<V, L: RawLock> DerefMut for SyncSlotGuardMut<V, L> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.get_mut()
     }
 }
 
-pub(crate) struct UnlockRwLockShared {
-    pub(crate) lock: *mut RawRwLock,
+impl<V, L: RawLock> AsRef<V> for SyncSlotGuardMut<V, L> {
+    fn as_ref(&self) -> &V {
+        self.get()
+    }
+}
+
+impl<V, L: RawLock> AsMut<V> for SyncSlotGuardMut<V, L> {
+    fn as_mut(&mut self) -> &mut V {
+        self.get_mut()
+    }
+}
+
+impl<V, L: RawLock> Borrow<V> for SyncSlotGuardMut<V, L> {
+    fn borrow(&self) -> &V {
+        self.get()
+    }
+}
+
+impl<V, L: RawLock> BorrowMut<V> for SyncSlotGuardMut<V, L> {
+    fn borrow_mut(&mut self) -> &mut V {
+        self.get_mut()
+    }
+}
+
+/// A [`SyncSlotGuard`] narrowed down to part of the value with [`SyncSlotGuard::map`].
+pub struct MappedSyncSlotGuard<U: ?Sized, L: RawLock = DefaultRawLock> {
+    value: *const U,
+    locks: GuardLocks<L>,
+}
+
+unsafe impl<U: ?Sized + Sync, L: RawLock> Sync for MappedSyncSlotGuard<U, L> {}
+unsafe impl<U: ?Sized + Sync, L: RawLock> Send for MappedSyncSlotGuard<U, L> {}
+
+impl<U: ?Sized + Debug, L: RawLock> Debug for MappedSyncSlotGuard<U, L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MappedSyncSlotGuard")
+            .field("value", &unsafe { &*self.value })
+            .finish()
+    }
+}
+
+impl<U: ?Sized, L: RawLock> Drop for MappedSyncSlotGuard<U, L> {
+    fn drop(&mut self) {
+        unsafe { self.locks.unlock(Access::Shared) }
+    }
+}
+
+impl<U: ?Sized, L: RawLock> MappedSyncSlotGuard<U, L> {
+    pub fn map<T: ?Sized, F: FnOnce(&U) -> &T>(self, f: F) -> MappedSyncSlotGuard<T, L> {
+        let this = core::mem::ManuallyDrop::new(self);
+        MappedSyncSlotGuard {
+            value: f(unsafe { &*this.value }),
+            locks: this.locks,
+        }
+    }
+}
+
+impl<U: ?Sized, L: RawLock> Deref for MappedSyncSlotGuard<U, L> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.value }
+    }
+}
+
+/// A [`SyncSlotGuardMut`] narrowed down to part of the value with [`SyncSlotGuardMut::map`].
+pub struct MappedSyncSlotGuardMut<U: ?Sized, L: RawLock = DefaultRawLock> {
+    value: *mut U,
+    locks: GuardLocks<L>,
+}
+
+unsafe impl<U: ?Sized + Sync, L: RawLock> Sync for MappedSyncSlotGuardMut<U, L> {}
+unsafe impl<U: ?Sized + Send, L: RawLock> Send for MappedSyncSlotGuardMut<U, L> {}
+
+impl<U: ?Sized + Debug, L: RawLock> Debug for MappedSyncSlotGuardMut<U, L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MappedSyncSlotGuardMut")
+            .field("value", &unsafe { &*self.value })
+            .finish()
+    }
+}
+
+impl<U: ?Sized, L: RawLock> Drop for MappedSyncSlotGuardMut<U, L> {
+    fn drop(&mut self) {
+        unsafe { self.locks.unlock(Access::Exclusive) }
+    }
+}
+
+impl<U: ?Sized, L: RawLock> MappedSyncSlotGuardMut<U, L> {
+    pub fn map<T: ?Sized, F: FnOnce(&mut U) -> &mut T>(self, f: F) -> MappedSyncSlotGuardMut<T, L> {
+        let this = core::mem::ManuallyDrop::new(self);
+        let value = this.value;
+        MappedSyncSlotGuardMut {
+            value: f(unsafe { &mut *value }),
+            locks: this.locks,
+        }
+    }
+}
+
+impl<U: ?Sized, L: RawLock> Deref for MappedSyncSlotGuardMut<U, L> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.value }
+    }
+}
+
+impl<U: ?Sized, L: RawLock> DerefMut for MappedSyncSlotGuardMut<U, L> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.value }
+    }
+}
+
+// Both futures try their lock up to `spins` more times, spinning in between, before giving up
+// on the current poll; a lock that is only held briefly is then often taken in one poll.
+pub(crate) struct UnlockRwLockShared<L> {
+    pub(crate) lock: *mut L,
+    pub(crate) spins: u32,
+}
+
+unsafe impl<L: RawLock> Send for UnlockRwLockShared<L> {}
+
+impl<L: RawLock> Future for UnlockRwLockShared<L> {
+    type Output = ();
+
+    fn poll(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Self::Output> {
+        let lock = unsafe { &*self.lock };
+        if spin_until(self.spins, || lock.try_lock_shared()) {
+            Poll::Ready(())
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+pub(crate) struct UnlockRwLockExclusive<L> {
+    pub(crate) lock: *mut L,
+    pub(crate) spins: u32,
 }
 
-impl Future for UnlockRwLockShared {
+unsafe impl<L: RawLock> Send for UnlockRwLockExclusive<L> {}
+
+impl<L: RawLock> Future for UnlockRwLockExclusive<L> {
     type Output = ();
 
     fn poll(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Self::Output> {
-        if unsafe { &*self.lock }.try_lock_shared() {
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Self::Output> {
+        let lock = unsafe { &*self.lock };
+        if spin_until(self.spins, || lock.try_lock_exclusive()) {
             Poll::Ready(())
         } else {
             cx.waker().wake_by_ref();
@@ -398,18 +2793,30 @@ impl Future for UnlockRwLockShared {
     }
 }
 
-pub(crate) struct UnlockRwLockExclusive {
-    pub(crate) lock: *mut RawRwLock,
+pub(crate) fn spin_until(spins: u32, mut try_lock: impl FnMut() -> bool) -> bool {
+    if try_lock() {
+        return true;
+    }
+    for _ in 0..spins {
+        core::hint::spin_loop();
+        if try_lock() {
+            return true;
+        }
+    }
+    false
 }
 
-impl Future for UnlockRwLockExclusive {
+// Returns `Pending` once, so that the task goes to the back of the runtime's queue.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
     type Output = ();
 
     fn poll(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Self::Output> {
-        if unsafe { &*self.lock }.try_lock_exclusive() {
+        mut self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Self::Output> {
+        if core::mem::replace(&mut self.0, true) {
             Poll::Ready(())
         } else {
             cx.waker().wake_by_ref();
@@ -0,0 +1,95 @@
+use slotmap::*;
+
+use crate::raw_lock::{DefaultRawLock, RawLock};
+use crate::sync_slot_map::{SyncSlotGuardMut, SyncSlotMap};
+
+/// A [`SyncSlotMap`] whose entries act like mutexes: reading an entry locks it exclusively,
+/// just like writing it, so there is only the one guard type, [`SyncSlotGuardMut`].
+///
+/// That gives up concurrent readers of the same entry, which write-heavy workloads rarely get
+/// to use anyway, in exchange for never having to hand an entry back and forth between
+/// readers and writers. The map as a whole is locked exactly like a `SyncSlotMap`, so
+/// different entries can still be used concurrently.
+///
+/// This does not give entries a smaller or cheaper lock: they keep the same `L` element locks
+/// as a `SyncSlotMap` underneath, only always taken exclusively, so an entry costs exactly
+/// what it does there. Every element lock is boxed on its own and allocations are rounded up
+/// to at least a word, so a one-byte mutex would save nothing per entry, while a second lock
+/// type for the elements would have to be carried by every guard type of the crate.
+pub struct SyncSlotMapMutex<K: Key, V, L: RawLock = DefaultRawLock> {
+    inner: SyncSlotMap<K, V, L>,
+}
+
+impl<K: Key, V, L: RawLock> Default for SyncSlotMapMutex<K, V, L> {
+    fn default() -> Self {
+        Self::with_raw_lock()
+    }
+}
+
+impl<K: Key, V> SyncSlotMapMutex<K, V> {
+    pub fn new() -> Self {
+        Self::with_raw_lock()
+    }
+}
+
+impl<K: Key, V, L: RawLock> SyncSlotMapMutex<K, V, L> {
+    /// Creates an empty map which uses `L` for all of its locks (and its guards').
+    pub fn with_raw_lock() -> Self {
+        Self {
+            inner: SyncSlotMap::with_raw_lock(),
+        }
+    }
+
+    pub fn insert(&self, value: V) -> K {
+        self.inner.insert(value)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_insert_for(&self, value: V, timeout: std::time::Duration) -> Option<K> {
+        self.inner.try_insert_for(value, timeout)
+    }
+
+    pub async fn insert_async(&self, value: V) -> K {
+        self.inner.insert_async(value).await
+    }
+
+    pub fn remove(&self, key: K) -> Option<V> {
+        self.inner.remove(key)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_remove_for(&self, key: K, timeout: std::time::Duration) -> Option<Option<V>> {
+        self.inner.try_remove_for(key, timeout)
+    }
+
+    pub async fn remove_async(&self, key: K) -> Option<V> {
+        self.inner.remove_async(key).await
+    }
+
+    pub fn get(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        self.inner.get_mut(key)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn try_get_for(
+        &self,
+        key: K,
+        timeout: std::time::Duration,
+    ) -> Option<Option<SyncSlotGuardMut<V, L>>> {
+        self.inner.try_get_mut_for(key, timeout)
+    }
+
+    pub async fn get_async(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        self.inner.get_mut_async(key).await
+    }
+
+    pub fn approx_len(&self) -> usize {
+        self.inner.approx_len()
+    }
+
+    /// Consumes the map, returning the [`SyncSlotMap`] it is made of, with its entries
+    /// readable concurrently again.
+    pub fn into_inner(self) -> SyncSlotMap<K, V, L> {
+        self.inner
+    }
+}
@@ -0,0 +1,296 @@
+use std::cell::UnsafeCell;
+#[cfg(feature = "std")]
+use std::time::Duration;
+
+use slotmap::*;
+
+use crate::locks::{Access, Locks, Registered, SparseLocks};
+use crate::raw_lock::{DefaultRawLock, RawLock};
+use crate::sync_slot_map::{DropBehavior, SyncSlotGuard, SyncSlotGuardMut};
+
+/// A [`SyncSecondarySlotMap`](crate::SyncSecondarySlotMap) backed by `SparseSecondaryMap`, for
+/// when only a few keys of a large primary map are populated.
+pub struct SyncSparseSecondarySlotMap<K: Key, V, L: RawLock = DefaultRawLock> {
+    inner: UnsafeCell<SparseSecondaryMap<K, Box<V>>>,
+    locks: *mut Locks<K, L, SparseLocks<K, L>>,
+}
+
+unsafe impl<K: Key, V, L: RawLock> Send for SyncSparseSecondarySlotMap<K, V, L> where V: Send {}
+unsafe impl<K: Key, V, L: RawLock> Sync for SyncSparseSecondarySlotMap<K, V, L> where V: Send + Sync {}
+
+impl<K: Key, V, L: RawLock> Drop for SyncSparseSecondarySlotMap<K, V, L> {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        let leak = || core::mem::forget(core::mem::take(inner));
+        if unsafe { &*self.locks }.lock_for_drop("SyncSparseSecondarySlotMap", leak) {
+            unsafe { drop(Box::from_raw(self.locks)) };
+        }
+    }
+}
+
+impl<K: Key, V, L: RawLock> Default for SyncSparseSecondarySlotMap<K, V, L> {
+    fn default() -> Self {
+        Self::with_raw_lock()
+    }
+}
+
+impl<K: Key, V> SyncSparseSecondarySlotMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_raw_lock()
+    }
+}
+
+impl<K: Key, V, L: RawLock> SyncSparseSecondarySlotMap<K, V, L> {
+    /// Creates an empty map which uses `L` for all of its locks (and its guards').
+    pub fn with_raw_lock() -> Self {
+        let locks = Box::new(Locks::new());
+        let locks = Box::into_raw(locks);
+        Self {
+            inner: UnsafeCell::new(SparseSecondaryMap::new()),
+            locks,
+        }
+    }
+
+    /// Sets whether guards (and whole-map operations) unlock fairly, handing their locks
+    /// straight to a waiting thread rather than letting whichever thread comes along first
+    /// barge in (see [`RawLock::unlock_shared_fair`]). Off by default.
+    ///
+    /// This is slower, but keeps a steady stream of readers from starving writers. It takes
+    /// effect immediately, including for guards which are already held.
+    pub fn set_fair(&self, fair: bool) {
+        unsafe { &*self.locks }.set_fair(fair);
+    }
+
+    /// Sets what dropping the map does while guards on it are still alive, like
+    /// [`SyncSlotMap::set_drop_behavior`](crate::SyncSlotMap::set_drop_behavior). The default
+    /// is to wait.
+    pub fn set_drop_behavior(&self, behavior: DropBehavior) {
+        unsafe { &*self.locks }.set_drop_behavior(behavior);
+    }
+
+    /// Sets how long dropping the map waits for guards before giving up, like
+    /// [`SyncSlotMap::set_drop_timeout`](crate::SyncSlotMap::set_drop_timeout). A second by
+    /// default.
+    #[cfg(feature = "std")]
+    pub fn set_drop_timeout(&self, timeout: Duration) {
+        unsafe { &*self.locks }.set_drop_timeout(timeout);
+    }
+
+    /// Sets how many more times the `*_async` methods try a busy lock, spinning in between,
+    /// before yielding to the async runtime. Zero (the default) yields straight away.
+    ///
+    /// Spinning a little lets locks which are only held briefly be taken without the task
+    /// going back to the runtime's queue, at the cost of keeping the thread busy meanwhile.
+    pub fn set_spin_count(&self, spins: u32) {
+        unsafe { &*self.locks }.set_spins(spins);
+    }
+
+    /// The spin count set with `set_spin_count`.
+    pub fn spin_count(&self) -> u32 {
+        unsafe { &*self.locks }.spins()
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive();
+        let replaced = unsafe { self.insert_locked(key, value) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let (old_key, old_value, lock) = replaced?;
+        unsafe { locks.retire(lock) };
+        (old_key == key).then_some(*old_value)
+    }
+
+    pub fn try_insert_for(
+        &self,
+        key: K,
+        value: V,
+        timeout: std::time::Duration,
+    ) -> Option<Option<V>> {
+        let locks = unsafe { &*self.locks };
+        if !locks.structure.try_lock_exclusive_for(timeout) {
+            return None;
+        }
+        let replaced = unsafe { self.insert_locked(key, value) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let Some((old_key, old_value, lock)) = replaced else {
+            return Some(None);
+        };
+        unsafe { locks.retire(lock) };
+        Some((old_key == key).then_some(*old_value))
+    }
+
+    pub async fn insert_async(&self, key: K, value: V) -> Option<V> {
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive_async(locks.spins()).await;
+        let replaced = unsafe { self.insert_locked(key, value) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let (old_key, old_value, lock) = replaced?;
+        let old_value = unsafe { locks.retire_async(lock, old_value).await };
+        (old_key == key).then_some(*old_value)
+    }
+
+    // A replaced entry (either `key` itself or an older key for the same slot) gets a new lock,
+    // the caller retires the old one so guards still on the old value are waited out.
+    unsafe fn insert_locked(&self, key: K, value: V) -> Option<(K, Box<V>, Box<L>)> {
+        let inner = &mut *self.inner.get();
+        match (*self.locks).register(key) {
+            Registered::Refused => None,
+            Registered::Fresh => {
+                inner.insert(key, Box::new(value));
+                None
+            }
+            Registered::Replaced(old_key, lock) => {
+                let old_value = inner.remove(old_key)?;
+                inner.insert(key, Box::new(value));
+                Some((old_key, old_value, lock))
+            }
+        }
+    }
+
+    pub fn remove(&self, key: K) -> Option<V> {
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive();
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let (value, lock) = removed?;
+        unsafe { locks.retire(lock) };
+        Some(*value)
+    }
+
+    pub fn try_remove_for(&self, key: K, timeout: std::time::Duration) -> Option<Option<V>> {
+        let locks = unsafe { &*self.locks };
+        if !locks.structure.try_lock_exclusive_for(timeout) {
+            return None;
+        }
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let Some((value, lock)) = removed else {
+            return Some(None);
+        };
+        unsafe { locks.retire(lock) };
+        Some(Some(*value))
+    }
+
+    pub async fn remove_async(&self, key: K) -> Option<V> {
+        let locks = unsafe { &*self.locks };
+        locks.structure.lock_exclusive_async(locks.spins()).await;
+        let removed = unsafe { self.remove_locked(key) };
+        unsafe { locks.structure.unlock_exclusive() };
+        let (value, lock) = removed?;
+        let value = unsafe { locks.retire_async(lock, value).await };
+        Some(*value)
+    }
+
+    // The entry is unlinked here, the caller then retires its lock, which waits out any guards
+    // still pointing at the value before it is moved out of its box.
+    unsafe fn remove_locked(&self, key: K) -> Option<(Box<V>, Box<L>)> {
+        let value = (*self.inner.get()).remove(key)?;
+        let lock = (*self.locks).unregister(key)?;
+        Some((value, lock))
+    }
+
+    /// Gives memory left over from removed entries back to the allocator, waiting for every
+    /// guard to be dropped first.
+    pub fn shrink_to_fit(&self) {
+        let locks = unsafe { &*self.locks };
+        locks.lock_exclusive();
+        unsafe {
+            let inner = &mut *self.inner.get();
+            *inner = inner.drain().collect();
+            locks.shrink_to_fit();
+            locks.unlock_exclusive();
+        }
+    }
+
+    /// Frees the element locks of removed entries that are kept around for reuse, and shrinks
+    /// the table of element locks down to the highest slot still in use.
+    ///
+    /// The table is indexed by slot, so without this it stays as large as the map has ever
+    /// been. It waits for every guard to be dropped first, like [`SyncSparseSecondarySlotMap::shrink_to_fit`].
+    /// Nothing compacts automatically except in [`SyncSparseSecondarySlotMap::shrink_to_fit`].
+    pub fn compact_locks(&self) {
+        unsafe { &*self.locks }.compact();
+    }
+
+    fn lookup(&self, key: K) -> Option<*mut V> {
+        unsafe { &*self.inner.get() }
+            .get(key)
+            .map(|value| &**value as *const V as *mut V)
+    }
+
+    pub fn get(&self, key: K) -> Option<SyncSlotGuard<V, L>> {
+        let (locks, value) =
+            unsafe { (*self.locks).acquire(key, Access::Shared, || self.lookup(key)) }?;
+        Some(SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    pub fn get_mut(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        let (locks, value) =
+            unsafe { (*self.locks).acquire(key, Access::Exclusive, || self.lookup(key)) }?;
+        Some(SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    pub fn try_get_for(
+        &self,
+        key: K,
+        timeout: std::time::Duration,
+    ) -> Option<Option<SyncSlotGuard<V, L>>> {
+        let acquired = unsafe {
+            (*self.locks).try_acquire_for(key, Access::Shared, timeout, || self.lookup(key))
+        }?;
+        Some(acquired.map(|(locks, value)| SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        }))
+    }
+
+    pub fn try_get_mut_for(
+        &self,
+        key: K,
+        timeout: std::time::Duration,
+    ) -> Option<Option<SyncSlotGuardMut<V, L>>> {
+        let acquired = unsafe {
+            (*self.locks).try_acquire_for(key, Access::Exclusive, timeout, || self.lookup(key))
+        }?;
+        Some(acquired.map(|(locks, value)| SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        }))
+    }
+
+    pub async fn get_async(&self, key: K) -> Option<SyncSlotGuard<V, L>> {
+        let (locks, value) = unsafe {
+            (*self.locks)
+                .acquire_async(key, Access::Shared, || self.lookup(key))
+                .await
+        }?;
+        Some(SyncSlotGuard {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+
+    pub async fn get_mut_async(&self, key: K) -> Option<SyncSlotGuardMut<V, L>> {
+        let (locks, value) = unsafe {
+            (*self.locks)
+                .acquire_async(key, Access::Exclusive, || self.lookup(key))
+                .await
+        }?;
+        Some(SyncSlotGuardMut {
+            value,
+            locks,
+            key: key.data(),
+        })
+    }
+}
@@ -0,0 +1,94 @@
+//! A lock for maps used from tokio, with the `tokio` feature.
+
+use std::time::Instant;
+
+use tokio::sync::Notify;
+
+use crate::raw_lock::{DefaultRawLock, RawLock};
+
+/// A [`RawLock`] which wakes the `*_async` methods waiting on it through a
+/// [`tokio::sync::Notify`] when it's unlocked, instead of them polling it over and over.
+///
+/// Everything else is passed on to `L`, so the blocking methods behave just like with `L`
+/// itself. Use it as a map's lock type, e.g. `SyncSlotMap<K, V, TokioRawLock>`.
+pub struct TokioRawLock<L = DefaultRawLock> {
+    lock: L,
+    unlocked: Notify,
+}
+
+unsafe impl<L: RawLock> RawLock for TokioRawLock<L> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        lock: L::INIT,
+        unlocked: Notify::const_new(),
+    };
+
+    fn lock_shared(&self) {
+        self.lock.lock_shared()
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.lock.try_lock_shared()
+    }
+
+    fn try_lock_shared_until(&self, deadline: Instant) -> bool {
+        self.lock.try_lock_shared_until(deadline)
+    }
+
+    fn lock_shared_recursive(&self) {
+        self.lock.lock_shared_recursive()
+    }
+
+    fn try_lock_shared_recursive(&self) -> bool {
+        self.lock.try_lock_shared_recursive()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.lock.unlock_shared();
+        self.unlocked.notify_waiters();
+    }
+
+    fn lock_exclusive(&self) {
+        self.lock.lock_exclusive()
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.lock.try_lock_exclusive()
+    }
+
+    fn try_lock_exclusive_until(&self, deadline: Instant) -> bool {
+        self.lock.try_lock_exclusive_until(deadline)
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.lock.unlock_exclusive();
+        self.unlocked.notify_waiters();
+    }
+
+    unsafe fn unlock_shared_fair(&self) {
+        self.lock.unlock_shared_fair();
+        self.unlocked.notify_waiters();
+    }
+
+    unsafe fn unlock_exclusive_fair(&self) {
+        self.lock.unlock_exclusive_fair();
+        self.unlocked.notify_waiters();
+    }
+
+    unsafe fn downgrade(&self) {
+        self.lock.downgrade();
+        self.unlocked.notify_waiters();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.lock.is_locked()
+    }
+
+    fn is_locked_exclusive(&self) -> bool {
+        self.lock.is_locked_exclusive()
+    }
+
+    fn unlocked(&self) -> Option<&Notify> {
+        Some(&self.unlocked)
+    }
+}
@@ -0,0 +1,44 @@
+//! Lock events for the `tracing` feature. Without it these do nothing and compile away.
+
+use slotmap::KeyData;
+
+/// Runs `acquire`, which takes the locks `op` needs (for `key`, if it's known yet), and emits an
+/// event with how long that took.
+#[inline(always)]
+pub(crate) fn acquire<R>(op: &'static str, key: Option<KeyData>, acquire: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "tracing")]
+    {
+        let start = std::time::Instant::now();
+        let result = acquire();
+        tracing::trace!(
+            target: "sync_slotmap",
+            op,
+            key = ?key,
+            waited_us = start.elapsed().as_micros() as u64,
+            "acquired"
+        );
+        result
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (op, key);
+        acquire()
+    }
+}
+
+/// Emits an event for the locks `op` took being released.
+#[inline(always)]
+pub(crate) fn released(op: &'static str, key: Option<KeyData>) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(target: "sync_slotmap", op, key = ?key, "released");
+    #[cfg(not(feature = "tracing"))]
+    let _ = (op, key);
+}
+
+/// Emits a warning about how the map is being used.
+pub(crate) fn warn(message: core::fmt::Arguments<'_>) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(target: "sync_slotmap", "{message}");
+    #[cfg(not(feature = "tracing"))]
+    let _ = message;
+}
@@ -0,0 +1,35 @@
+#![cfg(all(feature = "deadlock-detection", not(feature = "std-locks")))]
+
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+use slotmap::DefaultKey;
+use sync_slotmap::{check_deadlocks, SyncSlotMap};
+
+#[test]
+fn deadlock_is_detected() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, i32>::new());
+    let (a, b) = (map.insert(1), map.insert(2));
+    let barrier = Arc::new(Barrier::new(2));
+    // Each thread holds one entry and waits for the other's, so neither ever finishes.
+    for (held, wanted) in [(a, b), (b, a)] {
+        let (map, barrier) = (map.clone(), barrier.clone());
+        thread::spawn(move || {
+            let _held = map.get_mut(held).unwrap();
+            barrier.wait();
+            let _wanted = map.get_mut(wanted).unwrap();
+        });
+    }
+    let mut found = Vec::new();
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(100));
+        found = check_deadlocks();
+        if !found.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].threads.len(), 2);
+    assert!(check_deadlocks().is_empty());
+}
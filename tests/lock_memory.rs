@@ -0,0 +1,94 @@
+//! Measures the memory held by the maps' element locks, with an allocator counting the bytes
+//! in use. The tests take turns so that they don't count each other's allocations.
+#![cfg(feature = "std")]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use slotmap::DefaultKey;
+use sync_slotmap::{SyncDenseSlotMap, SyncSlotMap};
+
+struct Counting;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+static TURN: Mutex<()> = Mutex::new(());
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        IN_USE.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        IN_USE.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn in_use() -> usize {
+    IN_USE.load(Ordering::SeqCst)
+}
+
+#[test]
+fn compact_locks_after_churn() {
+    let _turn = TURN.lock().unwrap();
+    let map = SyncSlotMap::<DefaultKey, u64>::new();
+    let dense = SyncDenseSlotMap::<DefaultKey, u64>::new();
+    let kept = map.insert(1);
+    for _ in 0..5 {
+        let keys: Vec<_> = (0..10_000).map(|i| map.insert(i)).collect();
+        let dense_keys: Vec<_> = (0..10_000).map(|i| dense.insert(i)).collect();
+        for key in keys {
+            map.remove(key);
+        }
+        for key in dense_keys {
+            dense.remove(key);
+        }
+    }
+    let before = in_use();
+    map.compact_locks();
+    dense.compact_locks();
+    let freed = before - in_use();
+    // At least a pointer's worth for each of the 20,000 removed entries' locks.
+    assert!(freed >= 20_000 * size_of::<usize>());
+    assert_eq!(*map.get(kept).unwrap(), 1);
+    let key = map.insert(2);
+    assert_eq!(*map.get_mut(key).unwrap(), 2);
+}
+
+// Fills a map with 100,000 entries, keeps every 100th and compacts its locks, returning the
+// bytes it then takes up.
+fn sparsely_used(sparse_locks: bool) -> usize {
+    let before = in_use();
+    let map = SyncSlotMap::<DefaultKey, u64>::builder()
+        .sparse_locks(sparse_locks)
+        .build();
+    let keys: Vec<_> = (0..100_000).map(|i| map.insert(i)).collect();
+    for (i, &key) in keys.iter().enumerate() {
+        if i % 100 != 0 {
+            map.remove(key);
+        }
+    }
+    drop(keys);
+    map.compact_locks();
+    let used = in_use() - before;
+    assert_eq!(map.approx_len(), 1000);
+    used
+}
+
+#[test]
+fn sparse_locks_on_a_sparse_map() {
+    let _turn = TURN.lock().unwrap();
+    let table = sparsely_used(false);
+    let sparse = sparsely_used(true);
+    // The table keeps 16 bytes for each of the 100,000 slots, the hash map about 50 for each
+    // of the 1,000 entries.
+    assert!(
+        table - sparse >= 1_000_000,
+        "table {table}, sparse {sparse}"
+    );
+}
@@ -0,0 +1,204 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use slotmap::{DefaultKey, SlotMap};
+use sync_slotmap::{
+    DefaultRawLock, RawLock, SyncDenseSlotMap, SyncSecondarySlotMap, SyncSlotGuard, SyncSlotMap,
+};
+
+// Only the methods a lock has to provide, counting exclusive locks.
+struct Counting(DefaultRawLock);
+
+static EXCLUSIVE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl RawLock for Counting {
+    const INIT: Self = Counting(<DefaultRawLock as RawLock>::INIT);
+
+    fn lock_shared(&self) {
+        self.0.lock_shared()
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.0.try_lock_shared()
+    }
+
+    fn lock_shared_recursive(&self) {
+        self.0.lock_shared_recursive()
+    }
+
+    fn try_lock_shared_recursive(&self) -> bool {
+        self.0.try_lock_shared_recursive()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.0.unlock_shared()
+    }
+
+    fn lock_exclusive(&self) {
+        EXCLUSIVE.fetch_add(1, Ordering::SeqCst);
+        self.0.lock_exclusive()
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.0.try_lock_exclusive()
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.0.unlock_exclusive()
+    }
+
+    unsafe fn downgrade(&self) {
+        self.0.downgrade()
+    }
+
+    fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+
+    fn is_locked_exclusive(&self) -> bool {
+        self.0.is_locked_exclusive()
+    }
+}
+
+#[test]
+fn custom_lock() {
+    let map = SyncSlotMap::<DefaultKey, i32, Counting>::with_raw_lock();
+    let before = EXCLUSIVE.load(Ordering::SeqCst);
+    let key = map.insert(1);
+    *map.get_mut(key).unwrap() += 1;
+    assert_eq!(*map.get(key).unwrap(), 2);
+    assert!(EXCLUSIVE.load(Ordering::SeqCst) > before);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn custom_lock_default_timed_methods() {
+    use std::time::{Duration, Instant};
+
+    let map = SyncSlotMap::<DefaultKey, i32, Counting>::with_raw_lock();
+    let key = map.insert(1);
+    let guard = map.get_mut(key).unwrap();
+    let start = Instant::now();
+    assert!(map.try_get_for(key, Duration::from_millis(20)).is_none());
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    drop(guard);
+    assert_eq!(
+        *map.try_get_for(key, Duration::from_millis(20))
+            .unwrap()
+            .unwrap(),
+        1
+    );
+}
+
+// Run with `--no-default-features --features std-locks` to cover `std::sync::RwLock`.
+#[cfg(feature = "std")]
+#[test]
+fn default_lock() {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let lock = DefaultRawLock::INIT;
+    lock.lock_shared();
+    assert!(lock.try_lock_shared());
+    assert!(!lock.try_lock_exclusive());
+    unsafe { lock.unlock_shared() };
+    unsafe { lock.unlock_shared() };
+    lock.lock_exclusive();
+    assert!(lock.is_locked_exclusive());
+    unsafe { lock.downgrade() };
+    assert!(lock.is_locked() && !lock.is_locked_exclusive());
+    unsafe { lock.unlock_shared() };
+    assert!(!lock.is_locked());
+
+    let map = Arc::new(SyncSlotMap::<DefaultKey, i32>::new());
+    let key = map.insert(0);
+    let guard = map.get(key).unwrap();
+    assert!(map
+        .try_get_mut_for(key, Duration::from_millis(20))
+        .is_none());
+    let writer = thread::spawn({
+        let map = map.clone();
+        move || *map.get_mut(key).unwrap() += 1
+    });
+    thread::sleep(Duration::from_millis(20));
+    drop(guard);
+    writer.join().unwrap();
+    assert_eq!(*map.get(key).unwrap(), 1);
+
+    // A writer panicking doesn't poison the entry, it's unlocked with whatever was written.
+    let panicked = thread::spawn({
+        let map = map.clone();
+        move || {
+            let mut guard = map.get_mut(key).unwrap();
+            *guard += 1;
+            panic!("while writing");
+        }
+    });
+    assert!(panicked.join().is_err());
+    assert_eq!(*map.try_get_mut(key).unwrap().unwrap(), 2);
+}
+
+#[test]
+fn custom_lock_in_every_map() {
+    let before = EXCLUSIVE.load(Ordering::SeqCst);
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let key = primary.insert(());
+
+    let secondary = SyncSecondarySlotMap::<DefaultKey, i32, Counting>::with_raw_lock();
+    secondary.insert(key, 1);
+    *secondary.get_mut(key).unwrap() += 1;
+    let guard: SyncSlotGuard<i32, Counting> = secondary.get(key).unwrap();
+    assert_eq!(*guard, 2);
+    drop(guard);
+
+    let dense = SyncDenseSlotMap::<DefaultKey, i32, Counting>::with_raw_lock();
+    let key = dense.insert(3);
+    *dense.get_mut(key).unwrap() += 1;
+    assert_eq!(*dense.get(key).unwrap(), 4);
+    assert_eq!(dense.remove(key), Some(4));
+
+    assert!(EXCLUSIVE.load(Ordering::SeqCst) >= before + 2);
+}
+
+#[cfg(feature = "spin-locks")]
+#[test]
+fn spin_lock() {
+    use std::sync::Arc;
+    use std::thread;
+    use sync_slotmap::SpinRawLock;
+
+    let lock = SpinRawLock::INIT;
+    lock.lock_shared();
+    assert!(!lock.try_lock_exclusive());
+    // A waiting writer doesn't keep out a thread that already reads.
+    lock.lock_shared_recursive();
+    unsafe { lock.unlock_shared() };
+    unsafe { lock.unlock_shared() };
+    assert!(lock.try_lock_exclusive());
+    assert!(!lock.try_lock_shared());
+    unsafe { lock.downgrade() };
+    assert!(lock.try_lock_shared());
+    unsafe { lock.unlock_shared() };
+    unsafe { lock.unlock_shared() };
+    assert!(!lock.is_locked());
+
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u64, SpinRawLock>::with_raw_lock());
+    let key = map.insert(0);
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let map = map.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    *map.get_mut(key).unwrap() += 1;
+                    let added = map.insert(1);
+                    assert_eq!(*map.get(added).unwrap(), 1);
+                    map.remove(added);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*map.get(key).unwrap(), 4000);
+}
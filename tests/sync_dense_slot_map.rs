@@ -0,0 +1,107 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use common::poll_once;
+use slotmap::DefaultKey;
+use sync_slotmap::{DropBehavior, SyncDenseSlotMap};
+
+#[test]
+fn drop_behaviors() {
+    let map = SyncDenseSlotMap::<DefaultKey, i32>::new();
+    let guard = map.get(map.insert(7)).unwrap();
+    map.set_drop_behavior(DropBehavior::Panic);
+    map.set_drop_timeout(Duration::from_millis(20));
+    assert!(catch_unwind(AssertUnwindSafe(|| drop(map))).is_err());
+    assert_eq!(*guard, 7);
+    drop(guard);
+
+    let map = SyncDenseSlotMap::<DefaultKey, i32>::new();
+    let guard = map.get(map.insert(8)).unwrap();
+    map.set_drop_behavior(DropBehavior::Warn);
+    map.set_drop_timeout(Duration::from_millis(20));
+    drop(map);
+    assert_eq!(*guard, 8);
+}
+
+#[test]
+fn insert_with_room_while_guard_held() {
+    let map = Arc::new(SyncDenseSlotMap::<DefaultKey, i32>::new());
+    map.reserve(16);
+    let key = map.insert(1);
+    let guard = map.get(key).unwrap();
+    thread::spawn({
+        let map = map.clone();
+        move || {
+            for i in 0..15 {
+                map.insert(i);
+            }
+        }
+    })
+    .join()
+    .unwrap();
+    assert_eq!(*guard, 1);
+}
+
+#[test]
+fn iteration() {
+    let map = SyncDenseSlotMap::<DefaultKey, i32>::new();
+    let keys: Vec<_> = (0..10).map(|i| map.insert(i)).collect();
+    // Moves the last value into the removed one's place.
+    assert_eq!(map.remove(keys[2]), Some(2));
+
+    let mut seen = Vec::new();
+    map.for_each(|key, &value| seen.push((key, value)));
+    seen.sort_by_key(|&(_, value)| value);
+    let expected: Vec<_> = (0..10)
+        .filter(|&i| i != 2)
+        .map(|i| (keys[i], i as i32))
+        .collect();
+    assert_eq!(seen, expected);
+
+    let mut iterated: Vec<_> = map.iter().map(|(key, guard)| (key, *guard)).collect();
+    iterated.sort_by_key(|&(_, value)| value);
+    assert_eq!(iterated, expected);
+    assert_eq!(map.values().map(|guard| *guard).sum::<i32>(), 45 - 2);
+    for &(key, value) in &expected {
+        assert_eq!(*map.get(key).unwrap(), value);
+    }
+
+    map.for_each_mut(|_, value| *value *= 2);
+    for &(key, value) in &expected {
+        assert_eq!(*map.get(key).unwrap(), value * 2);
+    }
+}
+
+#[test]
+fn cancelled_async_calls_release_their_locks() {
+    let map = SyncDenseSlotMap::<DefaultKey, i32>::new();
+    let key = map.insert(1);
+    let guard = map.get_mut(key).unwrap();
+    assert!(!poll_once(map.get_async(key)));
+    assert!(!poll_once(map.get_mut_async(key)));
+    assert!(!poll_once(map.remove_async(key)));
+    drop(guard);
+    assert_eq!(*map.get(key).unwrap(), 1);
+    assert!(map.try_insert_for(2, Duration::from_millis(50)).is_some());
+    assert_eq!(map.remove(key), Some(1));
+}
+
+#[test]
+fn panicking_closures_leave_the_map_unlocked() {
+    let map = SyncDenseSlotMap::<DefaultKey, i32>::new();
+    let keys = [map.insert(1), map.insert(2)];
+    assert!(catch_unwind(AssertUnwindSafe(|| map.for_each(|_, _| panic!("boom")))).is_err());
+    assert!(catch_unwind(AssertUnwindSafe(|| map.for_each_mut(|_, _| panic!("boom")))).is_err());
+    // Removing locks the whole map exclusively.
+    assert_eq!(
+        map.try_remove_for(keys[0], Duration::from_secs(5)),
+        Some(Some(1))
+    );
+    assert_eq!(*map.get(keys[1]).unwrap(), 2);
+}
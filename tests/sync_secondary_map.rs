@@ -0,0 +1,548 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::block_on;
+use slotmap::{DefaultKey, SlotMap};
+use sync_slotmap::{DropBehavior, SyncSecondarySlotMap, SyncSlotMap};
+
+#[test]
+fn insert_get_remove() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let key = primary.insert(());
+    let map = SyncSecondarySlotMap::<DefaultKey, i32>::new();
+
+    assert!(map.get(key).is_none());
+    assert_eq!(map.insert(key, 1), None);
+    assert_eq!(map.insert(key, 2), Some(1));
+    *map.get_mut(key).unwrap() += 1;
+    assert_eq!(*map.get(key).unwrap(), 3);
+    assert_eq!(map.remove(key), Some(3));
+    assert_eq!(map.remove(key), None);
+    assert!(map.get_mut(key).is_none());
+}
+
+#[test]
+fn stale_keys() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let old = primary.insert(());
+    let map = SyncSecondarySlotMap::<DefaultKey, i32>::new();
+    map.insert(old, 1);
+
+    primary.remove(old);
+    let new = primary.insert(());
+    // Inserting a newer key for the same slot drops the old entry rather than handing it back.
+    assert_eq!(map.insert(new, 2), None);
+    assert!(map.get(old).is_none());
+    assert_eq!(map.remove(old), None);
+    // And an older key can't clobber the newer entry.
+    assert_eq!(map.insert(old, 9), None);
+    assert_eq!(*map.get(new).unwrap(), 2);
+}
+
+#[test]
+fn timed_and_async() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let (key, missing) = (primary.insert(()), primary.insert(()));
+    let map = SyncSecondarySlotMap::<DefaultKey, i32>::new();
+    assert_eq!(
+        map.try_insert_for(key, 1, Duration::from_millis(20)),
+        Some(None)
+    );
+
+    let guard = map.get_mut(key).unwrap();
+    assert!(map.try_get_for(key, Duration::from_millis(20)).is_none());
+    assert!(map
+        .try_get_mut_for(key, Duration::from_millis(20))
+        .is_none());
+    drop(guard);
+    assert!(map
+        .try_get_for(missing, Duration::from_millis(20))
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        *map.try_get_for(key, Duration::from_millis(20))
+            .unwrap()
+            .unwrap(),
+        1
+    );
+
+    assert_eq!(block_on(map.insert_async(key, 2)), Some(1));
+    *block_on(map.get_mut_async(key)).unwrap() += 1;
+    assert_eq!(*block_on(map.get_async(key)).unwrap(), 3);
+    assert!(block_on(map.get_async(missing)).is_none());
+    assert_eq!(block_on(map.remove_async(key)), Some(3));
+    assert_eq!(
+        map.try_remove_for(key, Duration::from_millis(20)),
+        Some(None)
+    );
+}
+
+#[test]
+fn drop_behaviors() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let key = primary.insert(());
+
+    let map = SyncSecondarySlotMap::<DefaultKey, i32>::new();
+    map.insert(key, 7);
+    let guard = map.get(key).unwrap();
+    map.set_drop_behavior(DropBehavior::Panic);
+    map.set_drop_timeout(Duration::from_millis(20));
+    assert!(catch_unwind(AssertUnwindSafe(|| drop(map))).is_err());
+    assert_eq!(*guard, 7);
+    drop(guard);
+
+    let map = SyncSecondarySlotMap::<DefaultKey, i32>::new();
+    map.insert(key, 8);
+    let guard = map.get(key).unwrap();
+    map.set_drop_behavior(DropBehavior::Warn);
+    map.set_drop_timeout(Duration::from_millis(20));
+    drop(map);
+    assert_eq!(*guard, 8);
+}
+
+struct CountWakes(AtomicUsize);
+
+impl Wake for CountWakes {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn wait_for_is_woken_by_inserts() {
+    let wakes = Arc::new(CountWakes(AtomicUsize::new(0)));
+    let waker = Waker::from(wakes.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let (key, other) = (primary.insert(()), primary.insert(()));
+    let map = SyncSecondarySlotMap::<DefaultKey, i32>::new();
+
+    let mut waiting = pin!(map.wait_for(key));
+    assert!(waiting.as_mut().poll(&mut cx).is_pending());
+    map.insert(other, 1);
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+    assert!(waiting.as_mut().poll(&mut cx).is_pending());
+    map.insert(key, 7);
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 2);
+    match waiting.as_mut().poll(&mut cx) {
+        Poll::Ready(guard) => assert_eq!(*guard, 7),
+        Poll::Pending => panic!("still waiting after the insert"),
+    }
+}
+
+#[test]
+fn dropped_wait_for_futures_release_their_wakers() {
+    let wakes = Arc::new(CountWakes(AtomicUsize::new(0)));
+    let waker = Waker::from(wakes.clone());
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let key = primary.insert(());
+    let map = SyncSecondarySlotMap::<DefaultKey, i32>::new();
+
+    let mut cx = Context::from_waker(&waker);
+    for _ in 0..100 {
+        let mut waiting = pin!(map.wait_for(key));
+        assert!(waiting.as_mut().poll(&mut cx).is_pending());
+    }
+    assert!(map
+        .wait_for_timeout(key, Duration::from_millis(1))
+        .is_none());
+    drop(waker);
+    // Only the test still has a hold on the waker.
+    assert_eq!(Arc::strong_count(&wakes), 1);
+}
+
+#[test]
+fn wait_for_timeout() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let (key, missing) = (primary.insert(()), primary.insert(()));
+    let map = Arc::new(SyncSecondarySlotMap::<DefaultKey, i32>::new());
+    let producer = thread::spawn({
+        let map = map.clone();
+        move || {
+            thread::sleep(Duration::from_millis(50));
+            map.insert(key, 9);
+        }
+    });
+    assert_eq!(
+        *map.wait_for_timeout(key, Duration::from_secs(5)).unwrap(),
+        9
+    );
+    producer.join().unwrap();
+
+    let start = Instant::now();
+    assert!(map
+        .wait_for_timeout(missing, Duration::from_millis(30))
+        .is_none());
+    assert!(start.elapsed() >= Duration::from_millis(30));
+}
+
+#[test]
+fn get_or_insert_with_key_runs_once() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let map = Arc::new(SyncSecondarySlotMap::<DefaultKey, (DefaultKey, u32)>::new());
+    let calls = Arc::new(AtomicUsize::new(0));
+    for _ in 0..100 {
+        let key = primary.insert(());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (map, calls) = (map.clone(), calls.clone());
+                thread::spawn(move || {
+                    let mut guard = map
+                        .get_or_insert_with_key(key, |key| {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            (key, 0)
+                        })
+                        .unwrap();
+                    assert_eq!(guard.0, key);
+                    guard.1 += 1;
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(map.get(key).unwrap().1, 4);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 100);
+}
+
+#[test]
+fn merge() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let keys: Vec<_> = (0..10).map(|_| primary.insert(())).collect();
+    let a = Arc::new(SyncSecondarySlotMap::<DefaultKey, i32>::new());
+    let b = Arc::new(SyncSecondarySlotMap::<DefaultKey, i32>::new());
+    for &key in &keys[..6] {
+        a.insert(key, 1);
+    }
+    for &key in &keys[4..] {
+        b.insert(key, 10);
+    }
+    a.merge(&b, |into, from| *into += from);
+    assert_eq!(*a.get(keys[0]).unwrap(), 1);
+    assert_eq!(*a.get(keys[5]).unwrap(), 11);
+    assert_eq!(*a.get(keys[9]).unwrap(), 10);
+    assert_eq!((a.approx_len(), b.approx_len()), (10, 0));
+    assert!(b.get(keys[9]).is_none());
+    a.merge(&a, |_, _| unreachable!());
+    assert_eq!(a.approx_len(), 10);
+
+    // Merging two maps into each other from different threads doesn't deadlock.
+    for _ in 0..200 {
+        let merger = thread::spawn({
+            let (a, b) = (a.clone(), b.clone());
+            move || a.merge(&b, |into, from| *into += from)
+        });
+        b.merge(&a, |into, from| *into += from);
+        merger.join().unwrap();
+    }
+    assert_eq!(a.approx_len() + b.approx_len(), 10);
+}
+
+#[test]
+fn approx_len_counts_keys_once() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let (a, b) = (primary.insert(()), primary.insert(()));
+    let map = SyncSecondarySlotMap::<DefaultKey, i32>::new();
+    map.insert(a, 1);
+    map.insert(a, 2);
+    map.insert(b, 3);
+    assert_eq!(map.approx_len(), 2);
+    map.remove(a);
+    map.remove(a);
+    assert_eq!(map.approx_len(), 1);
+}
+
+#[test]
+fn shrink_to_fit() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let keys: Vec<_> = (0..1000).map(|_| primary.insert(())).collect();
+    let map = SyncSecondarySlotMap::<DefaultKey, u32>::new();
+    for &key in &keys {
+        map.insert(key, 1);
+    }
+    for &key in &keys[1..] {
+        map.remove(key);
+    }
+    assert!(map.capacity() >= 1000);
+    map.shrink_to_fit();
+    assert!(map.capacity() < 1000);
+    assert_eq!(*map.get(keys[0]).unwrap(), 1);
+    assert!(map.get(keys[1]).is_none());
+    map.insert(keys[999], 2);
+    assert_eq!(*map.get_mut(keys[999]).unwrap(), 2);
+}
+
+#[test]
+fn equality() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let keys: Vec<_> = (0..3).map(|_| primary.insert(())).collect();
+    let a = SyncSecondarySlotMap::<DefaultKey, u32>::new();
+    let b = SyncSecondarySlotMap::<DefaultKey, u32>::new();
+    assert!(a == b);
+    a.insert(keys[0], 1);
+    b.insert(keys[0], 1);
+    assert!(a == b && a == a);
+    b.insert(keys[1], 2);
+    assert!(a != b);
+    // The same values under different keys aren't equal.
+    a.insert(keys[2], 2);
+    assert!(a != b);
+    b.remove(keys[1]);
+    b.insert(keys[2], 2);
+    assert!(a == b);
+    *b.get_mut(keys[2]).unwrap() = 3;
+    assert!(a != b);
+}
+
+#[test]
+fn set_fair() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let key = primary.insert(());
+    for fair in [false, true] {
+        let map = Arc::new(SyncSecondarySlotMap::<DefaultKey, u64>::new());
+        map.set_fair(fair);
+        map.insert(key, 0);
+        let stop = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..6)
+            .map(|_| {
+                let (map, stop) = (map.clone(), stop.clone());
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        std::hint::black_box(*map.get(key).unwrap());
+                    }
+                })
+            })
+            .collect();
+        let start = Instant::now();
+        for _ in 0..200 {
+            *map.get_mut(key).unwrap() += 1;
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        stop.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(*map.get(key).unwrap(), 200);
+    }
+}
+
+#[test]
+fn get_mut_or_insert_converges() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let key = primary.insert(());
+    let map = Arc::new(SyncSecondarySlotMap::<DefaultKey, u64>::new());
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let map = map.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    *map.get_mut_or_insert(key, 0).unwrap() += 1;
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*map.get(key).unwrap(), 8000);
+    assert_eq!(map.approx_len(), 1);
+
+    primary.remove(key);
+    let stale = key;
+    let key = primary.insert(());
+    *map.get_mut_or_insert(key, 5).unwrap() += 1;
+    assert!(map.get(stale).is_none());
+    assert_eq!(*map.get(key).unwrap(), 6);
+}
+
+#[test]
+fn hash_ignores_insertion_order() {
+    fn hash(value: &impl Hash) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let keys: Vec<_> = (0..20).map(|_| primary.insert(())).collect();
+    let a = SyncSecondarySlotMap::<DefaultKey, u32>::new();
+    let b = SyncSecondarySlotMap::<DefaultKey, u32>::new();
+    for (i, &key) in keys.iter().enumerate() {
+        a.insert(key, i as u32);
+    }
+    for (i, &key) in keys.iter().enumerate().rev() {
+        b.insert(key, i as u32);
+    }
+    assert_eq!(hash(&a), hash(&b));
+    b.insert(keys[0], 99);
+    assert_ne!(hash(&a), hash(&b));
+
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let (x, y) = (map.insert(3), map.insert(3));
+    assert_eq!(hash(&map.get(x).unwrap()), hash(&3));
+    let guards: HashSet<_> = [map.get(x).unwrap(), map.get(y).unwrap()]
+        .into_iter()
+        .collect();
+    assert_eq!(guards.len(), 1);
+    drop(guards);
+    assert_eq!(hash(&map.get_mut(y).unwrap()), hash(&3));
+}
+
+#[test]
+fn get_mut_or_default_under_contention() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let keys: Vec<_> = (0..4).map(|_| primary.insert(())).collect();
+    let map = Arc::new(SyncSecondarySlotMap::<DefaultKey, u64>::new());
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let (map, keys) = (map.clone(), keys.clone());
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    *map.get_mut_or_default(keys[i % 4]).unwrap() += 1;
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    for key in keys {
+        assert_eq!(*map.get(key).unwrap(), 4000);
+    }
+    assert_eq!(map.approx_len(), 4);
+
+    // The primary map can't make up a value for a key it doesn't have.
+    let map = SyncSlotMap::<DefaultKey, u64>::new();
+    let key = map.insert(1);
+    *map.get_mut_or_default(key).unwrap() += 1;
+    assert_eq!(*map.get(key).unwrap(), 2);
+    map.remove(key);
+    assert!(map.get_mut_or_default(key).is_none());
+}
+
+#[test]
+fn swap_value_never_tears() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let key = primary.insert(());
+    let missing = primary.insert(());
+    let map = Arc::new(SyncSecondarySlotMap::<DefaultKey, [u64; 8]>::new());
+    map.insert(key, [0; 8]);
+    assert_eq!(map.swap_value(missing, [1; 8]), None);
+    assert!(map.get(missing).is_none());
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let (map, stop) = (map.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let value = map.get(key).unwrap();
+                    assert!(value.iter().all(|&x| x == value[0]));
+                }
+            })
+        })
+        .collect();
+    for i in 1..2000 {
+        assert_eq!(map.swap_value(key, [i; 8]), Some([i - 1; 8]));
+    }
+    assert_eq!(
+        map.try_swap_value_for(key, [5; 8], Duration::from_millis(100)),
+        Some(Some([1999; 8]))
+    );
+    assert_eq!(block_on(map.swap_value_async(key, [6; 8])), Some([5; 8]));
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(map.approx_len(), 1);
+}
+
+#[test]
+fn extend() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let keys: Vec<_> = (0..20_000).map(|_| primary.insert(())).collect();
+    let pairs = || keys.iter().copied().zip(0..);
+
+    // Best of a few runs each, so a preempted run doesn't decide the comparison.
+    let (mut bulk, mut looped) = (Duration::MAX, Duration::MAX);
+    for _ in 0..5 {
+        let map = SyncSecondarySlotMap::<DefaultKey, usize>::new();
+        let start = Instant::now();
+        map.extend(pairs());
+        bulk = bulk.min(start.elapsed());
+        assert_eq!(map.approx_len(), keys.len());
+        assert_eq!(*map.get(keys[42]).unwrap(), 42);
+
+        let map = SyncSecondarySlotMap::<DefaultKey, usize>::new();
+        let start = Instant::now();
+        for (key, value) in pairs() {
+            map.insert(key, value);
+        }
+        looped = looped.min(start.elapsed());
+    }
+    assert!(bulk < looped, "extend {bulk:?}, inserts {looped:?}");
+
+    let mut map = SyncSecondarySlotMap::<DefaultKey, usize>::new();
+    map.extend(pairs().take(10));
+    Extend::extend(&mut map, [(keys[5], 50), (keys[10], 100)]);
+    assert_eq!(map.approx_len(), 11);
+    assert_eq!(*map.get(keys[5]).unwrap(), 50);
+    assert_eq!(*map.get(keys[10]).unwrap(), 100);
+}
+
+#[test]
+fn get_or_try_insert_with() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let key = primary.insert(());
+    let map = Arc::new(SyncSecondarySlotMap::<DefaultKey, u32>::new());
+    assert_eq!(
+        map.get_or_try_insert_with(key, || Err("no file")).err(),
+        Some("no file")
+    );
+    assert!(map.get(key).is_none());
+    assert_eq!(map.approx_len(), 0);
+
+    // Failing threads race succeeding ones; only a success may ever insert.
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            let map = map.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let value = map.get_or_try_insert_with(key, || match i % 2 {
+                        0 => Ok(0),
+                        _ => Err(()),
+                    });
+                    if let Ok(value) = value {
+                        *value.unwrap() += 1;
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let value = *map.get(key).unwrap();
+    assert!((400..=800).contains(&value), "{value}");
+    assert_eq!(
+        *map.get_or_try_insert_with(key, || Err(()))
+            .unwrap()
+            .unwrap(),
+        value
+    );
+    assert_eq!(map.approx_len(), 1);
+}
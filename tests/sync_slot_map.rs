@@ -0,0 +1,2154 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{block_on, poll_once};
+use slotmap::{DefaultKey, Key, KeyData};
+use sync_slotmap::backoff::Exponential;
+use sync_slotmap::{
+    get_entity_and_meta_mut, with_locked2, Backoff, CasError, ChangeEvent, GetResult,
+    LazySyncSlotMap, LockStats, Metrics, ReadWriteView, SlotOrigin, SyncSecondarySlotMap,
+    SyncSlotMap,
+};
+
+#[test]
+fn get_recursive_while_whole_map_writer_waits() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, i32>::new());
+    // Keys in different slots, so a plain `get` of the first would use another part of the
+    // map's lock than the guard held on the last.
+    let keys: Vec<_> = (0..8).map(|i| map.insert(i)).collect();
+    let held = map.get(keys[7]).unwrap();
+    let writer = thread::spawn({
+        let map = map.clone();
+        move || map.compact_locks()
+    });
+    thread::sleep(Duration::from_millis(100));
+    let nested = map.get_recursive(keys[0]).unwrap();
+    assert_eq!(*held + *nested, 7);
+    drop((held, nested));
+    writer.join().unwrap();
+}
+
+#[test]
+fn scans_skip_expired_entries() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let live = map.insert(1);
+    map.insert_with_ttl(2, Duration::from_millis(1));
+    thread::sleep(Duration::from_millis(10));
+
+    let mut seen = Vec::new();
+    map.for_each(|key, _| seen.push(key));
+    assert_eq!(seen, [live]);
+    assert_eq!(map.count(|_| true), 1);
+    assert_eq!(map.fold(0, |sum, _, value| sum + value), 1);
+    assert_eq!(map.snapshot(), [(live, 1)]);
+    assert_eq!(map.values_cloned(), [1]);
+    assert_eq!(map.find_key(&2), None);
+    assert_eq!(map.find_map(|_, &value| (value == 2).then_some(())), None);
+
+    let mut seen = Vec::new();
+    map.for_each_mut(|key, _| seen.push(key));
+    assert_eq!(seen, [live]);
+    assert_eq!(map.apply_all(|_, value| *value), [(live, 1)]);
+    assert_eq!(map.map_values(|value| *value).snapshot(), [(live, 1)]);
+    assert!(map.remove_where(|_, &value| value == 2).is_empty());
+    map.retain(|key, _| key != live);
+    assert!(map.get(live).is_none());
+    // The expired entry was skipped rather than removed.
+    assert_eq!(map.reap_expired(), 1);
+}
+
+#[test]
+fn map_values_keeps_live_keys() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let keys: Vec<_> = (0..6).map(|i| map.insert(i)).collect();
+    map.remove(keys[1]);
+    map.remove(keys[4]);
+    // Bump the version of a slot that's live again.
+    map.remove(keys[2]);
+    let reinserted = map.insert(20);
+
+    let mapped = map.map_values(|value| value * 10);
+    assert_eq!(mapped.approx_len(), map.approx_len());
+    for key in [keys[0], keys[3], keys[5], reinserted] {
+        assert_eq!(*mapped.get(key).unwrap(), *map.get(key).unwrap() * 10);
+    }
+    for key in [keys[1], keys[2], keys[4]] {
+        assert!(mapped.get(key).is_none());
+    }
+    // New keys never collide with the ones carried over.
+    let added = mapped.insert(7);
+    assert!(![keys[0], keys[3], keys[5], reinserted].contains(&added));
+    assert_eq!(*mapped.get(reinserted).unwrap(), 200);
+}
+
+#[test]
+fn map_values_after_churn() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let mut key = map.insert(0);
+    // One removal and insert per version bump, which is what mapping has to replay.
+    for i in 1..100_000 {
+        map.remove(key);
+        key = map.insert(i);
+    }
+    let other = map.insert(-1);
+
+    let mapped = map.map_values(|value| value + 1);
+    assert_eq!(*mapped.get(key).unwrap(), 100_000);
+    assert_eq!(*mapped.get(other).unwrap(), 0);
+    assert_eq!(mapped.approx_len(), 2);
+}
+
+#[test]
+fn drop_waits_for_guards() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let key = map.insert(1);
+    let guard = map.get(key).unwrap();
+    let reader = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(*guard, 1);
+    });
+    let start = std::time::Instant::now();
+    drop(map);
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    reader.join().unwrap();
+}
+
+#[test]
+fn drop_behaviors() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use sync_slotmap::DropBehavior;
+
+    // The guard outlives the map, its value stays readable since it's leaked.
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let guard = map.get(map.insert(7)).unwrap();
+    map.set_drop_behavior(DropBehavior::Panic);
+    map.set_drop_timeout(Duration::from_millis(20));
+    assert!(catch_unwind(AssertUnwindSafe(|| drop(map))).is_err());
+    assert_eq!(*guard, 7);
+    drop(guard);
+
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let guard = map.get(map.insert(8)).unwrap();
+    map.set_drop_behavior(DropBehavior::Warn);
+    map.set_drop_timeout(Duration::from_millis(20));
+    drop(map);
+    assert_eq!(*guard, 8);
+    drop(guard);
+
+    // Without guards the map is freed as usual.
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    map.insert(1);
+    map.set_drop_behavior(DropBehavior::Panic);
+    drop(map);
+}
+
+#[test]
+fn concurrent_inserts_removes_and_reads() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u64>::new());
+    let keys: Arc<Vec<_>> = Arc::new((0..16).map(|_| map.insert(0)).collect());
+    let threads: Vec<_> = (0..8)
+        .map(|thread| {
+            let (map, keys) = (map.clone(), keys.clone());
+            thread::spawn(move || {
+                for i in 0..5000 {
+                    let key = keys[(i * 7 + thread) % keys.len()];
+                    *map.get_mut(key).unwrap() += 1;
+                    if i % 10 == 0 {
+                        let added = map.insert(5);
+                        assert_eq!(*map.get(added).unwrap(), 5);
+                        assert_eq!(map.remove(added), Some(5));
+                    }
+                    if i % 37 == 0 {
+                        let _ = map.try_get_for(key, Duration::from_millis(1));
+                    }
+                    if i % 51 == 0 {
+                        *block_on(map.get_mut_async(key)).unwrap() += 1;
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let total: u64 = keys.iter().map(|&key| *map.get(key).unwrap()).sum();
+    let async_increments = (0..5000).filter(|i| i % 51 == 0).count() as u64;
+    assert_eq!(total, 8 * (5000 + async_increments));
+    assert_eq!(map.approx_len(), 16);
+}
+
+#[test]
+fn insert_while_guard_held() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, i32>::new());
+    let key = map.insert(1);
+    let read = map.get(key).unwrap();
+    let written = map.get_mut(map.insert(2)).unwrap();
+    let (done, finished) = mpsc::channel();
+    thread::spawn({
+        let map = map.clone();
+        move || {
+            let keys: Vec<_> = (0..1000).map(|i| map.insert(i)).collect();
+            done.send(keys).unwrap();
+        }
+    });
+    // The guards are never dropped while the inserts run.
+    let keys = finished.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(*map.get(keys[999]).unwrap(), 999);
+    assert_eq!((*read, *written), (1, 2));
+}
+
+#[test]
+fn remove_waits_for_guard() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, String>::new());
+    let key = map.insert("a".into());
+    let guard = map.get(key).unwrap();
+    let remover = thread::spawn({
+        let map = map.clone();
+        move || map.remove(key)
+    });
+    thread::sleep(Duration::from_millis(50));
+    // The key is already gone for everyone else.
+    assert!(map.get(key).is_none());
+    assert_eq!(*guard, "a");
+    drop(guard);
+    assert_eq!(remover.join().unwrap().as_deref(), Some("a"));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_for_each() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    let map = SyncSlotMap::<DefaultKey, u64>::new();
+    let keys: Vec<_> = (0..10_000).map(|i| map.insert(i)).collect();
+    map.par_for_each_mut(|_, value| *value *= 2);
+    let sum = AtomicU64::new(0);
+    map.par_for_each(|_, value| {
+        sum.fetch_add(*value, Ordering::Relaxed);
+    });
+    assert_eq!(sum.into_inner(), (0..10_000).map(|i| i * 2).sum::<u64>());
+    assert_eq!(*map.get(keys[3]).unwrap(), 6);
+}
+
+#[cfg(feature = "stream")]
+#[test]
+fn stream() {
+    use futures::StreamExt;
+
+    let map = SyncSlotMap::<DefaultKey, u64>::new();
+    let keys: Vec<_> = (0..10).map(|i| map.insert(i)).collect();
+    map.remove(keys[2]);
+    let values: Vec<u64> = block_on(map.stream().map(|guard| *guard).collect());
+    assert_eq!(values, [0, 1, 3, 4, 5, 6, 7, 8, 9]);
+
+    // Keys removed after the stream started are skipped.
+    let mut stream = Box::pin(map.stream());
+    assert_eq!(*block_on(stream.next()).unwrap(), 0);
+    map.remove(keys[1]);
+    assert_eq!(*block_on(stream.next()).unwrap(), 3);
+}
+
+#[test]
+fn change_events() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u64>::new());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    map.on_change({
+        let (events, map) = (events.clone(), Arc::downgrade(&map));
+        move |event| {
+            events.lock().unwrap().push(event);
+            // The map's locks are released by now.
+            if let (Some(map), ChangeEvent::Inserted(key)) = (map.upgrade(), event) {
+                assert!(map.get(key).is_some());
+            }
+        }
+    });
+    let key = map.insert(1);
+    map.remove(key);
+    map.remove(key);
+    assert_eq!(
+        *events.lock().unwrap(),
+        [ChangeEvent::Inserted(key), ChangeEvent::Removed(key)]
+    );
+}
+
+#[test]
+fn swap() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, i32>::new());
+    let (a, b) = (map.insert(1), map.insert(2));
+    assert!(!map.swap(a, a));
+    // Swapping in opposite orders from several threads doesn't deadlock.
+    let threads: Vec<_> = (0..4)
+        .map(|thread| {
+            let map = map.clone();
+            thread::spawn(move || {
+                for _ in 0..10_000 {
+                    if thread % 2 == 0 {
+                        assert!(map.swap(a, b));
+                    } else {
+                        assert!(map.swap(b, a));
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!((*map.get(a).unwrap(), *map.get(b).unwrap()), (1, 2));
+    assert!(map.swap(a, b));
+    assert_eq!((*map.get(a).unwrap(), *map.get(b).unwrap()), (2, 1));
+    map.remove(b);
+    assert!(!map.swap(a, b));
+}
+
+#[test]
+fn replace() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let key = map.insert(1);
+    assert_eq!(map.replace(key, 2), Some(1));
+    let guard = map.get(key).unwrap();
+    assert!(map
+        .try_replace_for(key, 5, Duration::from_millis(20))
+        .is_none());
+    drop(guard);
+    assert_eq!(block_on(map.replace_async(key, 3)), Some(2));
+    assert_eq!(*map.get(key).unwrap(), 3);
+    map.remove(key);
+    assert_eq!(map.replace(key, 4), None);
+    assert!(map.get(key).is_none());
+}
+
+#[test]
+fn is_locked() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let key = map.insert(1);
+    assert_eq!(map.is_locked(key), Some(false));
+    let guard = map.get(key).unwrap();
+    assert_eq!(map.is_locked(key), Some(true));
+    assert_eq!(map.is_locked_exclusive(key), Some(false));
+    drop(guard);
+    let guard = map.get_mut(key).unwrap();
+    assert_eq!(map.is_locked(key), Some(true));
+    assert_eq!(map.is_locked_exclusive(key), Some(true));
+    drop(guard);
+    assert_eq!(map.is_locked_exclusive(key), Some(false));
+    map.remove(key);
+    assert_eq!(map.is_locked(key), None);
+    assert_eq!(map.is_locked_exclusive(key), None);
+}
+
+#[test]
+fn try_lock_all_for() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let keys: Vec<_> = (0..50).map(|i| map.insert(i)).collect();
+    let all = map.try_lock_all_for(Duration::from_millis(50)).unwrap();
+    assert_eq!(all.len(), 50);
+    assert!(all.iter().zip(0..).all(|((_, guard), i)| **guard == i));
+    assert!(map
+        .try_get_mut_for(keys[3], Duration::from_millis(10))
+        .is_none());
+    drop(all);
+
+    let held = map.get_mut(keys[25]).unwrap();
+    assert!(map.try_lock_all_for(Duration::from_millis(20)).is_none());
+    // Everything taken before the timeout was released again.
+    for &key in &keys {
+        if key != keys[25] {
+            assert_eq!(map.is_locked(key), Some(false));
+        }
+    }
+    drop(held);
+    assert!(map.try_lock_all_for(Duration::from_millis(20)).is_some());
+}
+
+#[test]
+fn get_key_value() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let key = map.insert(1);
+    let (found, guard) = map.get_key_value(key).unwrap();
+    assert_eq!((found, *guard), (key, 1));
+    drop(guard);
+    let (found, mut guard) = map.get_key_value_mut(key).unwrap();
+    assert_eq!(found, key);
+    *guard = 4;
+    drop(guard);
+    assert_eq!(*map.get(key).unwrap(), 4);
+    map.remove(key);
+    assert!(map.get_key_value(key).is_none());
+    assert!(map.get_key_value_mut(key).is_none());
+}
+
+#[test]
+fn contains_value_and_find_key() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let first = map.insert(1);
+    let twos: Vec<_> = (0..3).map(|_| map.insert(2)).collect();
+    assert!(map.contains_value(&1));
+    assert!(!map.contains_value(&3));
+    assert_eq!(map.find_key(&1), Some(first));
+    // Duplicates give the first match in slot order.
+    assert_eq!(map.find_key(&2), Some(twos[0]));
+    map.remove(twos[0]);
+    assert_eq!(map.find_key(&2), Some(twos[1]));
+    assert_eq!(map.find_key(&3), None);
+}
+
+#[test]
+fn for_each() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let keys: Vec<_> = (0..10).map(|i| map.insert(i)).collect();
+    map.for_each_mut(|_, value| *value *= 2);
+    let mut seen = Vec::new();
+    map.for_each(|key, value| seen.push((key, *value)));
+    assert_eq!(
+        seen,
+        keys.iter()
+            .zip(0..)
+            .map(|(&key, i)| (key, i * 2))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn count_and_fold() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let keys: Vec<_> = (0..10).map(|i| map.insert(i)).collect();
+    assert_eq!(map.count(|value| value % 2 == 0), 5);
+    assert_eq!(map.fold(0, |sum, _, value| sum + value), 45);
+    let max_key = map.fold(None, |max: Option<(DefaultKey, i32)>, key, &value| {
+        Some(max.filter(|&(_, max)| max > value).unwrap_or((key, value)))
+    });
+    assert_eq!(max_key, Some((keys[9], 9)));
+}
+
+#[test]
+fn into_values_and_into_keys() {
+    let map = SyncSlotMap::<DefaultKey, String>::new();
+    let keys: Vec<_> = (0..10).map(|i| map.insert(i.to_string())).collect();
+    map.remove(keys[3]);
+    let mut values: Vec<_> = map.into_values().collect();
+    values.sort();
+    assert_eq!(values, ["0", "1", "2", "4", "5", "6", "7", "8", "9"]);
+
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let keys: Vec<_> = (0..10).map(|i| map.insert(i)).collect();
+    assert_eq!(map.into_keys().collect::<Vec<_>>(), keys);
+}
+
+#[test]
+fn snapshot() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let key = map.insert(1);
+    let snapshot = map.snapshot();
+    *map.get_mut(key).unwrap() = 5;
+    map.insert(2);
+    assert_eq!(snapshot, [(key, 1)]);
+    assert_eq!(map.snapshot().len(), 2);
+}
+
+#[test]
+fn release() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let key = map.insert(1);
+    map.get(key).unwrap().release();
+    assert_eq!(map.is_locked(key), Some(false));
+    map.get_mut(key).unwrap().release();
+    assert_eq!(map.is_locked(key), Some(false));
+
+    let read = map.get_mut(key).unwrap().release_and_get();
+    assert_eq!(map.is_locked_exclusive(key), Some(false));
+    assert_eq!(*map.get(key).unwrap(), 1);
+    assert!(map
+        .try_get_mut_for(key, Duration::from_millis(10))
+        .is_none());
+    read.release();
+    assert_eq!(map.is_locked(key), Some(false));
+}
+
+#[test]
+fn mapped_guards() {
+    let map = SyncSlotMap::<DefaultKey, (i32, String)>::new();
+    let key = map.insert((1, "a".into()));
+    let name = map.get(key).unwrap().map(|value| &value.1);
+    assert_eq!(*name, "a");
+    assert!(map
+        .try_get_mut_for(key, Duration::from_millis(10))
+        .is_none());
+    drop(name);
+
+    let mut count = map.get_mut(key).unwrap().map(|value| &mut value.0);
+    *count += 1;
+    assert!(map.try_get_for(key, Duration::from_millis(10)).is_none());
+    drop(count);
+    assert_eq!(map.is_locked(key), Some(false));
+    assert_eq!(map.get(key).unwrap().0, 2);
+}
+
+#[test]
+fn leak_and_unlock_raw() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let key = map.insert(1);
+    map.get_mut(key).unwrap().leak();
+    assert_eq!(map.is_locked_exclusive(key), Some(true));
+    assert!(map.try_get_for(key, Duration::from_millis(10)).is_none());
+    unsafe { map.unlock_exclusive_raw(key) };
+    assert_eq!(map.is_locked(key), Some(false));
+    assert_eq!(*map.get(key).unwrap(), 1);
+
+    // Guards locking several keys, or one that was just inserted, leak the same way.
+    let (inserted, guard) = map.insert_and_get(2);
+    guard.leak();
+    let other = map.insert(3);
+    for guard in map.get_disjoint_mut_many(&[key, other]).unwrap() {
+        guard.leak();
+    }
+    for key in [inserted, key, other] {
+        unsafe { map.unlock_exclusive_raw(key) };
+    }
+    let removed = map.try_remove_where_for(|_, _| true, Duration::from_secs(5));
+    assert_eq!(removed.map(|removed| removed.len()), Some(3));
+}
+
+#[test]
+fn first_and_last_key_value() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    assert!(map.first_key_value().is_none());
+    assert!(map.last_key_value().is_none());
+    let keys: Vec<_> = (0..5).map(|i| map.insert(i)).collect();
+    let (key, guard) = map.first_key_value().unwrap();
+    assert_eq!((key, *guard), (keys[0], 0));
+    drop(guard);
+    let (key, guard) = map.last_key_value().unwrap();
+    assert_eq!((key, *guard), (keys[4], 4));
+    drop(guard);
+    map.remove(keys[0]);
+    assert_eq!(map.first_key_value().unwrap().0, keys[1]);
+}
+
+#[test]
+fn lock_stats() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, i32>::new());
+    let keys: Vec<_> = (0..5).map(|i| map.insert(i)).collect();
+    assert_eq!(map.lock_stats(), LockStats::default());
+    let read = map.get(keys[0]).unwrap();
+    let written = map.get_mut(keys[3]).unwrap();
+    assert_eq!(map.lock_stats().locked_keys, 2);
+
+    let writer = thread::spawn({
+        let map = map.clone();
+        move || map.compact_locks()
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        map.lock_stats(),
+        LockStats {
+            global_is_writer_waiting: true,
+            locked_keys: 2,
+        }
+    );
+    drop((read, written));
+    writer.join().unwrap();
+    assert_eq!(map.lock_stats(), LockStats::default());
+}
+
+#[test]
+fn compare_and_set() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, i32>::new());
+    let key = map.insert(1);
+    assert_eq!(map.compare_and_set(key, &1, 2), Ok(()));
+    assert_eq!(map.compare_and_set(key, &1, 3), Err(CasError::Mismatch(3)));
+    assert_eq!(*map.get(key).unwrap(), 2);
+
+    // Concurrent increments through compare_and_set don't lose updates.
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let map = map.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    loop {
+                        let current = *map.get(key).unwrap();
+                        if map.compare_and_set(key, &current, current + 1).is_ok() {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*map.get(key).unwrap(), 4002);
+
+    map.remove(key);
+    assert_eq!(map.compare_and_set(key, &2, 4), Err(CasError::NotFound(4)));
+}
+
+#[test]
+fn entries_expire() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let short = map.insert_with_ttl(1, Duration::from_millis(30));
+    let long = map.insert_with_ttl(2, Duration::from_secs(60));
+    let forever = map.insert(3);
+    assert_eq!(*map.get(short).unwrap(), 1);
+    thread::sleep(Duration::from_millis(50));
+    assert!(map.get(short).is_none());
+    assert!(map.get_mut(short).is_none());
+    assert!(!map.swap(short, forever));
+    assert_eq!(map.first_key_value().unwrap().0, long);
+    assert_eq!(map.reap_expired(), 1);
+    assert_eq!(map.reap_expired(), 0);
+    assert_eq!(map.remove(short), None);
+    assert_eq!(*map.get(long).unwrap(), 2);
+}
+
+#[test]
+fn guards_as_ref_and_borrow() {
+    fn len(value: impl AsRef<String>) -> usize {
+        value.as_ref().len()
+    }
+    fn push(mut value: impl AsMut<String>) {
+        value.as_mut().push('c')
+    }
+    fn borrowed_len(value: impl Borrow<String>) -> usize {
+        value.borrow().len()
+    }
+
+    let map = SyncSlotMap::<DefaultKey, String>::new();
+    let key = map.insert("ab".into());
+    push(map.get_mut(key).unwrap());
+    assert_eq!(len(map.get(key).unwrap()), 3);
+    assert_eq!(len(map.get_mut(key).unwrap()), 3);
+    assert_eq!(borrowed_len(map.get(key).unwrap()), 3);
+    assert_eq!(borrowed_len(map.get_mut(key).unwrap()), 3);
+}
+
+#[test]
+fn cancelled_async_calls_release_their_locks() {
+    let map = SyncSlotMap::<DefaultKey, String>::new();
+    let key = map.insert("a".into());
+    let guard = map.get_mut(key).unwrap();
+    assert!(!poll_once(map.get_async(key)));
+    assert!(!poll_once(map.get_mut_async(key)));
+    assert!(!poll_once(map.replace_async(key, "b".into())));
+    drop(guard);
+    assert_eq!(map.is_locked(key), Some(false));
+    assert!(map.try_get_mut_for(key, Duration::ZERO).unwrap().is_some());
+
+    // A removal cancelled while waiting out a guard has already taken the entry out.
+    let guard = map.get(key).unwrap();
+    assert!(!poll_once(map.remove_async(key)));
+    assert!(map.get(key).is_none());
+    assert_eq!(*guard, "a");
+    drop(guard);
+    assert_eq!(*map.get(map.insert("c".into())).unwrap(), "c");
+}
+
+#[test]
+fn get_disjoint_mut_many() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, i64>::new());
+    let keys: Arc<Vec<_>> = Arc::new((0..8).map(|_| map.insert(0)).collect());
+    assert!(map
+        .get_disjoint_mut_many(&[keys[0], keys[1], keys[0]])
+        .is_none());
+    let removed = map.insert(0);
+    map.remove(removed);
+    assert!(map.get_disjoint_mut_many(&[keys[0], removed]).is_none());
+    assert_eq!(map.is_locked(keys[0]), Some(false));
+
+    let guards = map.get_disjoint_mut_many(&[keys[2], keys[1]]).unwrap();
+    assert_eq!(guards.len(), 2);
+    assert_eq!(map.is_locked_exclusive(keys[1]), Some(true));
+    drop(guards);
+
+    // Overlapping sets of five keys, picked in different orders on each thread.
+    let threads: Vec<_> = (0..6)
+        .map(|thread| {
+            let (map, keys) = (map.clone(), keys.clone());
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    let start = i * (thread + 1) + thread;
+                    let picked: Vec<_> = (0..5).map(|j| keys[(start + j * 3) % 8]).collect();
+                    for mut guard in map.get_disjoint_mut_many(&picked).unwrap() {
+                        *guard += 1;
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let total: i64 = keys.iter().map(|&key| *map.get(key).unwrap()).sum();
+    assert_eq!(total, 6 * 2000 * 5);
+}
+
+#[test]
+fn approx_len_after_mixed_workload() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+    let threads: Vec<_> = (0..4)
+        .map(|thread| {
+            let map = map.clone();
+            thread::spawn(move || {
+                let mut keys = Vec::new();
+                for i in 0..1000 {
+                    keys.push(map.insert(i));
+                    if i % 3 == thread {
+                        let key = keys.pop().unwrap();
+                        map.remove(key);
+                        // Removing it again doesn't count twice.
+                        map.remove(key);
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(map.approx_len(), map.iter().count());
+    assert_eq!(map.approx_len(), 3000);
+}
+
+#[test]
+fn shrink_to_fit() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..1000)
+        .map(|i| map.insert_with_ttl(i, Duration::from_secs(100)))
+        .collect();
+    for &key in &keys[1..] {
+        map.remove(key);
+    }
+    map.shrink_to_fit();
+    assert_eq!(*map.get(keys[0]).unwrap(), 0);
+    assert!(map.get(keys[1]).is_none());
+    let key = map.insert(5);
+    assert_eq!(*map.get_mut(key).unwrap(), 5);
+}
+
+#[test]
+fn get_cloned() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, String>::new());
+    let key = map.insert("a".into());
+    let value = map.get_cloned(key).unwrap();
+    // Nothing is left locked, so a writer gets in straight away.
+    assert!(map.try_get_mut_for(key, Duration::ZERO).unwrap().is_some());
+    assert_eq!(value, "a");
+    assert_eq!(
+        map.try_get_cloned_for(key, Duration::ZERO),
+        Some(Some("a".to_string()))
+    );
+    let guard = map.get_mut(key).unwrap();
+    assert_eq!(map.try_get_cloned_for(key, Duration::from_millis(10)), None);
+    drop(guard);
+    map.remove(key);
+    assert_eq!(map.get_cloned(key), None);
+    assert_eq!(map.try_get_cloned_for(key, Duration::ZERO), Some(None));
+}
+
+#[test]
+fn equality() {
+    let a = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..5).map(|i| a.insert(i)).collect();
+    let b = a.map_values(|value| *value);
+    assert!(a == b && a == a);
+    *b.get_mut(keys[1]).unwrap() = 9;
+    assert!(a != b);
+    *b.get_mut(keys[1]).unwrap() = 1;
+    // The same values under different keys aren't equal.
+    let value = b.remove(keys[4]).unwrap();
+    b.insert(value);
+    assert!(a != b);
+}
+
+#[test]
+fn iterate_by_reference() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let keys: Vec<_> = (0..5).map(|i| map.insert(i)).collect();
+    let mut seen = Vec::new();
+    for (key, value) in &map {
+        // Only the current entry is held, the map can still change around it.
+        if key == keys[0] {
+            map.remove(keys[1]);
+            map.insert(10);
+        }
+        assert_eq!(map.is_locked(key), Some(true));
+        seen.push((key, *value));
+    }
+    assert_eq!(
+        seen,
+        [(keys[0], 0), (keys[2], 2), (keys[3], 3), (keys[4], 4)]
+    );
+}
+
+#[test]
+fn find_map_short_circuits() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..10).map(|i| map.insert(i)).collect();
+    let mut calls = 0;
+    let found = map.find_map(|key, &value| {
+        calls += 1;
+        (value == 3).then_some(key)
+    });
+    assert_eq!(found, Some(keys[3]));
+    assert_eq!(calls, 4);
+    assert_eq!(map.find_map(|_, &value| (value > 100).then_some(())), None);
+}
+
+#[test]
+fn sorted_snapshot_by_key() {
+    let map = SyncSlotMap::<DefaultKey, (u32, &str)>::new();
+    for entry in [(5, "a"), (1, "b"), (9, "c"), (1, "d")] {
+        map.insert(entry);
+    }
+    let names: Vec<_> = map
+        .sorted_snapshot_by_key(|&(score, _)| Reverse(score))
+        .into_iter()
+        .map(|(_, (_, name))| name)
+        .collect();
+    // Ties keep their slot order.
+    assert_eq!(names, ["c", "a", "b", "d"]);
+    let unsorted: Vec<_> = map
+        .snapshot()
+        .into_iter()
+        .map(|(_, (_, name))| name)
+        .collect();
+    assert_eq!(unsorted, ["a", "b", "c", "d"]);
+}
+
+#[test]
+fn remove_many() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..6).map(|i| map.insert(i)).collect();
+    map.remove(keys[2]);
+    let removed = map.remove_many(&[keys[0], keys[2], keys[4], keys[0]]);
+    assert_eq!(removed, [Some(0), None, Some(4), None]);
+    assert_eq!(map.approx_len(), 3);
+    assert_eq!(map.is_locked(keys[0]), None);
+    assert_eq!(map.is_locked(keys[4]), None);
+
+    let guard = map.get(keys[1]).unwrap();
+    assert_eq!(
+        map.try_remove_many_for(&[keys[1]], Duration::from_millis(10)),
+        None
+    );
+    drop(guard);
+    assert_eq!(
+        map.try_remove_many_for(&[keys[1], keys[2]], Duration::from_millis(10)),
+        Some(vec![Some(1), None])
+    );
+}
+
+#[test]
+fn retain_async_between_chunks() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..300).map(|i| map.insert(i)).collect();
+    let mut retain = pin!(map.retain_async(|_, value| *value % 2 == 0));
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut polls = 0;
+    while retain.as_mut().poll(&mut cx).is_pending() {
+        // The map is usable while it yields.
+        map.insert(1001);
+        map.remove(keys[299 - polls]);
+        polls += 1;
+    }
+    assert!(polls >= 2);
+    // Entries inserted in between are kept, whatever `f` would say.
+    assert_eq!(map.count(|&value| value == 1001), polls);
+    assert_eq!(map.count(|&value| value % 2 == 1 && value < 1000), 0);
+    // Of the removed keys, every other one held an even value.
+    assert_eq!(map.approx_len(), polls + 150 - polls / 2);
+}
+
+#[test]
+fn remove_where() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..10).map(|i| map.insert(i)).collect();
+    let removed = map.remove_where(|_, value| value % 3 == 0);
+    assert_eq!(
+        removed,
+        [(keys[0], 0), (keys[3], 3), (keys[6], 6), (keys[9], 9)]
+    );
+    assert_eq!(map.count(|value| value % 3 == 0), 0);
+    assert_eq!(map.approx_len(), 6);
+
+    let guard = map.get(keys[1]).unwrap();
+    assert!(map
+        .try_remove_where_for(|_, _| true, Duration::from_millis(10))
+        .is_none());
+    drop(guard);
+    assert_eq!(
+        map.try_remove_where_for(|_, &value| value == 1, Duration::from_millis(10)),
+        Some(vec![(keys[1], 1)])
+    );
+    assert_eq!(map.approx_len(), 5);
+}
+
+// Has this thread write-lock `key` 100 times while another keeps write-locking it too, and
+// counts how often this one got it back straight after releasing it, even though the other
+// thread was queued up for it in the meantime.
+fn times_barged_past_writer(map: &Arc<SyncSlotMap<DefaultKey, u64>>, key: DefaultKey) -> usize {
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = thread::spawn({
+        let (map, stop) = (map.clone(), stop.clone());
+        move || {
+            while !stop.load(Ordering::Relaxed) {
+                *map.get_mut(key).unwrap() += 1;
+            }
+        }
+    });
+    let mut released_at = None;
+    let barged = (0..100)
+        .filter(|_| {
+            let guard = map.get_mut(key).unwrap();
+            thread::sleep(Duration::from_micros(50));
+            let barged = released_at == Some(*guard);
+            released_at = Some(*guard);
+            barged
+        })
+        .count();
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+    barged
+}
+
+#[test]
+fn builder() {
+    let map = Arc::new(
+        SyncSlotMap::<DefaultKey, u64>::builder()
+            .capacity(16)
+            .fair(true)
+            .default_timeout(Duration::from_millis(20))
+            .build(),
+    );
+    assert!(map.capacity() >= 16);
+    let key = map.insert(0);
+    let unfair = Arc::new(SyncSlotMap::<DefaultKey, u64>::new());
+    let unfair_key = unfair.insert(0);
+    let barged = times_barged_past_writer(&map, key);
+    let unfair_barged = times_barged_past_writer(&unfair, unfair_key);
+    // Only parking_lot hands locks over, the other backends' fair unlocks are plain unlocks.
+    #[cfg(not(any(feature = "std-locks", feature = "spin-locks")))]
+    assert!(barged * 4 < unfair_barged, "{barged} vs {unfair_barged}");
+    #[cfg(any(feature = "std-locks", feature = "spin-locks"))]
+    let _ = (barged, unfair_barged);
+
+    let guard = map.get_mut(key).unwrap();
+    let start = Instant::now();
+    assert!(map.try_get(key).is_none());
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    drop(guard);
+    assert!(map.try_get_mut(key).unwrap().is_some());
+
+    // Without a default timeout they don't wait.
+    let map = SyncSlotMap::<DefaultKey, u64>::builder().build();
+    let key = map.insert(1);
+    let _guard = map.get(key).unwrap();
+    assert!(map.try_get_mut(key).is_none());
+}
+
+#[test]
+fn set_fair() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u64>::new());
+    let key = map.insert(0);
+    let unfair = times_barged_past_writer(&map, key);
+    map.set_fair(true);
+    let fair = times_barged_past_writer(&map, key);
+    map.set_fair(false);
+    let unfair_again = times_barged_past_writer(&map, key);
+    // Only parking_lot hands locks over, the other backends' fair unlocks are plain unlocks.
+    #[cfg(not(any(feature = "std-locks", feature = "spin-locks")))]
+    assert!(
+        fair * 4 < unfair.min(unfair_again),
+        "{unfair} then {fair} then {unfair_again}"
+    );
+    #[cfg(any(feature = "std-locks", feature = "spin-locks"))]
+    let _ = (unfair, fair, unfair_again);
+}
+
+#[test]
+fn clone_guard_while_writer_waits() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, i32>::new());
+    let key = map.insert(1);
+    let guard = map.get(key).unwrap();
+    let writer = thread::spawn({
+        let map = map.clone();
+        move || *map.get_mut(key).unwrap() += 1
+    });
+    thread::sleep(Duration::from_millis(100));
+    let cloned = guard.clone();
+    assert_eq!(*guard + *cloned, 2);
+    drop((guard, cloned));
+    writer.join().unwrap();
+    assert_eq!(*map.get(key).unwrap(), 2);
+}
+
+static SHARED: LazySyncSlotMap<DefaultKey, u32> = LazySyncSlotMap::new();
+
+#[test]
+fn static_map_across_threads() {
+    let threads: Vec<_> = (0..4)
+        .map(|i| thread::spawn(move || SHARED.insert(i)))
+        .collect();
+    let keys: Vec<_> = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect();
+    let mut values: Vec<_> = keys.iter().map(|&key| *SHARED.get(key).unwrap()).collect();
+    values.sort();
+    assert_eq!(values, [0, 1, 2, 3]);
+}
+
+#[test]
+fn async_spins_before_yielding() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+    assert_eq!(map.spin_count(), 0);
+    let key = map.insert(1);
+    let guard = map.get_mut(key).unwrap();
+    assert!(!poll_once(map.get_async(key)));
+
+    map.set_spin_count(u32::MAX);
+    assert_eq!(map.spin_count(), u32::MAX);
+    let (started, start) = mpsc::channel();
+    let holder = thread::spawn(move || {
+        start.recv().unwrap();
+        thread::sleep(Duration::from_millis(2));
+        drop(guard);
+    });
+    started.send(()).unwrap();
+    // The lock is released while the first poll is still spinning.
+    assert!(poll_once(map.get_async(key)));
+    holder.join().unwrap();
+}
+
+#[test]
+fn sort_guards_by_value() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = [5, 1, 4, 2, 3].into_iter().map(|v| map.insert(v)).collect();
+    let mut guards: Vec<_> = map.iter().map(|(_, guard)| guard).collect();
+    guards.sort();
+    assert_eq!(
+        guards.iter().map(|g| **g).collect::<Vec<_>>(),
+        [1, 2, 3, 4, 5]
+    );
+    let set: BTreeSet<_> = guards.into_iter().collect();
+    assert_eq!(set.len(), 5);
+    assert_eq!(**set.first().unwrap(), 1);
+    drop(set);
+
+    let mut guards: Vec<_> = keys.iter().map(|&key| map.get_mut(key).unwrap()).collect();
+    guards.sort_by(|a, b| b.cmp(a));
+    assert_eq!(
+        guards.iter().map(|g| **g).collect::<Vec<_>>(),
+        [5, 4, 3, 2, 1]
+    );
+    assert!(guards[0] > guards[1] && guards[0] == guards[0]);
+}
+
+#[test]
+fn get_by_converts_keys() {
+    slotmap::new_key_type! { struct ThingKey; }
+    struct Handle(ThingKey);
+    impl From<Handle> for ThingKey {
+        fn from(handle: Handle) -> Self {
+            handle.0
+        }
+    }
+
+    let map = SyncSlotMap::<ThingKey, u32>::new();
+    let key = map.insert(1);
+    *map.get_mut_by(Handle(key)).unwrap() += 1;
+    assert_eq!(*map.get_by(Handle(key)).unwrap(), 2);
+    assert_eq!(*map.get_by(key).unwrap(), 2);
+    map.remove(key);
+    assert!(map.get_by(Handle(key)).is_none());
+    assert!(map.get_mut_by(Handle(key)).is_none());
+}
+
+#[test]
+fn get2_mut() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let a = map.insert(1);
+    let b = map.insert(2);
+    {
+        let (a, b) = map.get2_mut(a, b);
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        std::mem::swap(&mut *a, &mut *b);
+    }
+    assert_eq!((*map.get(a).unwrap(), *map.get(b).unwrap()), (2, 1));
+    map.remove(b);
+    let (guard_a, guard_b) = map.get2_mut(a, b);
+    assert!(guard_a.is_some() && guard_b.is_none());
+    drop(guard_a);
+    let (guard_b, guard_a) = map.get2_mut(b, a);
+    assert!(guard_b.is_none() && guard_a.is_some());
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "same key twice")]
+fn get2_mut_same_key() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let key = map.insert(1);
+    let _ = map.get2_mut(key, key);
+}
+
+#[test]
+fn apply_all() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (1..=5).map(|v| map.insert(v)).collect();
+    map.remove(keys[2]);
+    let expired = map.apply_all(|_, ttl| {
+        *ttl -= 1;
+        *ttl == 0
+    });
+    assert_eq!(
+        expired,
+        [
+            (keys[0], true),
+            (keys[1], false),
+            (keys[3], false),
+            (keys[4], false)
+        ]
+    );
+    assert_eq!(*map.get(keys[4]).unwrap(), 4);
+    let order: Vec<_> = map.iter().map(|(key, _)| key).collect();
+    assert_eq!(
+        map.apply_all(|key, _| key),
+        order.iter().map(|&key| (key, key)).collect::<Vec<_>>()
+    );
+}
+
+#[cfg(feature = "tokio-util")]
+#[test]
+fn get_async_cancellable() {
+    use std::sync::atomic::AtomicUsize;
+    use std::task::{Poll, Wake};
+
+    use tokio_util::sync::CancellationToken;
+
+    struct CountWakes(AtomicUsize);
+
+    impl Wake for CountWakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let wakes = Arc::new(CountWakes(AtomicUsize::new(0)));
+    let waker = Waker::from(wakes.clone());
+    let mut cx = Context::from_waker(&waker);
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let key = map.insert(1);
+    let token = CancellationToken::new();
+    let writer = map.get_mut(key).unwrap();
+    {
+        let mut get = pin!(map.get_async_cancellable(key, &token));
+        assert!(get.as_mut().poll(&mut cx).is_pending());
+        let before = wakes.0.load(Ordering::SeqCst);
+        token.cancel();
+        assert!(wakes.0.load(Ordering::SeqCst) > before);
+        assert!(matches!(get.as_mut().poll(&mut cx), Poll::Ready(None)));
+    }
+    drop(writer);
+    assert!(map
+        .try_get_mut_for(key, Duration::from_millis(10))
+        .unwrap()
+        .is_some());
+
+    // An already cancelled token doesn't lock anything either.
+    let mut get = pin!(map.get_async_cancellable(key, &token));
+    assert!(matches!(get.as_mut().poll(&mut cx), Poll::Ready(None)));
+    assert_eq!(map.is_locked(key), Some(false));
+}
+
+#[test]
+fn force_unlock() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+    let stuck = map.insert(0);
+    let key = map.insert(1);
+    // A whole-map operation that never finishes, though it doesn't touch `key`.
+    let (locked, is_locked) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    thread::spawn({
+        let map = map.clone();
+        move || {
+            map.for_each_mut(|key, _| {
+                if key == stuck {
+                    locked.send(()).unwrap();
+                    released.recv().unwrap();
+                }
+            })
+        }
+    });
+    is_locked.recv().unwrap();
+    std::mem::forget(release);
+    assert!(map.try_get_for(key, Duration::from_millis(20)).is_none());
+    unsafe { map.force_unlock_global() };
+    assert_eq!(*map.get(key).unwrap(), 1);
+
+    std::mem::forget(map.get_mut(key).unwrap());
+    assert!(map.try_get_for(key, Duration::from_millis(20)).is_none());
+    unsafe { map.force_unlock_key(key, true) };
+    std::mem::forget(map.get(key).unwrap());
+    assert!(map
+        .try_get_mut_for(key, Duration::from_millis(20))
+        .is_none());
+    unsafe { map.force_unlock_key(key, false) };
+    *map.get_mut(key).unwrap() = 5;
+    assert_eq!(map.remove(key), Some(5));
+}
+
+#[test]
+fn iter_filter_releases_skipped_entries() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..10).map(|v| map.insert(v)).collect();
+    let dirty: Vec<_> = map.iter_filter(|v| v % 2 == 0).collect();
+    assert_eq!(
+        dirty.iter().map(|(_, guard)| **guard).collect::<Vec<_>>(),
+        [0, 2, 4, 6, 8]
+    );
+    for (i, &key) in keys.iter().enumerate() {
+        assert_eq!(map.is_locked(key), Some(i % 2 == 0));
+    }
+    assert!(map.get_mut(keys[1]).is_some());
+    drop(dirty);
+    assert!(keys.iter().all(|&key| map.is_locked(key) == Some(false)));
+}
+
+#[test]
+fn project_into_secondary() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..10).map(|v| map.insert(v)).collect();
+    map.remove(keys[3]);
+    let tens = map.project_into_secondary(|v| v * 10);
+    assert_eq!(tens.approx_len(), 9);
+    for (i, &key) in keys.iter().enumerate() {
+        match i {
+            3 => assert!(tens.get(key).is_none()),
+            _ => assert_eq!(*tens.get(key).unwrap(), i as u32 * 10),
+        }
+    }
+}
+
+#[test]
+fn peak_len_after_spike() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..100).map(|v| map.insert(v)).collect();
+    for &key in &keys[10..] {
+        map.remove(key);
+    }
+    assert_eq!(map.approx_len(), 10);
+    assert_eq!(map.peak_len(), 100);
+    map.reset_peak();
+    assert_eq!(map.peak_len(), 10);
+    map.insert(1);
+    assert_eq!(map.peak_len(), 11);
+}
+
+#[test]
+fn key_timeout_overrides_default() {
+    let map = SyncSlotMap::<DefaultKey, u32>::builder()
+        .default_timeout(Duration::from_millis(10))
+        .build();
+    let a = map.insert(1);
+    let b = map.insert(2);
+    assert!(map.set_key_timeout(b, Duration::from_millis(150)));
+    let writers = (map.get_mut(a).unwrap(), map.get_mut(b).unwrap());
+    let start = Instant::now();
+    assert!(map.try_get(a).is_none());
+    assert!(start.elapsed() < Duration::from_millis(100));
+    let start = Instant::now();
+    assert!(map.try_get_mut(b).is_none());
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    drop(writers);
+
+    assert_eq!(map.clear_key_timeout(b), Some(Duration::from_millis(150)));
+    let writer = map.get_mut(b).unwrap();
+    let start = Instant::now();
+    assert!(map.try_get(b).is_none());
+    assert!(start.elapsed() < Duration::from_millis(100));
+    drop(writer);
+    map.remove(a);
+    assert!(!map.set_key_timeout(a, Duration::from_secs(1)));
+}
+
+#[test]
+fn scan_then_modify_claims_once() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, Option<usize>>::new());
+    for _ in 0..50 {
+        map.insert(None);
+    }
+    let claimers: Vec<_> = (0..8)
+        .map(|claimer| {
+            let map = map.clone();
+            thread::spawn(move || {
+                let mut claimed = 0;
+                while let Some(previous) =
+                    map.scan_then_modify(|_, slot| slot.is_none(), |slot| slot.replace(claimer))
+                {
+                    assert_eq!(previous, None);
+                    claimed += 1;
+                }
+                claimed
+            })
+        })
+        .collect();
+    let claimed: usize = claimers.into_iter().map(|c| c.join().unwrap()).sum();
+    assert_eq!(claimed, 50);
+    assert_eq!(map.count(|slot| slot.is_some()), 50);
+}
+
+#[test]
+fn define_sync_slotmap() {
+    sync_slotmap::define_sync_slotmap! {
+        struct PlayerKey;
+        type Players = &'static str;
+
+        /// Scores, keyed separately from players.
+        pub(crate) struct ScoreKey;
+        pub(crate) type Scores = u32;
+    }
+
+    let players = Players::new();
+    let scores = Scores::new();
+    let ferris: PlayerKey = players.insert("ferris");
+    let score: ScoreKey = scores.insert(3);
+    assert_eq!(*players.get(ferris).unwrap(), "ferris");
+    *scores.get_mut(score).unwrap() += 1;
+    assert_eq!(scores.remove(score), Some(4));
+    assert_ne!(PlayerKey::default(), ferris);
+}
+
+#[test]
+fn get_all_mut_where() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..20).map(|i| map.insert(i)).collect();
+    let pending = map.get_all_mut_where(|v| v % 3 == 0);
+    assert_eq!(
+        pending.iter().map(|(key, _)| *key).collect::<Vec<_>>(),
+        keys.iter().copied().step_by(3).collect::<Vec<_>>()
+    );
+    for (i, &key) in keys.iter().enumerate() {
+        assert_eq!(map.is_locked_exclusive(key), Some(i % 3 == 0));
+    }
+    for (_, mut job) in pending {
+        *job += 100;
+    }
+    assert_eq!(map.count(|v| *v >= 100), 7);
+    assert!(map.get_all_mut_where(|v| *v > 1000).is_empty());
+}
+
+#[test]
+fn insert_and_get_is_exclusive() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+    let (key, mut guard) = map.insert_and_get(1);
+    let remover = {
+        let map = map.clone();
+        thread::spawn(move || map.remove(key))
+    };
+    thread::sleep(Duration::from_millis(50));
+    // The remover has already unlinked the entry and is only waiting to take the value.
+    assert!(matches!(
+        map.try_get_for(key, Duration::from_millis(10)),
+        Some(None)
+    ));
+    assert_eq!(map.is_locked_exclusive(key), None);
+    assert!(!remover.is_finished());
+    *guard = 2;
+    drop(guard);
+    assert_eq!(remover.join().unwrap(), Some(2));
+}
+
+#[test]
+fn remove_with_runs_once() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+    let keys: Vec<_> = (0..100).map(|i| map.insert(i)).collect();
+    let finalized = Arc::new(Mutex::new(Vec::new()));
+    let removers: Vec<_> = (0..4)
+        .map(|_| {
+            let (map, keys, finalized) = (map.clone(), keys.clone(), finalized.clone());
+            thread::spawn(move || {
+                keys.iter()
+                    .filter_map(|&key| map.remove_with(key, |v| finalized.lock().unwrap().push(v)))
+                    .count()
+            })
+        })
+        .collect();
+    let removed: usize = removers.into_iter().map(|r| r.join().unwrap()).sum();
+    assert_eq!(removed, 100);
+    let mut finalized = finalized.lock().unwrap().clone();
+    finalized.sort();
+    assert_eq!(finalized, (0..100).collect::<Vec<_>>());
+    assert_eq!(map.approx_len(), 0);
+    assert_eq!(map.remove_with(keys[0], |v| v), None);
+}
+
+#[test]
+fn view_reads_and_writes_entries() {
+    fn plugin(view: ReadWriteView<'_, DefaultKey, u32>, keys: &[DefaultKey]) {
+        for &key in keys {
+            if view.contains_key(key) {
+                *view.get_mut(key).unwrap() *= 10;
+            }
+        }
+    }
+
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (1..=4).map(|v| map.insert(v)).collect();
+    map.remove(keys[1]);
+    let view = map.view();
+    thread::scope(|scope| {
+        scope.spawn(|| plugin(view, &keys[..2]));
+        scope.spawn(|| plugin(view, &keys[2..]));
+    });
+    assert_eq!(view.approx_len(), 3);
+    assert_eq!(
+        keys.iter()
+            .map(|&key| view.get(key).map(|v| *v))
+            .collect::<Vec<_>>(),
+        [Some(10), None, Some(30), Some(40)]
+    );
+}
+
+#[test]
+fn drain_async_in_batches() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..25).map(|v| map.insert(v)).collect();
+
+    // A held entry makes the drain wait, and giving up on it leaves the map as it was.
+    let reader = map.get(keys[0]).unwrap();
+    assert!(!poll_once(map.drain_async(10)));
+    drop(reader);
+    assert_eq!(map.approx_len(), 25);
+
+    let mut drained = Vec::new();
+    loop {
+        let batch = block_on(map.drain_async(10));
+        assert!(batch.len() <= 10);
+        if batch.is_empty() {
+            break;
+        }
+        drained.extend(batch.into_iter().map(|(_, v)| v));
+    }
+    drained.sort();
+    assert_eq!(drained, (0..25).collect::<Vec<_>>());
+    assert_eq!(map.approx_len(), 0);
+}
+
+#[test]
+fn with_locked2_from_many_threads() {
+    // Fresh pairs of maps, so that both of them end up first in address order some of the time.
+    for _ in 0..20 {
+        let entities = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+        let meta = Arc::new(SyncSecondarySlotMap::<DefaultKey, u32>::new());
+        let key = entities.insert(0);
+        meta.insert(key, 0);
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let (entities, meta) = (entities.clone(), meta.clone());
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        with_locked2(&entities, &meta, |entities, meta| {
+                            if i % 2 == 0 {
+                                *entities.get_mut(key).unwrap() += 1;
+                                *meta.get_mut(key).unwrap() += 1;
+                            } else {
+                                *meta.get_mut(key).unwrap() += 1;
+                                *entities.get_mut(key).unwrap() += 1;
+                            }
+                        });
+                        drop(meta.get(key));
+                        drop(entities.get_mut(key));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*entities.get(key).unwrap(), 800);
+        assert_eq!(*meta.get(key).unwrap(), 800);
+        with_locked2(&entities, &meta, |_, meta| {
+            assert_eq!(meta.remove(key), Some(800))
+        });
+        assert!(meta.get(key).is_none());
+    }
+}
+
+#[test]
+fn with_locked2_inserts_and_removes() {
+    let entities = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+    let meta = SyncSecondarySlotMap::<DefaultKey, u32>::new();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    entities.on_change({
+        let (entities, events) = (entities.clone(), events.clone());
+        // Reported once the maps are unlocked again, so the map can be used from here.
+        move |event| events.lock().unwrap().push((event, entities.approx_len()))
+    });
+    let old = entities.insert(1);
+    meta.insert(old, 10);
+    events.lock().unwrap().clear();
+
+    let new = with_locked2(&entities, &meta, |entities, meta| {
+        let new = entities.insert(2);
+        meta.insert(new, 20);
+        assert_eq!(entities.remove(old), Some(1));
+        assert_eq!(meta.remove(old), Some(10));
+        assert_eq!(entities.remove(old), None);
+        assert_eq!(*entities.get(new).unwrap(), 2);
+        new
+    });
+    assert!(entities.get(old).is_none());
+    assert_eq!(*entities.get(new).unwrap(), 2);
+    assert_eq!(*meta.get(new).unwrap(), 20);
+    assert_eq!(entities.approx_len(), 1);
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            (ChangeEvent::Inserted(new), 1),
+            (ChangeEvent::Removed(old), 1)
+        ]
+    );
+}
+
+#[test]
+fn guards_know_their_key() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let a = map.insert(1);
+    let b = map.insert(2);
+    assert_eq!(map.get(a).unwrap().key::<DefaultKey>(), a);
+    assert_eq!(map.get_mut(b).unwrap().key::<DefaultKey>(), b);
+    assert_eq!(
+        map.get_mut(b)
+            .unwrap()
+            .release_and_get()
+            .key::<DefaultKey>(),
+        b
+    );
+    for (key, guard) in map.iter() {
+        assert_eq!(guard.key::<DefaultKey>(), key);
+        assert_eq!(guard.clone().key::<DefaultKey>(), key);
+    }
+    let guards = map.get_disjoint_mut_many(&[b, a]).unwrap();
+    assert_eq!(guards[0].key::<DefaultKey>(), b);
+    assert_eq!(guards[1].key::<DefaultKey>(), a);
+    drop(guards);
+    let (key, guard) = map.insert_and_get(3);
+    assert_eq!(guard.key::<DefaultKey>(), key);
+}
+
+#[test]
+fn insert_reporting_recycled_slots() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let (a, origin) = map.insert_reporting(1);
+    assert_eq!(origin, SlotOrigin::Fresh);
+    assert_eq!(map.insert_reporting(2).1, SlotOrigin::Fresh);
+    map.remove(a);
+    let (b, origin) = map.insert_reporting(3);
+    assert_eq!(origin, SlotOrigin::Recycled);
+    assert_ne!(a, b);
+    assert!(map.get(a).is_none());
+    assert_eq!(map.insert_reporting(4).1, SlotOrigin::Fresh);
+}
+
+#[test]
+fn get_many_cloned() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, String>::new());
+    let a = map.insert("a".into());
+    let gone = map.insert("x".into());
+    let b = map.insert("b".into());
+    map.remove(gone);
+    assert_eq!(
+        map.get_many_cloned(&[b, gone, a]),
+        [Some("b".to_string()), None, Some("a".to_string())]
+    );
+    assert_eq!(
+        (map.is_locked(a), map.is_locked(b)),
+        (Some(false), Some(false))
+    );
+
+    // Every entry is let go of right after it's cloned, so a writer keeps getting through.
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u64>::new());
+    let key = map.insert(0);
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (map, stop) = (map.clone(), stop.clone());
+        thread::spawn(move || {
+            let mut writes = 0;
+            while !stop.load(Ordering::Relaxed) {
+                *map.get_mut(key).unwrap() += 1;
+                writes += 1;
+            }
+            writes
+        })
+    };
+    let keys = vec![key; 10_000];
+    let mut seen = 0;
+    for _ in 0..20 {
+        let values = map.get_many_cloned(&keys);
+        assert!(values.windows(2).all(|pair| pair[0] <= pair[1]));
+        seen = seen.max(values[keys.len() - 1].unwrap());
+    }
+    stop.store(true, Ordering::Relaxed);
+    let writes = writer.join().unwrap();
+    assert_eq!(*map.get(key).unwrap(), writes);
+    assert!(seen > 0);
+}
+
+#[test]
+fn drop_behavior_wait_ignores_timeout() {
+    use sync_slotmap::DropBehavior;
+
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let guard = map.get_mut(map.insert(1)).unwrap();
+    map.set_drop_behavior(DropBehavior::Wait);
+    map.set_drop_timeout(Duration::from_millis(10));
+    let writer = thread::spawn(move || {
+        let mut guard = guard;
+        thread::sleep(Duration::from_millis(200));
+        *guard += 1;
+    });
+    let start = Instant::now();
+    drop(map);
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    writer.join().unwrap();
+}
+
+#[test]
+fn get_mut_validated_evicts_stale_entries() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+    let fresh = map.insert(1);
+    let stale = map.insert(100);
+    assert_eq!(*map.get_mut_validated(fresh, |v| *v < 10).unwrap(), 1);
+    assert!(map.get_mut_validated(stale, |v| *v < 10).is_none());
+    assert!(map.get(stale).is_none());
+    assert_eq!(map.approx_len(), 1);
+
+    // Threads already waiting on an entry that gets evicted don't hang.
+    let stale = map.insert(100);
+    let guard = map.get_mut(stale).unwrap();
+    let reader = {
+        let map = map.clone();
+        thread::spawn(move || map.get(stale).map(|v| *v))
+    };
+    thread::sleep(Duration::from_millis(50));
+    let validator = {
+        let map = map.clone();
+        thread::spawn(move || map.get_mut_validated(stale, |v| *v < 10).is_none())
+    };
+    thread::sleep(Duration::from_millis(50));
+    drop(guard);
+    assert!(validator.join().unwrap());
+    assert!(matches!(reader.join().unwrap(), None | Some(100)));
+    assert!(map.get(stale).is_none());
+}
+
+#[test]
+fn trait_object_values() {
+    trait Counter: Send + Sync {
+        fn bump(&mut self) -> u32;
+    }
+
+    struct Ticks(u32);
+
+    impl Counter for Ticks {
+        fn bump(&mut self) -> u32 {
+            self.0 += 1;
+            self.0
+        }
+    }
+
+    let map = Arc::new(SyncSlotMap::<DefaultKey, Box<dyn Counter>>::new());
+    let key = map.insert(Box::new(Ticks(0)));
+    assert_eq!(map.get_mut(key).unwrap().bump(), 1);
+    let mut counter = map.get_mut(key).unwrap().map(|counter| &mut **counter);
+    assert_eq!(counter.bump(), 2);
+    drop(counter);
+    let bumper = {
+        let map = map.clone();
+        thread::spawn(move || map.get_mut(key).unwrap().bump())
+    };
+    assert_eq!(bumper.join().unwrap(), 3);
+    assert_eq!(map.remove(key).unwrap().bump(), 4);
+}
+
+#[test]
+fn weak_handle_upgrade() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+    let key = map.insert(5);
+    let weak = SyncSlotMap::downgrade(&map);
+    let other = map.clone();
+    drop(map);
+    assert_eq!(*weak.upgrade().unwrap().get(key).unwrap(), 5);
+    drop(other);
+    assert!(weak.upgrade().is_none());
+    assert!(weak.clone().upgrade().is_none());
+}
+
+#[test]
+fn get_mut_async_for() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u32>::new());
+    let key = map.insert(1);
+    *block_on(map.get_mut_async_for(key, Duration::from_millis(10)))
+        .unwrap()
+        .unwrap() += 1;
+
+    // Times out while another thread holds the entry.
+    let reader = map.get(key).unwrap();
+    let start = Instant::now();
+    assert!(block_on(map.get_mut_async_for(key, Duration::from_millis(50))).is_none());
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // Gets it once the other thread is done, before the deadline.
+    let releaser = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(30));
+        drop(reader);
+    });
+    let guard = block_on(map.get_mut_async_for(key, Duration::from_secs(5)));
+    assert_eq!(*guard.unwrap().unwrap(), 2);
+    releaser.join().unwrap();
+
+    // Giving up mid-wait leaves nothing locked.
+    let reader = map.get(key).unwrap();
+    assert!(!poll_once(
+        map.get_mut_async_for(key, Duration::from_secs(5))
+    ));
+    drop(reader);
+    assert!(map.try_get_mut_for(key, Duration::ZERO).unwrap().is_some());
+
+    map.remove(key);
+    assert!(
+        block_on(map.get_mut_async_for(key, Duration::from_millis(5)))
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn capacity_grows_with_reserve() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let before = map.capacity();
+    map.reserve(before + 100);
+    assert!(map.capacity() >= before + 100);
+    for i in 0..10 {
+        map.insert(i);
+    }
+    assert!(map.capacity() >= before + 100);
+
+    let secondary = SyncSecondarySlotMap::<DefaultKey, u32>::new();
+    secondary.set_capacity(500);
+    assert!(secondary.capacity() >= 500);
+}
+
+#[test]
+fn values_cloned() {
+    let map = SyncSlotMap::<DefaultKey, String>::new();
+    let keys: Vec<_> = ["a", "b", "c", "d"]
+        .iter()
+        .map(|v| map.insert(v.to_string()))
+        .collect();
+    map.remove(keys[1]);
+    let mut values = map.values_cloned();
+    values.sort();
+    assert_eq!(values, ["a", "c", "d"]);
+    assert_eq!(map.approx_len(), 3);
+    assert_eq!(*map.get(keys[3]).unwrap(), "d");
+    assert!(keys.iter().all(|&key| map.is_locked(key) != Some(true)));
+}
+
+#[test]
+fn get_checked() {
+    let map = SyncSlotMap::<DefaultKey, i32>::new();
+    let old = map.insert(1);
+    assert!(matches!(map.get_checked(old), GetResult::Live(v) if *v == 1));
+    map.remove(old);
+    assert!(matches!(map.get_checked(old), GetResult::Stale));
+
+    // The slot is reused with a new version, the old key stays stale.
+    let new = map.insert(2);
+    assert_eq!(new.data().as_ffi() as u32, old.data().as_ffi() as u32);
+    assert!(matches!(map.get_checked(old), GetResult::Stale));
+    assert!(matches!(map.get_checked(new), GetResult::Live(v) if *v == 2));
+
+    assert!(matches!(
+        map.get_checked(DefaultKey::null()),
+        GetResult::Unknown
+    ));
+    let beyond: DefaultKey = KeyData::from_ffi((1 << 32) | 1000).into();
+    assert!(matches!(map.get_checked(beyond), GetResult::Unknown));
+    let mapped = map.map_values(|v| *v);
+    assert!(matches!(mapped.get_checked(old), GetResult::Stale));
+}
+
+#[test]
+fn backoff_between_retries() {
+    struct Recorded(Exponential, Arc<Mutex<Vec<u32>>>);
+
+    impl Backoff for Recorded {
+        fn backoff(&self, retry: u32) {
+            self.1.lock().unwrap().push(retry);
+            self.0.backoff(retry);
+        }
+    }
+
+    let retries = Arc::new(Mutex::new(Vec::new()));
+    let map = Arc::new(
+        SyncSlotMap::<DefaultKey, u32>::builder()
+            .backoff(Recorded(Exponential::default(), retries.clone()))
+            .build(),
+    );
+    let (a, b) = (map.insert(1), map.insert(2));
+    let writer = map.get_mut(b).unwrap();
+    let both = {
+        let map = map.clone();
+        thread::spawn(move || {
+            map.get_disjoint_mut_many(&[a, b])
+                .map(|guards| guards.len())
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    // `b` goes away while `both` waits for it, which makes it start over.
+    let remover = {
+        let map = map.clone();
+        thread::spawn(move || map.remove(b))
+    };
+    thread::sleep(Duration::from_millis(50));
+    drop(writer);
+    assert_eq!(remover.join().unwrap(), Some(2));
+    assert_eq!(both.join().unwrap(), None);
+    assert_eq!(*retries.lock().unwrap(), [1]);
+}
+
+#[test]
+fn insert_and_get_async_is_exclusive() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let (key, mut guard) = block_on(map.insert_and_get_async(0));
+    assert!(map.try_get(key).is_none());
+    assert!(!poll_once(map.get_async(key)));
+    *guard = 7;
+    drop(guard);
+    assert_eq!(*map.get(key).unwrap(), 7);
+
+    // A recycled slot's entry is just as much the caller's.
+    map.remove(key);
+    let (recycled, guard) = block_on(map.insert_and_get_async(1));
+    assert_eq!(recycled.data().as_ffi() as u32, key.data().as_ffi() as u32);
+    assert!(map.try_get_mut(recycled).is_none());
+    drop(guard);
+
+    // Giving up while a scan keeps inserts out adds nothing and leaves nothing locked.
+    map.for_each(|_, _| assert!(!poll_once(map.insert_and_get_async(2))));
+    assert_eq!(map.approx_len(), 1);
+    block_on(map.insert_and_get_async(3));
+    assert_eq!(map.approx_len(), 2);
+}
+
+#[test]
+fn increment_from_many_threads() {
+    let map = SyncSlotMap::<DefaultKey, u64>::new();
+    let key = map.insert(0);
+    thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| {
+                for _ in 0..1000 {
+                    map.increment(key, 1).unwrap();
+                    block_on(map.increment_async(key, 2)).unwrap();
+                }
+            });
+        }
+    });
+    assert_eq!(*map.get(key).unwrap(), 24000);
+    assert_eq!(map.increment(key, 1), Some(24001));
+    map.remove(key);
+    assert_eq!(map.increment(key, 1), None);
+    assert_eq!(block_on(map.increment_async(key, 1)), None);
+}
+
+#[test]
+fn remove_if() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let key = map.insert(5);
+    assert_eq!(map.remove_if(key, |v| *v > 10), None);
+    assert_eq!(*map.get(key).unwrap(), 5);
+
+    let reader = map.get(key).unwrap();
+    thread::scope(|scope| {
+        let remover = scope.spawn(|| map.remove_if(key, |v| *v == 5));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!remover.is_finished());
+        drop(reader);
+        assert_eq!(remover.join().unwrap(), Some(5));
+    });
+    assert!(map.get(key).is_none());
+    assert_eq!(map.remove_if(key, |_| true), None);
+
+    // Conditional removals alongside scans, inserts and writers to the same entries.
+    let keys: Vec<_> = (0..64).map(|i| map.insert(i)).collect();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            for _ in 0..200 {
+                map.for_each(|_, _| {});
+            }
+        });
+        scope.spawn(|| {
+            for _ in 0..200 {
+                map.remove(map.insert(1));
+            }
+        });
+        scope.spawn(|| {
+            for &key in &keys {
+                drop(map.get_mut(key));
+            }
+        });
+        scope.spawn(|| {
+            for &key in &keys {
+                map.remove_if(key, |v| v % 2 == 0);
+            }
+        });
+    });
+    assert_eq!(map.approx_len(), 32);
+}
+
+#[test]
+fn busy_keys() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let a = map.insert(1);
+    map.insert(2);
+    let c = map.insert(3);
+    assert!(map.busy_keys().is_empty());
+    let reader = map.get(a).unwrap();
+    let writer = map.get_mut(c).unwrap();
+    assert_eq!(map.busy_keys(), [a, c]);
+    drop(reader);
+    assert_eq!(map.busy_keys(), [c]);
+    drop(writer);
+    assert!(map.busy_keys().is_empty());
+}
+
+#[test]
+fn metrics() {
+    #[derive(Default)]
+    struct Counts {
+        inserts: AtomicU64,
+        removes: AtomicU64,
+        contention: AtomicU64,
+        longest_wait_ms: AtomicU64,
+    }
+
+    impl Metrics for Counts {
+        fn on_lock_wait(&self, waited: Duration) {
+            self.longest_wait_ms
+                .fetch_max(waited.as_millis() as u64, Ordering::Relaxed);
+        }
+
+        fn on_insert(&self) {
+            self.inserts.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_remove(&self) {
+            self.removes.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_contention(&self) {
+            self.contention.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    map.insert(0);
+    let counts = Arc::new(Counts::default());
+    map.set_metrics(counts.clone());
+    let a = map.insert(1);
+    let b = map.insert(2);
+    map.remove(b);
+    assert_eq!(counts.inserts.load(Ordering::Relaxed), 2);
+    assert_eq!(counts.removes.load(Ordering::Relaxed), 1);
+    assert_eq!(counts.contention.load(Ordering::Relaxed), 0);
+
+    let writer = map.get_mut(a).unwrap();
+    thread::scope(|scope| {
+        scope.spawn(|| *map.get_mut(a).unwrap() += 1);
+        thread::sleep(Duration::from_millis(60));
+        drop(writer);
+    });
+    assert_eq!(counts.contention.load(Ordering::Relaxed), 1);
+    assert!(counts.longest_wait_ms.load(Ordering::Relaxed) >= 40);
+}
+
+#[test]
+fn entity_and_meta_locked_together() {
+    let entities = SyncSlotMap::<DefaultKey, u32>::new();
+    let meta = SyncSecondarySlotMap::<DefaultKey, u32>::new();
+    let a = entities.insert(0);
+    let b = entities.insert(0);
+    meta.insert(a, 0);
+    assert!(get_entity_and_meta_mut(&entities, &meta, b).is_none());
+    assert!(entities.get_mut(b).is_some());
+
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..1000 {
+                    let (mut entity, mut meta) =
+                        get_entity_and_meta_mut(&entities, &meta, a).unwrap();
+                    *entity += 1;
+                    *meta += 1;
+                }
+            });
+        }
+        scope.spawn(|| {
+            for _ in 0..200 {
+                with_locked2(&entities, &meta, |entities, meta| {
+                    assert_eq!(entities.get(a), meta.get(a));
+                });
+            }
+        });
+    });
+    assert_eq!(*entities.get(a).unwrap(), 4000);
+    assert_eq!(*meta.get(a).unwrap(), 4000);
+}
+
+#[test]
+fn sparse_locks() {
+    let map = SyncSlotMap::<DefaultKey, u32>::builder()
+        .sparse_locks(true)
+        .build();
+    let keys: Vec<_> = (0..1000).map(|i| map.insert(i)).collect();
+    for &key in &keys[..990] {
+        map.remove(key);
+    }
+    map.compact_locks();
+    let live = &keys[990..];
+    assert_eq!(*map.get(live[0]).unwrap(), 990);
+    *map.get_mut(live[1]).unwrap() += 1;
+    assert!(map.swap(live[2], live[3]));
+    assert_eq!(map.get_disjoint_mut_many(&live[4..8]).unwrap().len(), 4);
+    assert!(map.get(keys[0]).is_none());
+    let reused = map.insert(7);
+    assert_eq!(*map.get(reused).unwrap(), 7);
+    assert_eq!(map.remove_if(reused, |v| *v == 7), Some(7));
+    let doubled = map.map_values(|v| *v * 2);
+    assert_eq!(*doubled.get(live[0]).unwrap(), 1980);
+    assert!(map.busy_keys().is_empty());
+    assert_eq!(map.count(|_| true), 10);
+    assert_eq!(block_on(map.get_async(live[9])).map(|v| *v), Some(999));
+    map.shrink_to_fit();
+    assert_eq!(map.approx_len(), 10);
+}
+
+#[test]
+fn scans_guards_and_writers_together_dont_deadlock() {
+    let map = Arc::new(SyncSlotMap::<DefaultKey, u64>::new());
+    let keys: Vec<_> = (0..4).map(|i| map.insert(i)).collect();
+    let stop = Arc::new(AtomicBool::new(false));
+    let (done, finished) = mpsc::channel();
+    // Guards on every key, scans waiting for them and inserts and removals queueing up on the
+    // map's structure lock in between, all at once.
+    let roles = [
+        "get_mut",
+        "get_mut_async",
+        "for_each",
+        "for_each",
+        "insert",
+        "insert",
+    ];
+    for role in roles {
+        let (map, keys, stop, done) = (map.clone(), keys.clone(), stop.clone(), done.clone());
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match role {
+                    "get_mut" => {
+                        for &key in &keys {
+                            let mut guard = map.get_mut(key).unwrap();
+                            // Gives the other threads a chance to queue up behind it.
+                            thread::yield_now();
+                            *guard += 1;
+                        }
+                    }
+                    "get_mut_async" => {
+                        for &key in &keys {
+                            *block_on(map.get_mut_async(key)).unwrap() += 1;
+                        }
+                    }
+                    "for_each" => map.for_each(|_, value| assert!(*value < u64::MAX)),
+                    _ => {
+                        let key = map.insert(0);
+                        assert_eq!(map.remove(key), Some(0));
+                    }
+                }
+            }
+            done.send(()).unwrap();
+        });
+    }
+    thread::sleep(Duration::from_secs(3));
+    stop.store(true, Ordering::Relaxed);
+    for _ in roles {
+        finished
+            .recv_timeout(Duration::from_secs(10))
+            .expect("deadlocked");
+    }
+}
+
+#[test]
+fn force_unlock_key_after_multi_key_and_insert_holds() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let keys: Vec<_> = (0..3).map(|v| map.insert(v)).collect();
+    for guard in map.get_disjoint_mut_many(&keys[1..]).unwrap() {
+        std::mem::forget(guard);
+    }
+    let (inserted, guard) = map.insert_and_get(9);
+    std::mem::forget(guard);
+    for &key in keys[1..].iter().chain([&inserted]) {
+        unsafe { map.force_unlock_key(key, true) };
+    }
+    // Every hold was released from the part of the global lock it took, so the whole map can
+    // be locked again.
+    let removed = map.try_remove_where_for(|_, _| true, Duration::from_secs(5));
+    assert_eq!(removed.map(|removed| removed.len()), Some(4));
+}
+
+#[test]
+fn panicking_closures_leave_the_map_unlocked() {
+    let map = SyncSlotMap::<DefaultKey, u32>::new();
+    let secondary = SyncSecondarySlotMap::<DefaultKey, u32>::new();
+    let other = SyncSecondarySlotMap::<DefaultKey, u32>::new();
+    let key = map.insert(1);
+    secondary.insert(key, 1);
+    other.insert(key, 2);
+    let panics: [&dyn Fn(); 9] = [
+        &|| map.for_each(|_, _| panic!("boom")),
+        &|| map.for_each_mut(|_, _| panic!("boom")),
+        &|| drop(map.apply_all(|_, _| -> u32 { panic!("boom") })),
+        &|| map.retain(|_, _| panic!("boom")),
+        &|| drop(map.remove_where(|_, _| panic!("boom"))),
+        &|| {
+            map.remove_if(key, |_| panic!("boom"));
+        },
+        &|| drop(map.map_values(|_| -> u32 { panic!("boom") })),
+        &|| with_locked2(&map, &secondary, |_, _| panic!("boom")),
+        &|| secondary.merge(&other, |_, _| panic!("boom")),
+    ];
+    for f in panics {
+        assert!(catch_unwind(AssertUnwindSafe(f)).is_err());
+        let removed = map.try_remove_where_for(|_, _| false, Duration::from_secs(5));
+        assert_eq!(removed.map(|removed| removed.len()), Some(0));
+        assert!(secondary
+            .try_get_mut_for(key, Duration::from_secs(5))
+            .is_some());
+    }
+    assert_eq!(*map.get(key).unwrap(), 1);
+}
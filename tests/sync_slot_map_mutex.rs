@@ -0,0 +1,51 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{block_on, poll_once};
+use slotmap::DefaultKey;
+use sync_slotmap::SyncSlotMapMutex;
+
+#[test]
+fn readers_serialize() {
+    let map = Arc::new(SyncSlotMapMutex::<DefaultKey, u32>::new());
+    let key = map.insert(1);
+    let other = map.insert(2);
+    let guard = map.get(key).unwrap();
+    assert!(map.try_get_for(key, Duration::from_millis(10)).is_none());
+    assert!(!poll_once(map.get_async(key)));
+    // Other entries aren't held up.
+    assert_eq!(*map.get(other).unwrap(), 2);
+
+    let reader = thread::spawn({
+        let map = map.clone();
+        move || {
+            let start = Instant::now();
+            let value = *map.get(key).unwrap();
+            (value, start.elapsed())
+        }
+    });
+    thread::sleep(Duration::from_millis(50));
+    drop(guard);
+    let (value, waited) = reader.join().unwrap();
+    assert_eq!(value, 1);
+    assert!(waited >= Duration::from_millis(40));
+}
+
+#[test]
+fn insert_get_remove() {
+    let map = SyncSlotMapMutex::<DefaultKey, u32>::new();
+    let key = block_on(map.insert_async(1));
+    *map.get(key).unwrap() += 1;
+    assert_eq!(*block_on(map.get_async(key)).unwrap(), 2);
+    assert_eq!(map.approx_len(), 1);
+    assert_eq!(map.remove(key), Some(2));
+    assert!(map.get(key).is_none());
+    assert_eq!(map.try_remove_for(key, Duration::ZERO), Some(None));
+    let key = map.try_insert_for(3, Duration::from_millis(10)).unwrap();
+    assert_eq!(*map.into_inner().get(key).unwrap(), 3);
+}
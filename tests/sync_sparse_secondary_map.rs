@@ -0,0 +1,105 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::time::Duration;
+
+use common::block_on;
+use slotmap::{DefaultKey, SlotMap};
+use sync_slotmap::{DropBehavior, SyncSparseSecondarySlotMap};
+
+#[test]
+fn insert_get_remove() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let key = primary.insert(());
+    let map = SyncSparseSecondarySlotMap::<DefaultKey, i32>::new();
+
+    assert!(map.get(key).is_none());
+    assert_eq!(map.insert(key, 1), None);
+    assert_eq!(map.insert(key, 2), Some(1));
+    *map.get_mut(key).unwrap() += 1;
+    assert_eq!(*map.get(key).unwrap(), 3);
+    assert_eq!(map.remove(key), Some(3));
+    assert_eq!(map.remove(key), None);
+    assert!(map.get_mut(key).is_none());
+}
+
+#[test]
+fn stale_keys() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let old = primary.insert(());
+    let map = SyncSparseSecondarySlotMap::<DefaultKey, i32>::new();
+    map.insert(old, 1);
+
+    primary.remove(old);
+    let new = primary.insert(());
+    // Inserting a newer key for the same slot drops the old entry rather than handing it back.
+    assert_eq!(map.insert(new, 2), None);
+    assert!(map.get(old).is_none());
+    assert_eq!(map.remove(old), None);
+    // And an older key can't clobber the newer entry.
+    assert_eq!(map.insert(old, 9), None);
+    assert_eq!(*map.get(new).unwrap(), 2);
+}
+
+#[test]
+fn timed_and_async() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let (key, missing) = (primary.insert(()), primary.insert(()));
+    let map = SyncSparseSecondarySlotMap::<DefaultKey, i32>::new();
+    assert_eq!(
+        map.try_insert_for(key, 1, Duration::from_millis(20)),
+        Some(None)
+    );
+
+    let guard = map.get_mut(key).unwrap();
+    assert!(map.try_get_for(key, Duration::from_millis(20)).is_none());
+    assert!(map
+        .try_get_mut_for(key, Duration::from_millis(20))
+        .is_none());
+    drop(guard);
+    assert!(map
+        .try_get_for(missing, Duration::from_millis(20))
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        *map.try_get_for(key, Duration::from_millis(20))
+            .unwrap()
+            .unwrap(),
+        1
+    );
+
+    assert_eq!(block_on(map.insert_async(key, 2)), Some(1));
+    *block_on(map.get_mut_async(key)).unwrap() += 1;
+    assert_eq!(*block_on(map.get_async(key)).unwrap(), 3);
+    assert!(block_on(map.get_async(missing)).is_none());
+    assert_eq!(block_on(map.remove_async(key)), Some(3));
+    assert_eq!(
+        map.try_remove_for(key, Duration::from_millis(20)),
+        Some(None)
+    );
+}
+
+#[test]
+fn drop_behaviors() {
+    let mut primary = SlotMap::<DefaultKey, ()>::new();
+    let key = primary.insert(());
+
+    let map = SyncSparseSecondarySlotMap::<DefaultKey, i32>::new();
+    map.insert(key, 7);
+    let guard = map.get(key).unwrap();
+    map.set_drop_behavior(DropBehavior::Panic);
+    map.set_drop_timeout(Duration::from_millis(20));
+    assert!(catch_unwind(AssertUnwindSafe(|| drop(map))).is_err());
+    assert_eq!(*guard, 7);
+    drop(guard);
+
+    let map = SyncSparseSecondarySlotMap::<DefaultKey, i32>::new();
+    map.insert(key, 8);
+    let guard = map.get(key).unwrap();
+    map.set_drop_behavior(DropBehavior::Warn);
+    map.set_drop_timeout(Duration::from_millis(20));
+    drop(map);
+    assert_eq!(*guard, 8);
+}
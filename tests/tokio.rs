@@ -0,0 +1,63 @@
+#![cfg(feature = "tokio")]
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use slotmap::DefaultKey;
+use sync_slotmap::{SyncSlotMap, TokioRawLock};
+
+// Counts how many times the future it wraps is polled.
+struct Polls<F> {
+    future: Pin<Box<F>>,
+    polls: Arc<AtomicUsize>,
+}
+
+impl<F: Future> Future for Polls<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.future.as_mut().poll(cx)
+    }
+}
+
+#[test]
+fn contended_get_mut_async_waits_without_spinning() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let map = Arc::new(SyncSlotMap::<DefaultKey, u64, TokioRawLock>::with_raw_lock());
+        let key = map.insert(0);
+        let guard = map.get_mut(key).unwrap();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let map = map.clone();
+                tokio::spawn(Polls {
+                    future: Box::pin(async move {
+                        for _ in 0..100 {
+                            *map.get_mut_async(key).await.unwrap() += 1;
+                        }
+                    }),
+                    polls: polls.clone(),
+                })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // Each task was polled when spawned and has been parked on the lock since.
+        let polls_while_held = polls.load(Ordering::Relaxed);
+        assert!(polls_while_held <= 16, "polled {polls_while_held} times");
+        drop(guard);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(*map.get(key).unwrap(), 800);
+    });
+}
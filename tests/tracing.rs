@@ -0,0 +1,76 @@
+#![cfg(feature = "tracing")]
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+use slotmap::{DefaultKey, Key};
+use sync_slotmap::SyncSlotMap;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+// The fields of every event, formatted with `Debug`.
+type Events = Arc<Mutex<Vec<BTreeMap<&'static str, String>>>>;
+
+struct Recorder(Events);
+
+struct Fields(BTreeMap<&'static str, String>);
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name(), format!("{value:?}"));
+    }
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target() == "sync_slotmap"
+    }
+
+    fn new_span(&self, _: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields(BTreeMap::new());
+        event.record(&mut fields);
+        self.0.lock().unwrap().push(fields.0);
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+#[test]
+fn lock_events() {
+    let events = Events::default();
+    let key = tracing::subscriber::with_default(Recorder(events.clone()), || {
+        let map = SyncSlotMap::<DefaultKey, u32>::new();
+        let key = map.insert(1);
+        drop(map.get(key));
+        drop(map.get_mut(key));
+        map.remove(key);
+        key
+    });
+    let events = events.lock().unwrap();
+    let find = |op: &str, message: &str| {
+        events
+            .iter()
+            .find(|fields| fields["op"] == format!("{op:?}") && fields["message"] == message)
+            .unwrap_or_else(|| panic!("no {message} event for {op}"))
+    };
+    let key = format!("{:?}", Some(key.data()));
+    for op in ["get", "get_mut", "remove"] {
+        let acquired = find(op, "acquired");
+        assert_eq!(acquired["key"], key);
+        assert!(acquired["waited_us"].parse::<u64>().is_ok());
+        find(op, "released");
+    }
+    assert_eq!(find("insert", "released")["key"], key);
+}